@@ -1,5 +1,9 @@
 #![feature(future_join)]
 #![feature(let_chains)]
+#![cfg_attr(test, feature(test))]
+
+#[cfg(test)]
+extern crate test;
 
 use std::io::Write;
 use std::str::FromStr;
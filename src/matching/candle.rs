@@ -0,0 +1,169 @@
+use chrono::prelude::*;
+use chrono::Duration;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::matching::log::MatchLog;
+use crate::utils::error::CustomError;
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Candle {
+    pub open: Decimal,
+    pub high: Decimal,
+    pub low: Decimal,
+    pub close: Decimal,
+    pub volume: Decimal,
+}
+
+// aggregate the trades of [interval_start, interval_start + interval) into a candle, trades
+// are taken in the order of the tape, so open and close are the first and last of them.
+// an interval reaching past the range of the timestamps runs to the end of time, an
+// interval which isn't positive or starts beyond that range is an error
+pub fn candle(
+    trades: &[MatchLog],
+    interval_start: DateTime<Utc>,
+    interval: Duration,
+) -> Result<Option<Candle>, CustomError> {
+    if interval <= Duration::zero() {
+        return Err(CustomError::Other(format!(
+            "candle interval {} must be positive",
+            interval
+        )));
+    }
+    let start = interval_start.timestamp_nanos_opt().ok_or_else(|| {
+        CustomError::Other(format!(
+            "candle interval start {} is out of the range of the timestamps",
+            interval_start
+        ))
+    })?;
+    let end = start.saturating_add(interval.num_nanoseconds().unwrap_or(i64::MAX));
+
+    let mut candle: Option<Candle> = None;
+    for trade in trades.iter() {
+        let time = trade.base.time as i64;
+        if time < start || time >= end {
+            continue;
+        }
+        match candle.as_mut() {
+            Some(c) => {
+                c.high = Decimal::max(c.high, trade.price);
+                c.low = Decimal::min(c.low, trade.price);
+                c.close = trade.price;
+                c.volume += trade.size;
+            }
+            None => {
+                candle = Some(Candle {
+                    open: trade.price,
+                    high: trade.price,
+                    low: trade.price,
+                    close: trade.price,
+                    volume: trade.size,
+                })
+            }
+        }
+    }
+    Ok(candle)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use chrono::prelude::*;
+    use chrono::Duration;
+    use rust_decimal::Decimal;
+
+    use crate::matching::candle::{candle, Candle};
+    use crate::matching::log::{new_match_log, MatchLog};
+    use crate::matching::order_book::BookOrder;
+    use crate::models::types::*;
+
+    fn dec(s: &str) -> Decimal {
+        Decimal::from_str(s).unwrap()
+    }
+
+    fn trade(seq: u64, secs: i64, price: &str, size: &str) -> MatchLog {
+        let taker = BookOrder {
+            order_id: seq * 2,
+            side: SIDE_BUY,
+            ..Default::default()
+        };
+        let maker = BookOrder {
+            order_id: seq * 2 + 1,
+            side: SIDE_SELL,
+            ..Default::default()
+        };
+        let mut log = new_match_log(seq, "BTC-USD", seq, &taker, &maker, &dec(price), &dec(size));
+        log.base.time = (secs * 1_000_000_000) as u64;
+        log
+    }
+
+    #[test]
+    fn test_candle() {
+        let trades = vec![
+            trade(1, 59, "99", "1"),
+            trade(2, 60, "100", "1"),
+            trade(3, 90, "105", "2"),
+            trade(4, 100, "97", "0.5"),
+            trade(5, 119, "101", "1"),
+            trade(6, 120, "110", "3"),
+        ];
+        let start = Utc.timestamp_opt(60, 0).unwrap();
+
+        // the trade at the start is in, the one at the end belongs to the next interval
+        assert_eq!(
+            candle(&trades, start, Duration::seconds(60)).unwrap(),
+            Some(Candle {
+                open: dec("100"),
+                high: dec("105"),
+                low: dec("97"),
+                close: dec("101"),
+                volume: dec("4.5"),
+            })
+        );
+        assert_eq!(
+            candle(
+                &trades,
+                Utc.timestamp_opt(120, 0).unwrap(),
+                Duration::seconds(60)
+            )
+            .unwrap(),
+            Some(Candle {
+                open: dec("110"),
+                high: dec("110"),
+                low: dec("110"),
+                close: dec("110"),
+                volume: dec("3"),
+            })
+        );
+        assert_eq!(
+            candle(
+                &trades,
+                Utc.timestamp_opt(0, 0).unwrap(),
+                Duration::seconds(59)
+            )
+            .unwrap(),
+            None
+        );
+        assert_eq!(candle(&[], start, Duration::seconds(60)).unwrap(), None);
+    }
+
+    #[test]
+    fn test_candle_interval_bounds() {
+        let trades = vec![trade(1, 60, "100", "1"), trade(2, 90, "101", "1")];
+        let start = Utc.timestamp_opt(60, 0).unwrap();
+        assert!(candle(&trades, start, Duration::zero()).is_err());
+        assert!(candle(&trades, start, Duration::seconds(-60)).is_err());
+
+        // too long to count in nanoseconds, and past the end of the timestamps
+        let all = candle(&trades, start, Duration::milliseconds(i64::MAX)).unwrap();
+        assert_eq!(all.unwrap().volume, dec("2"));
+        let late = Utc.timestamp_opt(9_000_000_000, 0).unwrap();
+        assert_eq!(
+            candle(&trades, late, Duration::seconds(i64::MAX / 1_000_000)).unwrap(),
+            None
+        );
+        let beyond = Utc.timestamp_opt(10_000_000_000, 0).unwrap();
+        assert!(candle(&trades, beyond, Duration::seconds(60)).is_err());
+    }
+}
@@ -1,51 +1,203 @@
 use rust_decimal::Decimal;
 use std::cmp::Ordering;
 use std::collections::{BTreeMap, HashMap};
+use std::mem::size_of;
 use std::ops::Sub;
 
 use crate::matching::order_book::BookOrder;
 use crate::matching::ordering::{OrderingTrait, PriceOrderIdKeyAsc, PriceOrderIdKeyDesc};
 use crate::utils::error::CustomError;
 
+// aggregated (price, size) of a price level
+pub type Level = (Decimal, Decimal);
+
+// aggregated price levels, best first
+pub type Levels = Vec<Level>;
+
+#[derive(Clone)]
 pub struct Depth<T: OrderingTrait + Ord> {
     pub orders: HashMap<u64, BookOrder>,
     pub queue: BTreeMap<T, u64>,
+    // number of resting orders of each user
+    pub user_orders: HashMap<u64, usize>,
+    // total price*size of the resting orders
+    pub notional: Decimal,
+    // aggregated (price, size) of every price level, best first, kept by add and
+    // decr_size so the top levels are read without walking the queue
+    pub price_levels: Levels,
 }
 
 impl<T: OrderingTrait + Ord> Depth<T> {
     pub fn add(&mut self, order: &BookOrder) {
-        self.orders.insert(order.order_id, order.clone());
-        self.queue
-            .insert(T::new(&order.price, order.order_id), order.order_id);
+        let old = self.orders.insert(order.order_id, order.clone());
+        match &old {
+            Some(old) => self.notional -= old.price * old.size,
+            None => *self.user_orders.entry(order.user_id).or_insert(0) += 1,
+        }
+        self.notional += order.price * order.size;
+        self.queue.insert(
+            T::new(&order.price, order.priority_class, order.order_id),
+            order.order_id,
+        );
+        if let Some(old) = old {
+            self.adjust_level(&old.price, -old.size);
+        }
+        self.adjust_level(&order.price, order.size);
     }
 
     pub fn decr_size(&mut self, order_id: u64, size: &Decimal) -> Result<(), CustomError> {
+        // a negative decrement would grow the order instead
+        if size.is_sign_negative() && !size.is_zero() {
+            return Err(CustomError::NegativeSize {
+                order_id,
+                size: *size,
+            });
+        }
+
         return match self.orders.get(&order_id) {
             Some(order) => {
                 let mut order = order.clone();
                 match Decimal::cmp(&order.size, size) {
                     // order found in order book is not enough size (maybe some fatal issue)
-                    Ordering::Less => Err(CustomError::from_string(format!(
-                        "order {} size {} less than {}",
-                        order_id, order.size, size
-                    ))),
+                    Ordering::Less => Err(CustomError::InsufficientSize {
+                        order_id,
+                        size: order.size,
+                        decrement: *size,
+                    }),
                     _ => {
                         order.size = order.size.sub(size);
+                        self.notional -= order.price * size;
                         if order.size.is_zero() {
                             self.orders.remove(&order_id);
-                            self.queue.remove(&T::new(&order.price, order.order_id));
+                            self.queue.remove(&T::new(
+                                &order.price,
+                                order.priority_class,
+                                order.order_id,
+                            ));
+                            self.remove_user_order(order.user_id);
+                        }
+                        self.adjust_level(&order.price, -*size);
+                        if !order.size.is_zero() {
+                            self.orders.insert(order_id, order);
                         }
                         Ok(())
                     }
                 }
             }
             // order not found in order book (maybe some fatal issue)
-            None => Err(CustomError::from_string(format!(
-                "order {} not found on book",
-                order_id
-            ))),
+            None => Err(CustomError::NotFound(order_id)),
         };
     }
+
+    // move the size of the level at the price by delta, a new level is inserted at its
+    // place in the queue order and a level is dropped once no order rests at its price
+    fn adjust_level(&mut self, price: &Decimal, delta: Decimal) {
+        let key = T::new(price, 0, 0);
+        match self
+            .price_levels
+            .binary_search_by(|(p, _)| T::new(p, 0, 0).cmp(&key))
+        {
+            Ok(index) => {
+                self.price_levels[index].1 += delta;
+                if self.price_levels[index].1.is_zero() && self.level_orders(price).next().is_none()
+                {
+                    self.price_levels.remove(index);
+                }
+            }
+            Err(index) => self.price_levels.insert(index, (*price, delta)),
+        }
+    }
+
+    // the cached levels of at most n price levels, best first, borrowed without walking
+    // the queue
+    pub fn top_levels(&self, n: usize) -> &[Level] {
+        &self.price_levels[..n.min(self.price_levels.len())]
+    }
+
+    // approximate bytes held by the maps and the level cache, hash maps are counted by
+    // capacity with one control byte per bucket, btree entries get half again for the
+    // node overhead
+    pub fn memory_footprint(&self) -> usize {
+        let orders = self.orders.capacity() * (size_of::<u64>() + size_of::<BookOrder>() + 1);
+        let queue = self.queue.len() * (size_of::<T>() + size_of::<u64>()) * 3 / 2;
+        let user_orders = self.user_orders.capacity() * (size_of::<u64>() + size_of::<usize>() + 1);
+        let price_levels = self.price_levels.capacity() * size_of::<(Decimal, Decimal)>();
+        orders + queue + user_orders + price_levels
+    }
+
+    // give back the capacity the hash maps and the level cache kept from the orders which
+    // have left, the queue is a btree which frees its nodes as it goes, so the priority is untouched
+    pub fn compact(&mut self) {
+        self.orders.shrink_to_fit();
+        self.user_orders.shrink_to_fit();
+        self.price_levels.shrink_to_fit();
+    }
+
+    // the orders resting at the price in queue order, only the level is walked, its keys
+    // run from the highest priority class and the lowest id to the opposite
+    pub fn level_orders<'a>(&'a self, price: &Decimal) -> impl Iterator<Item = &'a BookOrder> {
+        self.queue
+            .range(T::new(price, u8::MAX, 0)..=T::new(price, 0, u64::MAX))
+            .map(move |(_, order_id)| &self.orders[order_id])
+    }
+
+    // total resting size priced within [low, high], only the levels in the range are
+    // walked. the side's ordering decides which bound the keys start from
+    pub fn size_in_range(&self, low: &Decimal, high: &Decimal) -> Decimal {
+        let start = T::new(low, u8::MAX, 0).min(T::new(high, u8::MAX, 0));
+        let end = T::new(low, 0, u64::MAX).max(T::new(high, 0, u64::MAX));
+        self.queue
+            .range(start..=end)
+            .map(|(_, order_id)| self.orders[order_id].size)
+            .sum()
+    }
+
+    // aggregated (price, size) of at most max_levels price levels, best first
+    pub fn levels(&self, max_levels: usize) -> Vec<(Decimal, Decimal)> {
+        let mut levels: Vec<(Decimal, Decimal)> = Vec::new();
+        for order_id in self.queue.values() {
+            let order = &self.orders[order_id];
+            match levels.last_mut() {
+                Some((price, size)) if *price == order.price => *size += order.size,
+                _ => {
+                    if levels.len() == max_levels {
+                        break;
+                    }
+                    levels.push((order.price, order.size));
+                }
+            }
+        }
+        levels
+    }
+
+    // aggregated (price, size, order count) of all the price levels, best first
+    pub fn levels_with_counts(&self) -> Vec<(Decimal, Decimal, usize)> {
+        let mut levels: Vec<(Decimal, Decimal, usize)> = Vec::new();
+        for order_id in self.queue.values() {
+            let order = &self.orders[order_id];
+            match levels.last_mut() {
+                Some((price, size, count)) if *price == order.price => {
+                    *size += order.size;
+                    *count += 1;
+                }
+                _ => levels.push((order.price, order.size, 1)),
+            }
+        }
+        levels
+    }
+
+    pub fn user_order_count(&self, user_id: u64) -> usize {
+        self.user_orders.get(&user_id).cloned().unwrap_or(0)
+    }
+
+    fn remove_user_order(&mut self, user_id: u64) {
+        if let Some(count) = self.user_orders.get_mut(&user_id) {
+            *count -= 1;
+            if *count == 0 {
+                self.user_orders.remove(&user_id);
+            }
+        }
+    }
 }
 
 // AskDepth is order by key PriceOrderIdKeyAsc
@@ -54,3 +206,208 @@ pub type AskDepth = Depth<PriceOrderIdKeyAsc>;
 // BidDepth is order by key PriceOrderIdKeyDesc
 // order by price DESC first, and then order id ASC
 pub type BidDepth = Depth<PriceOrderIdKeyDesc>;
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, HashMap};
+    use std::str::FromStr;
+
+    use rust_decimal::Decimal;
+
+    use crate::matching::depth::AskDepth;
+    use crate::matching::order_book::BookOrder;
+    use crate::models::types::SIDE_SELL;
+    use crate::utils::error::CustomError;
+
+    fn dec(s: &str) -> Decimal {
+        Decimal::from_str(s).unwrap()
+    }
+
+    fn new_ask_depth() -> AskDepth {
+        AskDepth {
+            orders: HashMap::new(),
+            queue: BTreeMap::new(),
+            user_orders: HashMap::new(),
+            notional: Decimal::ZERO,
+            price_levels: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_user_order_count() {
+        let mut depth = new_ask_depth();
+        for order_id in 1..4 {
+            depth.add(&BookOrder {
+                order_id,
+                user_id: 7,
+                size: dec("1"),
+                price: dec("100"),
+                side: SIDE_SELL,
+                ..Default::default()
+            });
+        }
+        // adding an order already on the book replaces it
+        depth.add(&depth.orders[&1].clone());
+        assert_eq!(depth.user_order_count(7), 3);
+        assert_eq!(depth.user_order_count(8), 0);
+
+        depth.decr_size(1, &dec("0.5")).unwrap();
+        assert_eq!(depth.user_order_count(7), 3);
+        depth.decr_size(1, &dec("0.5")).unwrap();
+        depth.decr_size(2, &dec("1")).unwrap();
+        depth.decr_size(3, &dec("1")).unwrap();
+        assert_eq!(depth.user_order_count(7), 0);
+        assert!(depth.user_orders.is_empty());
+    }
+
+    #[test]
+    fn test_decr_size_guard() {
+        let mut depth = new_ask_depth();
+        depth.add(&BookOrder {
+            order_id: 1,
+            size: dec("1"),
+            price: dec("100"),
+            side: SIDE_SELL,
+            ..Default::default()
+        });
+
+        assert_eq!(
+            depth.decr_size(1, &dec("1.5")),
+            Err(CustomError::InsufficientSize {
+                order_id: 1,
+                size: dec("1"),
+                decrement: dec("1.5"),
+            })
+        );
+        assert!(matches!(
+            depth.decr_size(1, &dec("-1")),
+            Err(CustomError::NegativeSize { order_id: 1, .. })
+        ));
+        assert_eq!(depth.decr_size(2, &dec("1")), Err(CustomError::NotFound(2)));
+        assert_eq!(depth.orders[&1].size, dec("1"));
+        assert_eq!(depth.queue.len(), 1);
+
+        depth.decr_size(1, &dec("0.4")).unwrap();
+        assert_eq!(depth.orders[&1].size, dec("0.6"));
+        assert!(depth.decr_size(1, &dec("1")).is_err());
+
+        // decrementing the full remaining size, as cancel does, removes the order
+        let remaining = depth.orders[&1].size;
+        depth.decr_size(1, &remaining).unwrap();
+        assert!(depth.orders.is_empty());
+        assert!(depth.queue.is_empty());
+    }
+
+    #[test]
+    fn test_notional() {
+        let mut depth = new_ask_depth();
+        let order = |order_id: u64, price: &str, size: &str| BookOrder {
+            order_id,
+            price: dec(price),
+            size: dec(size),
+            side: SIDE_SELL,
+            ..Default::default()
+        };
+        depth.add(&order(1, "100", "2"));
+        depth.add(&order(2, "101", "1"));
+        assert_eq!(depth.notional, dec("301"));
+
+        depth.decr_size(1, &dec("0.5")).unwrap();
+        assert_eq!(depth.notional, dec("251"));
+        depth.decr_size(2, &dec("1")).unwrap();
+        assert_eq!(depth.notional, dec("150"));
+
+        // put again with another size, only the new one counts
+        depth.add(&order(1, "100", "1"));
+        assert_eq!(depth.notional, dec("100"));
+        assert!(depth.decr_size(1, &dec("2")).is_err());
+        assert_eq!(depth.notional, dec("100"));
+    }
+
+    #[test]
+    fn test_level_orders() {
+        let mut depth = new_ask_depth();
+        for (order_id, price, priority_class) in [
+            (1, "100", 0),
+            (2, "101", 0),
+            (3, "100", 1),
+            (4, "99", 0),
+            (5, "100", 0),
+        ]
+        .iter()
+        {
+            depth.add(&BookOrder {
+                order_id: *order_id,
+                size: dec("1"),
+                price: dec(price),
+                priority_class: *priority_class,
+                side: SIDE_SELL,
+                ..Default::default()
+            });
+        }
+
+        let order_ids: Vec<u64> = depth
+            .level_orders(&dec("100"))
+            .map(|o| o.order_id)
+            .collect();
+        assert_eq!(order_ids, vec![3, 1, 5]);
+        assert_eq!(depth.level_orders(&dec("100.5")).count(), 0);
+    }
+
+    #[test]
+    fn test_size_in_range() {
+        let mut depth = new_ask_depth();
+        for (order_id, price, priority_class) in [
+            (1, "99", 1),
+            (2, "100", 0),
+            (3, "100", 1),
+            (4, "101", 1),
+            (5, "101", 0),
+            (6, "102", 0),
+        ]
+        .iter()
+        {
+            depth.add(&BookOrder {
+                order_id: *order_id,
+                size: dec(&order_id.to_string()),
+                price: dec(price),
+                priority_class: *priority_class,
+                side: SIDE_SELL,
+                ..Default::default()
+            });
+        }
+
+        // every priority class at both bounds is taken
+        assert_eq!(depth.size_in_range(&dec("100"), &dec("101")), dec("14"));
+        assert_eq!(depth.size_in_range(&dec("99"), &dec("99")), dec("1"));
+        assert!(depth.size_in_range(&dec("99.5"), &dec("99.9")).is_zero());
+    }
+
+    #[test]
+    fn test_price_levels() {
+        let mut depth = new_ask_depth();
+        let order = |order_id: u64, price: &str, size: &str| BookOrder {
+            order_id,
+            size: dec(size),
+            price: dec(price),
+            side: SIDE_SELL,
+            ..Default::default()
+        };
+        depth.add(&order(1, "101", "1"));
+        depth.add(&order(2, "100", "2"));
+        depth.add(&order(3, "101", "3"));
+        assert_eq!(
+            depth.price_levels,
+            vec![(dec("100"), dec("2")), (dec("101"), dec("4"))]
+        );
+        assert_eq!(depth.top_levels(1), &[(dec("100"), dec("2"))][..]);
+        assert_eq!(depth.top_levels(5).len(), 2);
+
+        // added again with another size
+        depth.add(&order(3, "101", "0.5"));
+        depth.decr_size(2, &dec("2")).unwrap();
+        depth.decr_size(1, &dec("0.5")).unwrap();
+        assert_eq!(depth.price_levels, vec![(dec("101"), dec("1"))]);
+        assert_eq!(depth.price_levels, depth.levels(usize::MAX));
+    }
+}
@@ -12,7 +12,6 @@ use crate::matching::log::LogTrait;
 use crate::matching::order_book::{OrderBook, OrderBookSnapshot};
 use crate::matching::redis_snapshot::RedisSnapshotStore;
 use crate::models::models::{Order, Product};
-use crate::models::types::{OrderStatus, TimeInForceType};
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Snapshot {
@@ -159,40 +158,7 @@ impl Engine {
         loop {
             select! {
                 Some(offset_order) = order_rx.recv() => {
-                    let mut logs= Vec::default();
-                    match offset_order.order.status {
-                        OrderStatus::OrderStatusCancelling => {
-                            logs = self.order_book.cancel_order(&offset_order.order);
-                        }
-                        _ => {
-                            match offset_order.order.time_in_force {
-                                TimeInForceType::ImmediateOrCancel => {
-                                    logs = self.order_book.apply_order(&offset_order.order);
-                                    let ioc_logs = self.order_book.cancel_order(&offset_order.order);
-                                    if !ioc_logs.is_empty() {
-                                        logs.extend(ioc_logs);
-                                    }
-                                },
-                                TimeInForceType::GoodTillCrossing => {
-                                    if self.order_book.is_order_will_not_match(&offset_order.order) {
-                                        logs = self.order_book.apply_order(&offset_order.order);
-                                    } else {
-                                        logs = self.order_book.nullify_order(&offset_order.order);
-                                    }
-                                },
-                                TimeInForceType::FillOrKill => {
-                                 if self.order_book.is_order_will_full_match(&offset_order.order) {
-                                        logs = self.order_book.apply_order(&offset_order.order);
-                                    } else {
-                                        logs = self.order_book.nullify_order(&offset_order.order);
-                                    }
-                                },
-                                TimeInForceType::GoodTillCanceled => {
-                                    logs = self.order_book.apply_order(&offset_order.order);
-                                },
-                            }
-                        }
-                    }
+                    let logs = self.order_book.process_order(&offset_order.order);
 
                     for log in logs {
                         if let Err(e) = log_tx.send(log).await{
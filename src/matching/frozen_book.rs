@@ -0,0 +1,71 @@
+use std::sync::Arc;
+
+use rust_decimal::Decimal;
+
+use crate::matching::depth::Levels;
+
+// immutable depth of a book at the time it was frozen, clones share the same levels
+#[derive(Debug, Clone)]
+pub struct FrozenBook {
+    // (price, size) levels, best first
+    bids: Arc<Vec<(Decimal, Decimal)>>,
+    asks: Arc<Vec<(Decimal, Decimal)>>,
+}
+
+impl FrozenBook {
+    pub fn new(bids: Vec<(Decimal, Decimal)>, asks: Vec<(Decimal, Decimal)>) -> Self {
+        FrozenBook {
+            bids: Arc::new(bids),
+            asks: Arc::new(asks),
+        }
+    }
+
+    pub fn best_bid(&self) -> Option<Decimal> {
+        self.bids.first().map(|(price, _)| *price)
+    }
+
+    pub fn best_ask(&self) -> Option<Decimal> {
+        self.asks.first().map(|(price, _)| *price)
+    }
+
+    pub fn l2_depth(&self, levels: usize) -> (Levels, Levels) {
+        (
+            self.bids.iter().take(levels).cloned().collect(),
+            self.asks.iter().take(levels).cloned().collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use std::thread;
+
+    use rust_decimal::Decimal;
+
+    use crate::matching::frozen_book::FrozenBook;
+
+    fn dec(s: &str) -> Decimal {
+        Decimal::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn test_frozen_book() {
+        let frozen = FrozenBook::new(
+            vec![(dec("99"), dec("1")), (dec("98"), dec("2"))],
+            vec![(dec("101"), dec("3"))],
+        );
+        assert_eq!(frozen.best_bid(), Some(dec("99")));
+        assert_eq!(frozen.best_ask(), Some(dec("101")));
+        assert_eq!(
+            frozen.l2_depth(1),
+            (vec![(dec("99"), dec("1"))], vec![(dec("101"), dec("3"))])
+        );
+
+        // clones are shared across reader threads
+        let reader = frozen.clone();
+        let depth = thread::spawn(move || reader.l2_depth(10)).join().unwrap();
+        assert_eq!(depth, frozen.l2_depth(10));
+        assert_eq!(FrozenBook::new(vec![], vec![]).best_bid(), None);
+    }
+}
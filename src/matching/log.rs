@@ -7,12 +7,15 @@ use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 use crate::matching::order_book::BookOrder;
 use crate::models::types::*;
+use crate::utils::error::CustomError;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum LogType {
     LogTypeMatch,
     LogTypeOpen,
     LogTypeDone,
+    LogTypeHeartbeat,
+    LogTypeChange,
 }
 
 pub fn serialize_log_type<S>(log_type: &LogType, serializer: S) -> Result<S::Ok, S::Error>
@@ -23,6 +26,8 @@ where
         LogType::LogTypeMatch => "match",
         LogType::LogTypeOpen => "open",
         LogType::LogTypeDone => "done",
+        LogType::LogTypeHeartbeat => "heartbeat",
+        LogType::LogTypeChange => "change",
     };
     serializer.serialize_str(string)
 }
@@ -36,16 +41,84 @@ where
         "match" => Ok(LogType::LogTypeMatch),
         "open" => Ok(LogType::LogTypeOpen),
         "done" => Ok(LogType::LogTypeDone),
+        "heartbeat" => Ok(LogType::LogTypeHeartbeat),
+        "change" => Ok(LogType::LogTypeChange),
         _ => Err(serde::de::Error::custom("invalid log_type string")),
     }
 }
 
 pub trait LogTrait: erased_serde::Serialize {
     fn get_seq(&self) -> u64;
+
+    fn get_base(&self) -> &Base;
+
+    fn to_event(&self) -> LogEvent;
+
+    fn log_type(&self) -> LogType {
+        self.get_base().r#type.clone()
+    }
+
+    fn product_id(&self) -> &str {
+        &self.get_base().product_id
+    }
+
+    fn time(&self) -> DateTime<Utc> {
+        Utc.timestamp_nanos(self.get_base().time as i64)
+    }
+
+    // unwrap the boxed log back into a match log, none for the other log types
+    fn into_match_log(self: Box<Self>) -> Option<MatchLog> {
+        None
+    }
 }
 
 serialize_trait_object!(LogTrait);
 
+// the decimal fields of the logs which deserialize to zero when absent, the compact json
+// omits them when they are zero. the optional fields are skipped by serde when none, so
+// every defaulted field of the logs is either here or never serialized as its default
+const COMPACT_FIELDS: [&str; 1] = ["remaining_funds"];
+
+// the json of the log without the fields holding their default, smaller on the wire, the
+// logs deserialize from it the same as from the full json
+pub fn to_compact_json(log: &dyn LogTrait) -> Result<serde_json::Value, CustomError> {
+    let mut value = serde_json::to_value(log).map_err(|e| CustomError::new(&e))?;
+    if let Some(fields) = value.as_object_mut() {
+        for name in COMPACT_FIELDS.iter() {
+            let is_zero = fields
+                .get(*name)
+                .and_then(|v| v.as_str())
+                .is_some_and(|v| v.parse::<Decimal>().is_ok_and(|d| d.is_zero()));
+            if is_zero {
+                fields.remove(*name);
+            }
+        }
+    }
+    Ok(value)
+}
+
+// every kind of log as one type, for consumers which need to tell them apart
+#[derive(Debug, Clone)]
+pub enum LogEvent {
+    Match(MatchLog),
+    Open(OpenLog),
+    Done(DoneLog),
+    Heartbeat(HeartbeatLog),
+    Change(ChangeLog),
+}
+
+impl LogEvent {
+    pub fn get_base(&self) -> &Base {
+        match self {
+            LogEvent::Match(log) => &log.base,
+            LogEvent::Open(log) => &log.base,
+            LogEvent::Done(log) => &log.base,
+            LogEvent::Heartbeat(log) => &log.base,
+            LogEvent::Change(log) => &log.base,
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Base {
     #[serde(serialize_with = "serialize_log_type")]
@@ -69,12 +142,22 @@ pub struct OpenLog {
     #[serde(serialize_with = "serialize_time_in_force_type")]
     #[serde(deserialize_with = "deserialize_time_in_force_type")]
     pub time_in_force: TimeInForceType,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_order_id: Option<String>,
 }
 
 impl LogTrait for OpenLog {
     fn get_seq(&self) -> u64 {
         self.base.sequence
     }
+
+    fn get_base(&self) -> &Base {
+        &self.base
+    }
+
+    fn to_event(&self) -> LogEvent {
+        LogEvent::Open(self.clone())
+    }
 }
 
 pub fn new_open_log(log_seq: u64, product_id: &str, taker_order: &BookOrder) -> OpenLog {
@@ -97,6 +180,7 @@ pub fn new_open_log(log_seq: u64, product_id: &str, taker_order: &BookOrder) ->
         price: taker_order.price,
         side: taker_order.side.clone(),
         time_in_force: taker_order.time_in_force.clone(),
+        client_order_id: taker_order.client_order_id.clone(),
     }
 }
 
@@ -107,6 +191,9 @@ pub struct DoneLog {
     pub user_id: u64,
     pub price: Decimal,
     pub remaining_size: Decimal,
+    // unspent funds of a market buy which are to be refunded, zero for the others
+    #[serde(default)]
+    pub remaining_funds: Decimal,
     #[serde(serialize_with = "serialize_done_reason")]
     #[serde(deserialize_with = "deserialize_done_reason")]
     pub reason: DoneReason,
@@ -116,12 +203,25 @@ pub struct DoneLog {
     #[serde(serialize_with = "serialize_time_in_force_type")]
     #[serde(deserialize_with = "deserialize_time_in_force_type")]
     pub time_in_force: TimeInForceType,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_order_id: Option<String>,
+    // of a rejected fill-or-kill, the size the book could have filled at the time
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fillable_size: Option<Decimal>,
 }
 
 impl LogTrait for DoneLog {
     fn get_seq(&self) -> u64 {
         self.base.sequence
     }
+
+    fn get_base(&self) -> &Base {
+        &self.base
+    }
+
+    fn to_event(&self) -> LogEvent {
+        LogEvent::Done(self.clone())
+    }
 }
 
 pub fn new_done_log(
@@ -149,9 +249,12 @@ pub fn new_done_log(
         user_id: order.user_id,
         price: order.price,
         remaining_size: remaining_size.clone(),
+        remaining_funds: order.funds,
         reason: reason.clone(),
         side: order.side.clone(),
         time_in_force: order.time_in_force.clone(),
+        client_order_id: order.client_order_id.clone(),
+        fillable_size: None,
     }
 }
 
@@ -166,20 +269,50 @@ pub struct MatchLog {
     #[serde(serialize_with = "serialize_side")]
     #[serde(deserialize_with = "deserialize_side")]
     pub side: Side,
+    // side of the aggressor, always the opposite of the maker side, none in the logs
+    // written before it was recorded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[serde(serialize_with = "serialize_opt_side")]
+    #[serde(deserialize_with = "deserialize_opt_side")]
+    pub taker_side: Option<Side>,
     pub price: Decimal,
     pub size: Decimal,
+    // size of the maker left on the book after this match, zero when it's filled, none in
+    // the logs written before it was recorded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub maker_remaining_size: Option<Decimal>,
+    // true when the match uncrossed the book at the end of an auction, neither order was
+    // the aggressor then and the bid is recorded as the taker
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub auction: bool,
     #[serde(serialize_with = "serialize_time_in_force_type")]
     #[serde(deserialize_with = "deserialize_time_in_force_type")]
     pub taker_time_in_force: TimeInForceType,
     #[serde(serialize_with = "serialize_time_in_force_type")]
     #[serde(deserialize_with = "deserialize_time_in_force_type")]
     pub maker_time_in_force: TimeInForceType,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub taker_client_order_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub maker_client_order_id: Option<String>,
 }
 
 impl LogTrait for MatchLog {
     fn get_seq(&self) -> u64 {
         self.base.sequence
     }
+
+    fn get_base(&self) -> &Base {
+        &self.base
+    }
+
+    fn to_event(&self) -> LogEvent {
+        LogEvent::Match(self.clone())
+    }
+
+    fn into_match_log(self: Box<Self>) -> Option<MatchLog> {
+        Some(*self)
+    }
 }
 
 pub fn new_match_log(
@@ -214,9 +347,210 @@ pub fn new_match_log(
         taker_user_id: taker_order.user_id,
         maker_user_id: maker_order.user_id,
         side: maker_order.side.clone(),
+        taker_side: Some(taker_order.side.clone()),
         price: price.clone(),
         size: size.clone(),
+        maker_remaining_size: Some(maker_order.size),
+        auction: false,
         taker_time_in_force: taker_order.time_in_force.clone(),
         maker_time_in_force: maker_order.time_in_force.clone(),
+        taker_client_order_id: taker_order.client_order_id.clone(),
+        maker_client_order_id: maker_order.client_order_id.clone(),
+    }
+}
+
+// the book is alive, sent when there are no other logs, it takes a sequence so that
+// a gap in the sequences is never mistaken for a quiet book
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct HeartbeatLog {
+    pub base: Base,
+    pub best_bid: Option<Decimal>,
+    pub best_ask: Option<Decimal>,
+}
+
+impl LogTrait for HeartbeatLog {
+    fn get_seq(&self) -> u64 {
+        self.base.sequence
+    }
+
+    fn get_base(&self) -> &Base {
+        &self.base
+    }
+
+    fn to_event(&self) -> LogEvent {
+        LogEvent::Heartbeat(self.clone())
+    }
+}
+
+pub fn new_heartbeat_log(
+    log_seq: u64,
+    product_id: &str,
+    best_bid: Option<Decimal>,
+    best_ask: Option<Decimal>,
+) -> HeartbeatLog {
+    HeartbeatLog {
+        base: Base {
+            r#type: LogType::LogTypeHeartbeat,
+            sequence: log_seq,
+            product_id: product_id.to_string(),
+            time: Utc::now().timestamp_nanos() as u64,
+        },
+        best_bid,
+        best_ask,
+    }
+}
+
+// a resting order moved to another price, e.g. a pegged order following its reference,
+// it keeps its id and size and rests at new_price from then on
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ChangeLog {
+    pub base: Base,
+    pub order_id: u64,
+    pub user_id: u64,
+    pub old_price: Decimal,
+    pub new_price: Decimal,
+    pub remaining_size: Decimal,
+    #[serde(serialize_with = "serialize_side")]
+    #[serde(deserialize_with = "deserialize_side")]
+    pub side: Side,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_order_id: Option<String>,
+}
+
+impl LogTrait for ChangeLog {
+    fn get_seq(&self) -> u64 {
+        self.base.sequence
+    }
+
+    fn get_base(&self) -> &Base {
+        &self.base
+    }
+
+    fn to_event(&self) -> LogEvent {
+        LogEvent::Change(self.clone())
+    }
+}
+
+pub fn new_change_log(
+    log_seq: u64,
+    product_id: &str,
+    order: &BookOrder,
+    new_price: &Decimal,
+) -> ChangeLog {
+    debug!(
+        "new_change_log: product_id: {} | log_seq:{} | order_id:{} | price:{} -> {}",
+        product_id, log_seq, order.order_id, order.price, new_price
+    );
+    ChangeLog {
+        base: Base {
+            r#type: LogType::LogTypeChange,
+            sequence: log_seq,
+            product_id: product_id.to_string(),
+            time: Utc::now().timestamp_nanos() as u64,
+        },
+        order_id: order.order_id,
+        user_id: order.user_id,
+        old_price: order.price,
+        new_price: *new_price,
+        remaining_size: order.size,
+        side: order.side.clone(),
+        client_order_id: order.client_order_id.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use chrono::prelude::*;
+    use rust_decimal::Decimal;
+
+    use crate::matching::log::{
+        new_done_log, new_match_log, new_open_log, to_compact_json, DoneLog, LogTrait, LogType,
+        MatchLog,
+    };
+    use crate::matching::order_book::BookOrder;
+    use crate::models::types::*;
+
+    #[test]
+    fn test_log_accessors() {
+        let before = Utc::now();
+        let log: Box<dyn LogTrait> = Box::new(new_open_log(3, "BTC-USD", &BookOrder::default()));
+
+        assert_eq!(log.get_seq(), 3);
+        assert!(matches!(log.log_type(), LogType::LogTypeOpen));
+        assert_eq!(log.product_id(), "BTC-USD");
+        assert!(log.time() >= before && log.time() <= Utc::now());
+    }
+
+    #[test]
+    fn test_compact_json() {
+        let order = BookOrder {
+            order_id: 1,
+            price: Decimal::from_str("100").unwrap(),
+            ..Default::default()
+        };
+        let log = new_done_log(2, "BTC-USD", &order, &Decimal::ZERO, &DONE_REASON_FILLED);
+        let full = serde_json::to_value(&log).unwrap();
+        let compact = to_compact_json(&log).unwrap();
+        assert!(full.get("remaining_funds").is_some());
+        assert!(compact.get("remaining_funds").is_none());
+        assert!(compact.get("client_order_id").is_none());
+        assert_eq!(compact["remaining_size"], "0");
+
+        let decoded: DoneLog = serde_json::from_str(&compact.to_string()).unwrap();
+        assert_eq!(serde_json::to_value(&decoded).unwrap(), full);
+
+        // non-zero values are kept
+        let order = BookOrder {
+            funds: Decimal::from_str("2.5").unwrap(),
+            client_order_id: Some("c-1".to_string()),
+            ..order
+        };
+        let log = new_done_log(3, "BTC-USD", &order, &Decimal::ZERO, &DONE_REASON_CANCELLED);
+        let compact = to_compact_json(&log).unwrap();
+        assert_eq!(compact["remaining_funds"], "2.5");
+        assert_eq!(compact["client_order_id"], "c-1");
+    }
+
+    #[test]
+    fn test_deserialize_old_match_log() {
+        // written before taker_side and maker_remaining_size were added
+        let json = r#"{"base":{"type":"match","sequence":3,"product_id":"BTC-USD","time":1695783003020967000},"trade_seq":1,"taker_order_id":2,"maker_order_id":1,"taker_user_id":2,"maker_user_id":1,"side":"sell","price":"100","size":"1","taker_time_in_force":"GTC","maker_time_in_force":"GTC"}"#;
+        let log: MatchLog = serde_json::from_str(json).unwrap();
+        assert!(log.taker_side.is_none());
+        assert!(log.maker_remaining_size.is_none());
+        assert!(!log.auction);
+        assert_eq!(log.trade_seq, 1);
+
+        let value = serde_json::to_value(&log).unwrap();
+        assert!(value.get("taker_side").is_none());
+        assert!(value.get("maker_remaining_size").is_none());
+        assert!(value.get("auction").is_none());
+        assert_eq!(value["side"], "sell");
+    }
+
+    #[test]
+    fn test_compact_json_match_log() {
+        let maker = BookOrder {
+            order_id: 1,
+            side: SIDE_SELL,
+            ..Default::default()
+        };
+        let taker = BookOrder {
+            order_id: 2,
+            side: SIDE_BUY,
+            ..Default::default()
+        };
+        let one = Decimal::from_str("1").unwrap();
+        // the maker is filled, which is told apart from a log without the remaining size
+        let log = new_match_log(3, "BTC-USD", 1, &taker, &maker, &one, &one);
+        let full = serde_json::to_value(&log).unwrap();
+        let compact = to_compact_json(&log).unwrap();
+        assert_eq!(full["maker_remaining_size"], "0");
+        assert_eq!(compact["maker_remaining_size"], "0");
+        assert!(compact.get("taker_client_order_id").is_none());
+        let decoded: MatchLog = serde_json::from_str(&compact.to_string()).unwrap();
+        assert_eq!(serde_json::to_value(&decoded).unwrap(), full);
     }
 }
@@ -0,0 +1,474 @@
+use rust_decimal::Decimal;
+
+use crate::matching::log::{
+    Base, ChangeLog, DoneLog, HeartbeatLog, LogEvent, LogType, MatchLog, OpenLog,
+};
+use crate::models::types::*;
+use crate::utils::error::CustomError;
+
+// fixed binary layout of the logs, little endian:
+// type byte, sequence u64, time u64, product_id (u16 length + bytes), then the fields of
+// the log in declaration order. ids are u64, decimals are the 16 bytes of Decimal::serialize
+// (96-bit mantissa with its scale, exact), enums are one byte and an optional string is a
+// presence byte followed by the string, the same goes for an optional decimal. a bool is
+// one byte
+const TYPE_MATCH: u8 = 0;
+const TYPE_OPEN: u8 = 1;
+const TYPE_DONE: u8 = 2;
+const TYPE_HEARTBEAT: u8 = 3;
+const TYPE_CHANGE: u8 = 4;
+
+struct Encoder {
+    buf: Vec<u8>,
+}
+
+impl Encoder {
+    fn put_u8(&mut self, v: u8) {
+        self.buf.push(v);
+    }
+
+    fn put_u64(&mut self, v: u64) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn put_decimal(&mut self, v: &Decimal) {
+        self.buf.extend_from_slice(&v.serialize());
+    }
+
+    // strings are prefixed by a u16 length, a longer one is refused rather than truncated
+    fn put_str(&mut self, v: &str) -> Result<(), CustomError> {
+        if v.len() > u16::MAX as usize {
+            return Err(CustomError::Encode(format!(
+                "string of {} bytes exceeds {}",
+                v.len(),
+                u16::MAX
+            )));
+        }
+        self.buf.extend_from_slice(&(v.len() as u16).to_le_bytes());
+        self.buf.extend_from_slice(v.as_bytes());
+        Ok(())
+    }
+
+    fn put_opt_str(&mut self, v: &Option<String>) -> Result<(), CustomError> {
+        match v {
+            Some(s) => {
+                self.put_u8(1);
+                self.put_str(s)
+            }
+            None => {
+                self.put_u8(0);
+                Ok(())
+            }
+        }
+    }
+
+    fn put_opt_decimal(&mut self, v: &Option<Decimal>) {
+        match v {
+            Some(d) => {
+                self.put_u8(1);
+                self.put_decimal(d);
+            }
+            None => self.put_u8(0),
+        }
+    }
+
+    fn put_bool(&mut self, v: bool) {
+        self.put_u8(v as u8);
+    }
+
+    fn put_opt_side(&mut self, v: &Option<Side>) {
+        match v {
+            Some(side) => {
+                self.put_u8(1);
+                self.put_side(side);
+            }
+            None => self.put_u8(0),
+        }
+    }
+
+    fn put_side(&mut self, v: &Side) {
+        self.put_u8(match v {
+            Side::SideBuy => 0,
+            Side::SideSell => 1,
+        });
+    }
+
+    fn put_time_in_force(&mut self, v: &TimeInForceType) {
+        self.put_u8(match v {
+            TimeInForceType::GoodTillCanceled => 0,
+            TimeInForceType::ImmediateOrCancel => 1,
+            TimeInForceType::GoodTillCrossing => 2,
+            TimeInForceType::FillOrKill => 3,
+        });
+    }
+
+    fn put_done_reason(&mut self, v: &DoneReason) {
+        self.put_u8(match v {
+            DoneReason::DoneReasonFilled => 0,
+            DoneReason::DoneReasonCancelled => 1,
+            DoneReason::DoneReasonStp => 2,
+            DoneReason::DoneReasonRisk => 3,
+            DoneReason::DoneReasonExpired => 4,
+            DoneReason::DoneReasonAdmin => 5,
+        });
+    }
+}
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], CustomError> {
+        if self.bytes.len() - self.pos < n {
+            return Err(CustomError::Decode(format!(
+                "need {} bytes at {}, {} in total",
+                n,
+                self.pos,
+                self.bytes.len()
+            )));
+        }
+        let v = &self.bytes[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(v)
+    }
+
+    fn u8(&mut self) -> Result<u8, CustomError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u64(&mut self) -> Result<u64, CustomError> {
+        let mut v = [0u8; 8];
+        v.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(v))
+    }
+
+    fn decimal(&mut self) -> Result<Decimal, CustomError> {
+        let mut v = [0u8; 16];
+        v.copy_from_slice(self.take(16)?);
+        Ok(Decimal::deserialize(v))
+    }
+
+    fn string(&mut self) -> Result<String, CustomError> {
+        let mut len = [0u8; 2];
+        len.copy_from_slice(self.take(2)?);
+        let v = self.take(u16::from_le_bytes(len) as usize)?;
+        String::from_utf8(v.to_vec()).map_err(|e| CustomError::Decode(e.to_string()))
+    }
+
+    fn opt_string(&mut self) -> Result<Option<String>, CustomError> {
+        match self.u8()? {
+            0 => Ok(None),
+            1 => Ok(Some(self.string()?)),
+            v => Err(CustomError::Decode(format!("invalid option byte {}", v))),
+        }
+    }
+
+    fn opt_decimal(&mut self) -> Result<Option<Decimal>, CustomError> {
+        match self.u8()? {
+            0 => Ok(None),
+            1 => Ok(Some(self.decimal()?)),
+            v => Err(CustomError::Decode(format!("invalid option byte {}", v))),
+        }
+    }
+
+    fn bool(&mut self) -> Result<bool, CustomError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            v => Err(CustomError::Decode(format!("invalid bool {}", v))),
+        }
+    }
+
+    fn opt_side(&mut self) -> Result<Option<Side>, CustomError> {
+        match self.u8()? {
+            0 => Ok(None),
+            1 => Ok(Some(self.side()?)),
+            v => Err(CustomError::Decode(format!("invalid option byte {}", v))),
+        }
+    }
+
+    fn side(&mut self) -> Result<Side, CustomError> {
+        match self.u8()? {
+            0 => Ok(Side::SideBuy),
+            1 => Ok(Side::SideSell),
+            v => Err(CustomError::Decode(format!("invalid side {}", v))),
+        }
+    }
+
+    fn time_in_force(&mut self) -> Result<TimeInForceType, CustomError> {
+        match self.u8()? {
+            0 => Ok(TimeInForceType::GoodTillCanceled),
+            1 => Ok(TimeInForceType::ImmediateOrCancel),
+            2 => Ok(TimeInForceType::GoodTillCrossing),
+            3 => Ok(TimeInForceType::FillOrKill),
+            v => Err(CustomError::Decode(format!("invalid time in force {}", v))),
+        }
+    }
+
+    fn done_reason(&mut self) -> Result<DoneReason, CustomError> {
+        match self.u8()? {
+            0 => Ok(DoneReason::DoneReasonFilled),
+            1 => Ok(DoneReason::DoneReasonCancelled),
+            2 => Ok(DoneReason::DoneReasonStp),
+            3 => Ok(DoneReason::DoneReasonRisk),
+            4 => Ok(DoneReason::DoneReasonExpired),
+            5 => Ok(DoneReason::DoneReasonAdmin),
+            v => Err(CustomError::Decode(format!("invalid done reason {}", v))),
+        }
+    }
+}
+
+impl LogEvent {
+    pub fn encode(&self) -> Result<Vec<u8>, CustomError> {
+        let mut e = Encoder { buf: Vec::new() };
+        e.put_u8(match self {
+            LogEvent::Match(_) => TYPE_MATCH,
+            LogEvent::Open(_) => TYPE_OPEN,
+            LogEvent::Done(_) => TYPE_DONE,
+            LogEvent::Heartbeat(_) => TYPE_HEARTBEAT,
+            LogEvent::Change(_) => TYPE_CHANGE,
+        });
+        let base = self.get_base();
+        e.put_u64(base.sequence);
+        e.put_u64(base.time);
+        e.put_str(&base.product_id)?;
+
+        match self {
+            LogEvent::Match(log) => {
+                e.put_u64(log.trade_seq);
+                e.put_u64(log.taker_order_id);
+                e.put_u64(log.maker_order_id);
+                e.put_u64(log.taker_user_id);
+                e.put_u64(log.maker_user_id);
+                e.put_side(&log.side);
+                e.put_opt_side(&log.taker_side);
+                e.put_decimal(&log.price);
+                e.put_decimal(&log.size);
+                e.put_opt_decimal(&log.maker_remaining_size);
+                e.put_bool(log.auction);
+                e.put_time_in_force(&log.taker_time_in_force);
+                e.put_time_in_force(&log.maker_time_in_force);
+                e.put_opt_str(&log.taker_client_order_id)?;
+                e.put_opt_str(&log.maker_client_order_id)?;
+            }
+            LogEvent::Open(log) => {
+                e.put_u64(log.order_id);
+                e.put_u64(log.user_id);
+                e.put_decimal(&log.remaining_size);
+                e.put_decimal(&log.price);
+                e.put_side(&log.side);
+                e.put_time_in_force(&log.time_in_force);
+                e.put_opt_str(&log.client_order_id)?;
+            }
+            LogEvent::Done(log) => {
+                e.put_u64(log.order_id);
+                e.put_u64(log.user_id);
+                e.put_decimal(&log.price);
+                e.put_decimal(&log.remaining_size);
+                e.put_decimal(&log.remaining_funds);
+                e.put_done_reason(&log.reason);
+                e.put_side(&log.side);
+                e.put_time_in_force(&log.time_in_force);
+                e.put_opt_str(&log.client_order_id)?;
+                e.put_opt_decimal(&log.fillable_size);
+            }
+            LogEvent::Heartbeat(log) => {
+                e.put_opt_decimal(&log.best_bid);
+                e.put_opt_decimal(&log.best_ask);
+            }
+            LogEvent::Change(log) => {
+                e.put_u64(log.order_id);
+                e.put_u64(log.user_id);
+                e.put_decimal(&log.old_price);
+                e.put_decimal(&log.new_price);
+                e.put_decimal(&log.remaining_size);
+                e.put_side(&log.side);
+                e.put_opt_str(&log.client_order_id)?;
+            }
+        }
+        Ok(e.buf)
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<LogEvent, CustomError> {
+        let mut d = Decoder { bytes, pos: 0 };
+        let r#type = match d.u8()? {
+            TYPE_MATCH => LogType::LogTypeMatch,
+            TYPE_OPEN => LogType::LogTypeOpen,
+            TYPE_DONE => LogType::LogTypeDone,
+            TYPE_HEARTBEAT => LogType::LogTypeHeartbeat,
+            TYPE_CHANGE => LogType::LogTypeChange,
+            v => return Err(CustomError::Decode(format!("invalid log type {}", v))),
+        };
+        let sequence = d.u64()?;
+        let time = d.u64()?;
+        let base = Base {
+            r#type,
+            sequence,
+            time,
+            product_id: d.string()?,
+        };
+
+        let event = match base.r#type {
+            LogType::LogTypeMatch => LogEvent::Match(MatchLog {
+                base,
+                trade_seq: d.u64()?,
+                taker_order_id: d.u64()?,
+                maker_order_id: d.u64()?,
+                taker_user_id: d.u64()?,
+                maker_user_id: d.u64()?,
+                side: d.side()?,
+                taker_side: d.opt_side()?,
+                price: d.decimal()?,
+                size: d.decimal()?,
+                maker_remaining_size: d.opt_decimal()?,
+                auction: d.bool()?,
+                taker_time_in_force: d.time_in_force()?,
+                maker_time_in_force: d.time_in_force()?,
+                taker_client_order_id: d.opt_string()?,
+                maker_client_order_id: d.opt_string()?,
+            }),
+            LogType::LogTypeOpen => LogEvent::Open(OpenLog {
+                base,
+                order_id: d.u64()?,
+                user_id: d.u64()?,
+                remaining_size: d.decimal()?,
+                price: d.decimal()?,
+                side: d.side()?,
+                time_in_force: d.time_in_force()?,
+                client_order_id: d.opt_string()?,
+            }),
+            LogType::LogTypeDone => LogEvent::Done(DoneLog {
+                base,
+                order_id: d.u64()?,
+                user_id: d.u64()?,
+                price: d.decimal()?,
+                remaining_size: d.decimal()?,
+                remaining_funds: d.decimal()?,
+                reason: d.done_reason()?,
+                side: d.side()?,
+                time_in_force: d.time_in_force()?,
+                client_order_id: d.opt_string()?,
+                fillable_size: d.opt_decimal()?,
+            }),
+            LogType::LogTypeHeartbeat => LogEvent::Heartbeat(HeartbeatLog {
+                base,
+                best_bid: d.opt_decimal()?,
+                best_ask: d.opt_decimal()?,
+            }),
+            LogType::LogTypeChange => LogEvent::Change(ChangeLog {
+                base,
+                order_id: d.u64()?,
+                user_id: d.u64()?,
+                old_price: d.decimal()?,
+                new_price: d.decimal()?,
+                remaining_size: d.decimal()?,
+                side: d.side()?,
+                client_order_id: d.opt_string()?,
+            }),
+        };
+
+        if d.pos != bytes.len() {
+            return Err(CustomError::Decode(format!(
+                "{} trailing bytes",
+                bytes.len() - d.pos
+            )));
+        }
+        Ok(event)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use rust_decimal::Decimal;
+
+    use crate::matching::log::{
+        new_change_log, new_done_log, new_heartbeat_log, new_match_log, new_open_log, LogEvent,
+    };
+    use crate::matching::order_book::BookOrder;
+    use crate::models::types::*;
+    use crate::utils::error::CustomError;
+
+    fn order(order_id: u64, side: Side, price: &str, size: &str) -> BookOrder {
+        BookOrder {
+            order_id,
+            user_id: order_id + 100,
+            size: Decimal::from_str(size).unwrap(),
+            price: Decimal::from_str(price).unwrap(),
+            side,
+            ..Default::default()
+        }
+    }
+
+    fn to_json(event: &LogEvent) -> serde_json::Value {
+        match event {
+            LogEvent::Match(log) => serde_json::to_value(log).unwrap(),
+            LogEvent::Open(log) => serde_json::to_value(log).unwrap(),
+            LogEvent::Done(log) => serde_json::to_value(log).unwrap(),
+            LogEvent::Heartbeat(log) => serde_json::to_value(log).unwrap(),
+            LogEvent::Change(log) => serde_json::to_value(log).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        let mut maker = order(1, SIDE_SELL, "100.25", "1.5");
+        maker.client_order_id = Some("maker-1".to_string());
+        let mut taker = order(2, SIDE_BUY, "101", "0.75");
+        taker.time_in_force = TimeInForceType::ImmediateOrCancel;
+        let size = taker.size;
+        let mut auction = new_match_log(5, "BTC-USD", 2, &taker, &maker, &maker.price, &size);
+        auction.auction = true;
+
+        let events = [
+            LogEvent::Open(new_open_log(1, "BTC-USD", &maker)),
+            LogEvent::Match(new_match_log(
+                2,
+                "BTC-USD",
+                1,
+                &taker,
+                &maker,
+                &maker.price,
+                &size,
+            )),
+            LogEvent::Done(new_done_log(
+                3,
+                "BTC-USD",
+                &taker,
+                &Decimal::ZERO,
+                &DONE_REASON_STP,
+            )),
+            LogEvent::Heartbeat(new_heartbeat_log(4, "BTC-USD", Some(maker.price), None)),
+            LogEvent::Match(auction),
+            LogEvent::Change(new_change_log(6, "BTC-USD", &maker, &taker.price)),
+        ];
+        for event in events.iter() {
+            let bytes = event.encode().unwrap();
+            let decoded = LogEvent::decode(&bytes).unwrap();
+            assert_eq!(to_json(&decoded), to_json(event));
+            assert!(bytes.len() < serde_json::to_vec(&to_json(event)).unwrap().len());
+
+            assert!(LogEvent::decode(&bytes[..bytes.len() - 1]).is_err());
+            let mut trailing = bytes.clone();
+            trailing.push(0);
+            assert!(LogEvent::decode(&trailing).is_err());
+        }
+        assert!(LogEvent::decode(&[9]).is_err());
+    }
+
+    #[test]
+    fn test_encode_long_client_order_id() {
+        let mut o = order(1, SIDE_SELL, "100", "1");
+        o.client_order_id = Some("x".repeat(u16::MAX as usize));
+        let event = LogEvent::Open(new_open_log(1, "BTC-USD", &o));
+        let decoded = LogEvent::decode(&event.encode().unwrap()).unwrap();
+        assert_eq!(to_json(&decoded), to_json(&event));
+
+        o.client_order_id = Some("x".repeat(u16::MAX as usize + 1));
+        let event = LogEvent::Open(new_open_log(1, "BTC-USD", &o));
+        assert!(matches!(event.encode(), Err(CustomError::Encode(_))));
+    }
+}
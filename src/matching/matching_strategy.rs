@@ -0,0 +1,266 @@
+use std::ops::{Div, Mul, Sub};
+
+use rust_decimal::Decimal;
+
+use crate::matching::order_book::BookOrder;
+use crate::models::types::{OrderType, Side};
+use crate::utils::error::CustomError;
+
+// how a taker is allocated among the makers it crosses. the book walks the plan in the
+// priority order of the makers and keeps the walk guards to itself: self trade prevention,
+// the reference bbo and the price protection may stop the walk or take a maker out, in
+// which case the rest of the taker is allocated again among the makers after it
+pub trait MatchingStrategy {
+    // (maker order id, size) fills of the taker, in the order of makers, which are the
+    // orders of the opposing queue within the limit of the taker, best first
+    fn allocate(
+        &self,
+        taker: &BookOrder,
+        makers: &[BookOrder],
+        base_scale: u32,
+    ) -> Result<Vec<(u64, Decimal)>, CustomError>;
+}
+
+// every maker is filled as much as the taker can before the next one, by price then time
+pub struct PriceTimeStrategy;
+
+impl MatchingStrategy for PriceTimeStrategy {
+    fn allocate(
+        &self,
+        taker: &BookOrder,
+        makers: &[BookOrder],
+        base_scale: u32,
+    ) -> Result<Vec<(u64, Decimal)>, CustomError> {
+        // market-buy is driven by funds, the other orders by size
+        let by_funds = matches!(
+            (&taker.r#type, &taker.side),
+            (OrderType::OrderTypeMarket, Side::SideBuy)
+        );
+        let (mut size_left, mut funds_left) = (taker.size, taker.funds);
+        let arithmetic = |maker: &BookOrder, op: &'static str| CustomError::Arithmetic {
+            taker_id: taker.order_id,
+            maker_id: maker.order_id,
+            op,
+        };
+
+        let mut fills = Vec::new();
+        for maker in makers {
+            let size = if by_funds {
+                // the size the funds buy at the price of the maker
+                let taker_size = funds_left
+                    .checked_div(maker.price)
+                    .ok_or_else(|| arithmetic(maker, "div"))?
+                    .trunc_with_scale(base_scale);
+                Decimal::min(taker_size, maker.size)
+            } else {
+                Decimal::min(size_left, maker.size)
+            };
+
+            // a fill of nothing is not a trade, it must never reach the tape
+            if size.is_zero() {
+                break;
+            }
+
+            // an all-or-none maker is only matched by a taker which completes it
+            if maker.all_or_none && size < maker.size {
+                continue;
+            }
+
+            if by_funds {
+                let funds = size
+                    .checked_mul(maker.price)
+                    .ok_or_else(|| arithmetic(maker, "mul"))?;
+                funds_left = funds_left.sub(funds);
+            } else {
+                size_left = size_left.sub(size);
+            }
+            fills.push((maker.order_id, size));
+        }
+        Ok(fills)
+    }
+}
+
+// the best level the taker can't take whole is shared among its makers in proportion to
+// their sizes, rounded down to base_scale with what is left over going by time. the levels
+// before it are taken whole, all-or-none makers are left out
+pub struct ProRataStrategy;
+
+impl MatchingStrategy for ProRataStrategy {
+    fn allocate(
+        &self,
+        taker: &BookOrder,
+        makers: &[BookOrder],
+        base_scale: u32,
+    ) -> Result<Vec<(u64, Decimal)>, CustomError> {
+        let by_funds = matches!(
+            (&taker.r#type, &taker.side),
+            (OrderType::OrderTypeMarket, Side::SideBuy)
+        );
+        let (mut size_left, mut funds_left) = (taker.size, taker.funds);
+
+        let mut fills = Vec::new();
+        let mut rest = makers;
+        while let Some(first) = rest.first() {
+            let price = first.price;
+            let level_len = rest.iter().take_while(|m| m.price == price).count();
+            let (level, next) = rest.split_at(level_len);
+            rest = next;
+
+            let level: Vec<&BookOrder> = level.iter().filter(|m| !m.all_or_none).collect();
+            let level_size: Decimal = level.iter().map(|m| m.size).sum();
+            if level_size.is_zero() {
+                continue;
+            }
+            let wanted = if by_funds {
+                funds_left
+                    .checked_div(price)
+                    .ok_or(CustomError::Arithmetic {
+                        taker_id: taker.order_id,
+                        maker_id: first.order_id,
+                        op: "div",
+                    })?
+                    .trunc_with_scale(base_scale)
+            } else {
+                size_left
+            };
+            if wanted.is_zero() {
+                break;
+            }
+
+            let sizes: Vec<Decimal> = if wanted >= level_size {
+                level.iter().map(|m| m.size).collect()
+            } else {
+                let mut sizes: Vec<Decimal> = level
+                    .iter()
+                    .map(|m| {
+                        wanted
+                            .mul(m.size)
+                            .div(level_size)
+                            .trunc_with_scale(base_scale)
+                    })
+                    .collect();
+                let mut left_over = wanted.sub(sizes.iter().copied().sum::<Decimal>());
+                for (size, m) in sizes.iter_mut().zip(level.iter()) {
+                    let extra = Decimal::min(left_over, m.size.sub(*size));
+                    *size += extra;
+                    left_over = left_over.sub(extra);
+                }
+                sizes
+            };
+
+            let filled: Decimal = sizes.iter().copied().sum();
+            for (size, m) in sizes.into_iter().zip(level.iter()) {
+                if !size.is_zero() {
+                    fills.push((m.order_id, size));
+                }
+            }
+            if by_funds {
+                funds_left = funds_left.sub(filled.mul(price));
+            } else {
+                size_left = size_left.sub(filled);
+            }
+        }
+        Ok(fills)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use rust_decimal::Decimal;
+
+    use crate::matching::matching_strategy::{
+        MatchingStrategy, PriceTimeStrategy, ProRataStrategy,
+    };
+    use crate::matching::order_book::BookOrder;
+    use crate::models::types::*;
+
+    fn dec(s: &str) -> Decimal {
+        Decimal::from_str(s).unwrap()
+    }
+
+    fn maker(order_id: u64, price: &str, size: &str) -> BookOrder {
+        BookOrder {
+            order_id,
+            price: dec(price),
+            size: dec(size),
+            side: SIDE_SELL,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_price_time_allocate() {
+        let mut all_or_none = maker(2, "100", "2");
+        all_or_none.all_or_none = true;
+        let makers = [
+            maker(1, "100", "1"),
+            all_or_none,
+            maker(3, "101", "1"),
+            maker(4, "102", "5"),
+        ];
+        let taker = BookOrder {
+            order_id: 9,
+            size: dec("2.5"),
+            side: SIDE_BUY,
+            r#type: ORDER_TYPE_LIMIT,
+            ..Default::default()
+        };
+
+        // the all-or-none maker is passed over, it can't be completed after the first one
+        let fills = PriceTimeStrategy.allocate(&taker, &makers, 4).unwrap();
+        assert_eq!(fills, vec![(1, dec("1")), (3, dec("1")), (4, dec("0.5"))]);
+
+        let market = BookOrder {
+            size: Decimal::ZERO,
+            funds: dec("150"),
+            r#type: ORDER_TYPE_MARKET,
+            ..taker.clone()
+        };
+        let fills = PriceTimeStrategy.allocate(&market, &makers, 4).unwrap();
+        assert_eq!(fills, vec![(1, dec("1")), (3, dec("0.495"))]);
+
+        assert!(PriceTimeStrategy
+            .allocate(&taker, &[], 4)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_pro_rata_allocate() {
+        let mut all_or_none = maker(3, "100", "4");
+        all_or_none.all_or_none = true;
+        let makers = [
+            maker(1, "99", "1"),
+            maker(2, "100", "1"),
+            all_or_none,
+            maker(4, "100", "2"),
+            maker(5, "101", "5"),
+        ];
+        let taker = BookOrder {
+            order_id: 9,
+            size: dec("2.0001"),
+            side: SIDE_BUY,
+            r#type: ORDER_TYPE_LIMIT,
+            ..Default::default()
+        };
+
+        // the first level is taken whole, 1.0001 is shared 1:2 by the next one, the unit
+        // left over by the rounding goes to the earlier maker
+        let fills = ProRataStrategy.allocate(&taker, &makers, 4).unwrap();
+        assert_eq!(
+            fills,
+            vec![(1, dec("1")), (2, dec("0.3334")), (4, dec("0.6667"))]
+        );
+
+        let market = BookOrder {
+            size: Decimal::ZERO,
+            funds: dec("399"),
+            r#type: ORDER_TYPE_MARKET,
+            ..taker.clone()
+        };
+        let fills = ProRataStrategy.allocate(&market, &makers, 4).unwrap();
+        assert_eq!(fills, vec![(1, dec("1")), (2, dec("1")), (4, dec("2"))]);
+    }
+}
@@ -1,8 +1,13 @@
+pub mod candle;
 pub mod depth;
 pub mod engine;
+pub mod frozen_book;
 pub mod kafka_log;
 pub mod kafka_order;
 pub mod log;
+pub mod log_codec;
+pub mod matching_strategy;
 pub mod order_book;
 pub mod ordering;
 pub mod redis_snapshot;
+pub mod registry;
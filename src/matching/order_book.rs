@@ -1,23 +1,100 @@
 use std::cmp::Ordering;
 use std::collections::BTreeMap;
+use std::collections::BTreeSet;
 use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::VecDeque;
 use std::ops::{Div, Mul, Sub};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
 
+use chrono::{DateTime, Utc};
 use log::info;
-use rust_decimal::prelude::Zero;
-use rust_decimal::Decimal;
+use rust_decimal::prelude::{ToPrimitive, Zero};
+use rust_decimal::{Decimal, RoundingStrategy};
 use serde::{Deserialize, Serialize};
 
-use crate::matching::depth::{AskDepth, BidDepth};
-use crate::matching::log::{new_done_log, new_match_log, new_open_log, LogTrait};
+use crate::matching::depth::{AskDepth, BidDepth, Level, Levels};
+use crate::matching::frozen_book::FrozenBook;
+use crate::matching::log::{
+    new_change_log, new_done_log, new_heartbeat_log, new_match_log, new_open_log, DoneLog,
+    HeartbeatLog, LogEvent, LogTrait, MatchLog,
+};
+use crate::matching::matching_strategy::{MatchingStrategy, PriceTimeStrategy};
 use crate::matching::ordering::{PriceOrderIdKeyAsc, PriceOrderIdKeyDesc};
-use crate::models::models::{Order, Product};
+use crate::models::models::{Order, Peg, Product};
 use crate::models::types::*;
+use crate::utils::error::CustomError;
 use crate::utils::window::Window;
 
 const ORDER_ID_WINDOW_CAP: u64 = 10000;
+// number of the latest trades kept for mark_price
+const RECENT_TRADES_CAP: usize = 1000;
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+// order ids from SEED_ORDER_ID_BASE on are reserved for the orders created by seed_levels
+pub const SEED_ORDER_ID_BASE: u64 = 1 << 63;
+pub const SEED_USER_ID: u64 = 0;
+
+// what cancel_order did to the order
+#[derive(Debug, Clone)]
+pub enum CancelOutcome {
+    Cancelled(DoneLog),
+    // the order was seen but isn't on the book any more (filled, cancelled or rejected)
+    AlreadyDone,
+    // the order has never been seen
+    NotFound,
+}
+
+// the price the rest of a limit taker which has crossed the book is put at
+#[derive(Debug, Clone, PartialEq)]
+pub enum RestPricePolicy {
+    // the limit price of the order
+    LimitPrice,
+    // the price of the last maker it matched with, which is better than its limit
+    LastMatchPrice,
+}
+
+// where liquidity_histogram_with_overflow puts the orders beyond its last bucket
+#[derive(Debug, Clone, PartialEq)]
+pub enum HistogramOverflow {
+    // add them to the last bucket
+    Clamp,
+    // leave them out
+    Drop,
+}
+
+// what to do when a taker crosses a resting order of the same user
+#[derive(Debug, Clone, PartialEq)]
+pub enum SelfTradePrevention {
+    // let the orders trade with each other
+    None,
+    // cancel the rest of the taker, the maker keeps resting
+    CancelTaker,
+    // cancel the maker and keep on matching the taker
+    CancelMaker,
+    // cancel both the taker and the maker
+    CancelBoth,
+}
+
+// why an order is refused by the book without being matched
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum RejectReason {
+    DuplicateOrderId,
+    PostOnlyCross,
+    FillOrKill,
+    SelfTrade,
+    MarketInAuction,
+    OffLot,
+    MaxOrdersPerUser,
+    TradeThrough,
+    InvalidOrder,
+    Halted,
+    MaxNotionalPerSide,
+    // a quote cancels an order which is not its user's resting order on that side
+    InvalidCancel,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct BookOrder {
     pub order_id: u64,
     pub user_id: u64,
@@ -33,6 +110,20 @@ pub struct BookOrder {
     #[serde(serialize_with = "serialize_time_in_force_type")]
     #[serde(deserialize_with = "deserialize_time_in_force_type")]
     pub time_in_force: TimeInForceType,
+    #[serde(default)]
+    pub priority_class: u8,
+    #[serde(default)]
+    pub all_or_none: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stp_group_id: Option<u64>,
+    #[serde(default)]
+    pub day_order: bool,
+    // the client_oid of the order, carried into the logs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_order_id: Option<String>,
+    // the reference the price of a pegged order follows, see OrderBook::reprice_pegs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peg: Option<Peg>,
 }
 
 impl Default for BookOrder {
@@ -46,10 +137,38 @@ impl Default for BookOrder {
             side: Side::SideBuy,
             r#type: OrderType::OrderTypeLimit,
             time_in_force: TimeInForceType::GoodTillCanceled,
+            priority_class: 0,
+            all_or_none: false,
+            stp_group_id: None,
+            day_order: false,
+            client_order_id: None,
+            peg: None,
+        }
+    }
+}
+
+// whether a taker of the side with the limit (none for unbounded) matches a maker at the price
+fn is_crossing(side: &Side, limit_price: Option<Decimal>, maker_price: &Decimal) -> bool {
+    match (side, limit_price) {
+        (_, None) => true,
+        (Side::SideBuy, Some(limit_price)) => {
+            Ordering::Less != Decimal::cmp(&limit_price, maker_price)
+        }
+        (Side::SideSell, Some(limit_price)) => {
+            Ordering::Greater != Decimal::cmp(&limit_price, maker_price)
         }
     }
 }
 
+// value-preserving, digits beyond the scale are kept (trailing zeros are dropped)
+fn normalize_decimal(value: Decimal, scale: i32) -> Decimal {
+    let mut value = value.normalize();
+    if (value.scale() as i32) < scale {
+        value.rescale(scale as u32);
+    }
+    value
+}
+
 impl BookOrder {
     pub fn new_book_order(order: &Order) -> Self {
         BookOrder {
@@ -61,8 +180,35 @@ impl BookOrder {
             side: order.side.clone(),
             r#type: order.r#type.clone(),
             time_in_force: order.time_in_force.clone(),
+            priority_class: order.priority_class,
+            all_or_none: order.all_or_none,
+            stp_group_id: order.stp_group_id,
+            day_order: order.day_order,
+            client_order_id: if order.client_oid.is_empty() {
+                None
+            } else {
+                Some(order.client_oid.clone())
+            },
+            peg: order.peg.clone(),
+        }
+    }
+
+    // whether a trade between the two orders is a self trade, same user or same stp group
+    pub fn is_same_trader(&self, other: &BookOrder) -> bool {
+        if self.user_id == other.user_id {
+            return true;
+        }
+        match (self.stp_group_id, other.stp_group_id) {
+            (Some(a), Some(b)) => a == b,
+            _ => false,
         }
     }
+
+    pub fn normalize(&mut self, product: &Product) {
+        self.price = normalize_decimal(self.price, product.quote_scale);
+        self.size = normalize_decimal(self.size, product.base_scale);
+        self.funds = normalize_decimal(self.funds, product.quote_scale);
+    }
 }
 
 #[derive(Default, Debug, Serialize, Deserialize, Clone)]
@@ -74,6 +220,26 @@ pub struct OrderBookSnapshot {
     pub order_id_window: Window,
 }
 
+// depth chart of the book, every level with the size accumulated from the best price
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct BookShape {
+    pub bid_prices: Vec<Decimal>,
+    pub bid_sizes: Vec<Decimal>,
+    pub ask_prices: Vec<Decimal>,
+    pub ask_sizes: Vec<Decimal>,
+    pub cumulative_bid: Vec<Decimal>,
+    pub cumulative_ask: Vec<Decimal>,
+}
+
+// the depth payload of the exchange apis, levels are [price, size], sequence is the log_seq
+// the depth was taken at, clients sync it with the log stream
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct DepthResponse {
+    pub sequence: u64,
+    pub bids: Vec<[Decimal; 2]>,
+    pub asks: Vec<[Decimal; 2]>,
+}
+
 pub struct OrderBook {
     pub product: Product,
     pub ask_depths: AskDepth,
@@ -81,6 +247,60 @@ pub struct OrderBook {
     pub trade_seq: u64,
     pub log_seq: u64,
     pub order_id_window: Window,
+    pub self_trade_prevention: SelfTradePrevention,
+    // orders only rest on the book and wait for run_auction while it's on
+    pub auction_mode: bool,
+    pub rest_price_policy: RestPricePolicy,
+    // a limit taker only matches makers which are at least this much better than its price
+    pub min_price_improvement: Decimal,
+    // a limit taker doesn't sweep further than protection_bps from the opposite best on
+    // its arrival, even if its limit allows, the rest is cancelled
+    pub clamp_to_protection: bool,
+    pub protection_bps: Decimal,
+    // matching is paused, new orders are rejected while cancels still go through
+    pub halted: bool,
+    // max number of resting orders per user on each side, the rest of a taker which
+    // would exceed it is cancelled instead of resting
+    pub max_orders_per_user: Option<usize>,
+    // max total price*size resting on each side, the rest of a taker which would push
+    // its side above it is cancelled instead of resting
+    pub max_notional_per_side: Option<Decimal>,
+    // the done log of a rejected fill-or-kill tells the size the book could have filled,
+    // so that the client can send a smaller one
+    pub report_fok_fillable: bool,
+    rejections: HashMap<RejectReason, u64>,
+    // whether the taker of the last applied order came to rest on the book
+    last_order_rested: bool,
+    // called with the elapsed time of every apply_order
+    latency_observer: Option<Box<dyn FnMut(Duration)>>,
+    // allocates the taker among the makers it crosses, price-time unless set otherwise
+    matching_strategy: Rc<dyn MatchingStrategy>,
+    // ids of the pegged orders resting on the book, repriced when their reference moves.
+    // the ids of the ones which left the book are dropped on the next reprice
+    pegs: BTreeSet<u64>,
+    // best (bid, ask) available elsewhere, takers never trade through it
+    reference_bbo: Option<(Decimal, Decimal)>,
+    // done logs of the orders which left the book in the current session
+    session_done_logs: Vec<DoneLog>,
+    // price, size and time of the most recent match
+    last_trade: Option<(Decimal, Decimal, DateTime<Utc>)>,
+    // (price, size) of the latest trades, the oldest first, at most RECENT_TRADES_CAP
+    recent_trades: VecDeque<(Decimal, Decimal)>,
+    // while try_apply_order or quote matches, the trades pushed out of recent_trades by the
+    // new ones, so that they can be put back on rollback
+    evicted_trades: Option<Vec<(Decimal, Decimal)>>,
+    // advanced by every order or cancel processed (rejected ones too) and every other change
+    // of the book, clients compare it to tell whether their copy is stale
+    version: u64,
+}
+
+// the book before a journaled update, what roll_back returns it to
+struct Checkpoint {
+    trade_seq: u64,
+    log_seq: u64,
+    session_done_logs: usize,
+    last_trade: Option<(Decimal, Decimal, DateTime<Utc>)>,
+    rejections: HashMap<RejectReason, u64>,
 }
 
 impl OrderBook {
@@ -90,29 +310,57 @@ impl OrderBook {
             ask_depths: AskDepth {
                 orders: HashMap::<u64, BookOrder>::new(),
                 queue: BTreeMap::<PriceOrderIdKeyAsc, u64>::new(),
+                user_orders: HashMap::new(),
+                notional: Decimal::zero(),
+                price_levels: Vec::new(),
             },
             bid_depths: BidDepth {
                 orders: HashMap::<u64, BookOrder>::new(),
                 queue: BTreeMap::<PriceOrderIdKeyDesc, u64>::new(),
+                user_orders: HashMap::new(),
+                notional: Decimal::zero(),
+                price_levels: Vec::new(),
             },
 
             trade_seq: 0,
             log_seq: 0,
             order_id_window: Window::new(0, ORDER_ID_WINDOW_CAP),
+            self_trade_prevention: SelfTradePrevention::None,
+            auction_mode: false,
+            rest_price_policy: RestPricePolicy::LimitPrice,
+            min_price_improvement: Decimal::zero(),
+            clamp_to_protection: false,
+            protection_bps: Decimal::zero(),
+            halted: false,
+            max_orders_per_user: None,
+            max_notional_per_side: None,
+            report_fok_fillable: false,
+            rejections: HashMap::new(),
+            last_order_rested: false,
+            latency_observer: None,
+            matching_strategy: Rc::new(PriceTimeStrategy),
+            pegs: BTreeSet::new(),
+            reference_bbo: None,
+            session_done_logs: Vec::new(),
+            last_trade: None,
+            recent_trades: VecDeque::new(),
+            evicted_trades: None,
+            version: 0,
         }
     }
 
-    pub fn is_order_will_not_match(&self, order: &Order) -> bool {
-        let mut taker_order = BookOrder::new_book_order(order);
+    // continue the sequences of a previous engine, the first logs carry the seeds + 1
+    pub fn new_order_book_with_seqs(product: &Product, trade_seq: u64, log_seq: u64) -> Self {
+        let mut order_book = OrderBook::new_order_book(product);
+        order_book.trade_seq = trade_seq;
+        order_book.log_seq = log_seq;
+        order_book
+    }
 
-        // If it's a Market-Buy order, set price to infinite high, and if it's market-sell,
-        // set price to zero, which ensures that prices will cross.
-        if let OrderType::OrderTypeMarket = taker_order.r#type {
-            taker_order.price = match taker_order.side {
-                Side::SideBuy => Decimal::MAX,
-                Side::SideSell => Decimal::ZERO,
-            }
-        }
+    pub fn is_order_will_not_match(&self, order: &Order) -> bool {
+        let taker_order = self.new_taker_order(order);
+        // a market order crosses any price
+        let limit_price = self.improved_price(&taker_order);
 
         match taker_order.side {
             // Need to check sell-one price
@@ -121,7 +369,7 @@ impl OrderBook {
                 Some((_, v)) => {
                     let maker_order = self.ask_depths.orders.get(v).unwrap();
                     // if taker's buy price is less than sell-one price
-                    if taker_order.price.lt(&maker_order.price) {
+                    if !is_crossing(&taker_order.side, limit_price, &maker_order.price) {
                         return true;
                     }
                 }
@@ -132,7 +380,7 @@ impl OrderBook {
                 Some((_k, v)) => {
                     let maker_order = self.bid_depths.orders.get(v).unwrap();
                     // if taker's sell price is greater than buy-one price
-                    if taker_order.price.gt(&maker_order.price) {
+                    if !is_crossing(&taker_order.side, limit_price, &maker_order.price) {
                         return true;
                     }
                 }
@@ -143,16 +391,15 @@ impl OrderBook {
     }
 
     pub fn is_order_will_full_match(&self, order: &Order) -> bool {
-        let mut taker_order = BookOrder::new_book_order(order);
+        self.simulate_full_match(order).0
+    }
 
-        // If it's a Market-Buy order, set price to infinite high, and if it's market-sell,
-        // set price to zero, which ensures that prices will cross.
-        if let OrderType::OrderTypeMarket = taker_order.r#type {
-            taker_order.price = match taker_order.side {
-                Side::SideBuy => Decimal::MAX,
-                Side::SideSell => Decimal::ZERO,
-            }
-        }
+    // whether the order would be fully filled, along with the size it would fill
+    fn simulate_full_match(&self, order: &Order) -> (bool, Decimal) {
+        let mut taker_order = self.new_taker_order(order);
+        let mut filled = Decimal::zero();
+        // a market order crosses any price
+        let limit_price = self.improved_price(&taker_order);
 
         match taker_order.side {
             Side::SideBuy => {
@@ -160,7 +407,7 @@ impl OrderBook {
                     let maker_order = self.ask_depths.orders.get(v).unwrap();
 
                     // check whether there is price crossing between the taker and the maker
-                    if Ordering::Less == Decimal::cmp(&taker_order.price, &maker_order.price) {
+                    if !is_crossing(&taker_order.side, limit_price, &maker_order.price) {
                         break;
                     }
 
@@ -170,8 +417,14 @@ impl OrderBook {
                                 break;
                             }
 
+                            // all-or-none makers which the taker can't complete are passed over
+                            if maker_order.all_or_none && taker_order.size < maker_order.size {
+                                continue;
+                            }
+
                             // Take the minimum size of taker and maker as trade size
                             let size = Decimal::min(taker_order.size, maker_order.size);
+                            filled += size;
 
                             // adjust the size of taker order
                             taker_order.size = taker_order.size.sub(size);
@@ -181,21 +434,37 @@ impl OrderBook {
                                 break;
                             }
 
-                            // calculate the size of taker at current price
-                            let taker_size = taker_order
-                                .funds
-                                .div(maker_order.price)
-                                .trunc_with_scale(self.product.base_scale as u32);
+                            // calculate the size of taker at current price, a maker which
+                            // can't be priced doesn't fill anything
+                            let taker_size = match taker_order.funds.checked_div(maker_order.price)
+                            {
+                                Some(v) => v.trunc_with_scale(self.product.base_scale as u32),
+                                None => return (false, filled),
+                            };
                             if taker_size.is_zero() {
+                                // the rest of funds can't buy any more, regard them as spent
+                                taker_order.funds = Decimal::zero();
                                 break;
                             }
 
+                            if maker_order.all_or_none && taker_size < maker_order.size {
+                                continue;
+                            }
+
                             // Take the minimum size of taker and maker as trade size
                             let size = Decimal::min(taker_size, maker_order.size);
                             let funds = size.mul(maker_order.price);
+                            filled += size;
 
                             // adjust the funds of taker order
                             taker_order.funds = taker_order.funds.sub(funds);
+
+                            // maker is able to absorb all the funds, the rest is less than
+                            // one unit at this price (and the following higher prices)
+                            if taker_size.le(&maker_order.size) {
+                                taker_order.funds = Decimal::zero();
+                                break;
+                            }
                         }
                     }
                 }
@@ -205,7 +474,7 @@ impl OrderBook {
                     let maker_order = self.bid_depths.orders.get(v).unwrap();
 
                     // check whether there is price crossing between the taker and the maker
-                    if Ordering::Greater == Decimal::cmp(&taker_order.price, &maker_order.price) {
+                    if !is_crossing(&taker_order.side, limit_price, &maker_order.price) {
                         break;
                     }
 
@@ -213,8 +482,13 @@ impl OrderBook {
                         break;
                     }
 
+                    if maker_order.all_or_none && taker_order.size < maker_order.size {
+                        continue;
+                    }
+
                     // Take the minimum size of taker and maker as trade size
                     let size = Decimal::min(taker_order.size, maker_order.size);
+                    filled += size;
 
                     // adjust the size of taker order
                     taker_order.size = taker_order.size.sub(size);
@@ -222,176 +496,597 @@ impl OrderBook {
             }
         }
 
-        if let OrderType::OrderTypeLimit = taker_order.r#type {
-            if Ordering::Greater == Decimal::cmp(&taker_order.size, &Decimal::zero()) {
-                return false;
+        // the book is exhausted before the size (or funds for market-buy) is used up
+        let remaining = match (&taker_order.r#type, &taker_order.side) {
+            (OrderType::OrderTypeMarket, Side::SideBuy) => taker_order.funds,
+            _ => taker_order.size,
+        };
+        (
+            Ordering::Greater != Decimal::cmp(&remaining, &Decimal::zero()),
+            filled,
+        )
+    }
+
+    // dispatch the order to cancel or matching according to its status and time in force
+    pub fn process_order(&mut self, order: &Order) -> Vec<Box<dyn LogTrait>> {
+        self.last_order_rested = false;
+        if let OrderStatus::OrderStatusCancelling = order.status {
+            let reference = self.peg_reference();
+            let mut logs = self.cancel_order_logs(order);
+            self.reprice_pegs(reference, &mut logs);
+            return logs;
+        }
+        if self.halted {
+            self.version += 1;
+            return self.reject_order(order, RejectReason::Halted);
+        }
+
+        match order.time_in_force {
+            TimeInForceType::ImmediateOrCancel => {
+                let mut logs = self.apply_order(order);
+                let reference = self.peg_reference();
+                self.cancel_ioc_rest(order, &mut logs);
+                self.reprice_pegs(reference, &mut logs);
+                logs
+            }
+            TimeInForceType::GoodTillCrossing => {
+                if self.is_order_will_not_match(order) {
+                    self.apply_order(order)
+                } else {
+                    self.version += 1;
+                    self.reject_order(order, RejectReason::PostOnlyCross)
+                }
+            }
+            TimeInForceType::FillOrKill => {
+                // a malformed or repeated order is refused by apply_order before the book
+                // is searched for its fill
+                if OrderBook::validate_order(order).is_err()
+                    || self.order_id_window.contains(order.id)
+                {
+                    return self.apply_order(order);
+                }
+                let (full, fillable) = self.simulate_full_match(order);
+                if full {
+                    self.apply_order(order)
+                } else {
+                    self.version += 1;
+                    let fillable = if self.report_fok_fillable {
+                        Some(fillable)
+                    } else {
+                        None
+                    };
+                    self.count_rejection(RejectReason::FillOrKill);
+                    self.nullify_order_with_fillable(order, fillable)
+                }
+            }
+            TimeInForceType::GoodTillCanceled => self.apply_order(order),
+        }
+    }
+
+    // the rest is cancelled within the same order, the version has moved already
+    fn cancel_ioc_rest(&mut self, order: &Order, logs: &mut Vec<Box<dyn LogTrait>>) {
+        match self.cancel_order_unversioned(order, DONE_REASON_CANCELLED) {
+            Ok(CancelOutcome::Cancelled(log)) => logs.push(Box::new(log)),
+            // a repeated id may be resting on the other side, that order is left alone
+            Ok(_) | Err(CustomError::WrongSide(_)) => {}
+            Err(e) => panic!("{}", e),
+        }
+        self.last_order_rested = false;
+    }
+
+    // why the time in force refuses the order before it's matched, if it does: a post-only
+    // order must not cross and a fill-or-kill one must fill completely
+    fn time_in_force_refusal(&self, order: &Order) -> Option<RejectReason> {
+        match order.time_in_force {
+            TimeInForceType::GoodTillCrossing if !self.is_order_will_not_match(order) => {
+                Some(RejectReason::PostOnlyCross)
             }
+            TimeInForceType::FillOrKill if !self.simulate_full_match(order).0 => {
+                Some(RejectReason::FillOrKill)
+            }
+            _ => None,
+        }
+    }
+
+    // the logs apply_order would produce for the order, the book itself is left untouched
+    pub fn preview_apply(&self, order: &Order) -> Vec<Box<dyn LogTrait>> {
+        self.preview_view().apply_order(order)
+    }
+
+    // a copy of the book to preview on, everything which drives matching is copied, the
+    // counters and buffers the preview would only add to start empty. the book is taken
+    // apart without `..`, so that a new field has to be decided on here to compile
+    fn preview_view(&self) -> OrderBook {
+        let OrderBook {
+            product,
+            ask_depths,
+            bid_depths,
+            trade_seq,
+            log_seq,
+            order_id_window,
+            self_trade_prevention,
+            auction_mode,
+            rest_price_policy,
+            min_price_improvement,
+            clamp_to_protection,
+            protection_bps,
+            halted,
+            max_orders_per_user,
+            max_notional_per_side,
+            report_fok_fillable,
+            rejections: _,
+            last_order_rested: _,
+            latency_observer: _,
+            matching_strategy,
+            pegs,
+            reference_bbo,
+            session_done_logs: _,
+            last_trade,
+            recent_trades,
+            evicted_trades: _,
+            version,
+        } = self;
+        OrderBook {
+            product: product.clone(),
+            ask_depths: ask_depths.clone(),
+            bid_depths: bid_depths.clone(),
+            trade_seq: *trade_seq,
+            log_seq: *log_seq,
+            order_id_window: order_id_window.clone(),
+            self_trade_prevention: self_trade_prevention.clone(),
+            auction_mode: *auction_mode,
+            rest_price_policy: rest_price_policy.clone(),
+            min_price_improvement: *min_price_improvement,
+            clamp_to_protection: *clamp_to_protection,
+            protection_bps: *protection_bps,
+            halted: *halted,
+            max_orders_per_user: *max_orders_per_user,
+            max_notional_per_side: *max_notional_per_side,
+            report_fok_fillable: *report_fok_fillable,
+            rejections: HashMap::new(),
+            last_order_rested: false,
+            latency_observer: None,
+            matching_strategy: matching_strategy.clone(),
+            pegs: pegs.clone(),
+            reference_bbo: *reference_bbo,
+            session_done_logs: Vec::new(),
+            last_trade: *last_trade,
+            recent_trades: recent_trades.clone(),
+            evicted_trades: None,
+            version: *version,
         }
+    }
+
+    pub fn set_reference_bbo(&mut self, reference_bbo: Option<(Decimal, Decimal)>) {
+        self.reference_bbo = reference_bbo;
+    }
+
+    pub fn set_latency_observer(&mut self, obs: Box<dyn FnMut(Duration)>) {
+        self.latency_observer = Some(obs);
+    }
 
-        true
+    pub fn set_matching_strategy(&mut self, strategy: Rc<dyn MatchingStrategy>) {
+        self.matching_strategy = strategy;
     }
 
     pub fn apply_order(&mut self, order: &Order) -> Vec<Box<dyn LogTrait>> {
-        let mut logs: Vec<Box<dyn LogTrait>> = Vec::new();
+        let mut logs = Vec::new();
+        self.apply_order_into(order, &mut logs);
+        logs
+    }
 
-        // prevent orders from being submitted repeatedly to the matching engine
+    // same as apply_order, but the logs are appended to out, so that a caller applying
+    // orders in a loop can reuse one buffer instead of getting a new vec per order
+    pub fn apply_order_into(&mut self, order: &Order, out: &mut Vec<Box<dyn LogTrait>>) {
+        self.timed(|order_book| order_book.apply_order_untimed(order, out))
+    }
+
+    // run f and report its elapsed time to the latency observer
+    fn timed<T>(&mut self, f: impl FnOnce(&mut Self) -> T) -> T {
+        // only read the clock when somebody is observing
+        let start = self.latency_observer.as_ref().map(|_| Instant::now());
+        let result = f(self);
+        if let Some(start) = start
+            && let Some(obs) = self.latency_observer.as_mut()
+        {
+            obs(start.elapsed());
+        }
+        result
+    }
+
+    fn apply_order_untimed(&mut self, order: &Order, out: &mut Vec<Box<dyn LogTrait>>) {
+        self.last_order_rested = false;
+        self.version += 1;
+
+        // prevent orders from being submitted repeatedly to the matching engine, a repeated
+        // id is a duplicate however the order was refused the first time
         if let Err(e) = self.order_id_window.put(order.id) {
             info!("{}, order_id: {}", e, order.id);
-            return logs;
+            self.count_rejection(RejectReason::DuplicateOrderId);
+            return;
+        }
+        if let Err(e) = OrderBook::validate_order(order) {
+            info!("{}", e);
+            out.extend(self.reject_order(order, RejectReason::InvalidOrder));
+            return;
+        }
+        if self.halted {
+            out.extend(self.reject_order(order, RejectReason::Halted));
+            return;
         }
 
-        let mut taker_order = BookOrder::new_book_order(order);
+        let reference = self.peg_reference();
+        if let Err(e) = self.match_order(order, &mut Vec::new(), out) {
+            panic!("{}", e);
+        }
+        self.reprice_pegs(reference, out);
+    }
 
-        // If it's a Market-Buy order, set price to infinite high, and if it's market-sell,
-        // set price to zero, which ensures that prices will cross.
-        if let OrderType::OrderTypeMarket = taker_order.r#type {
-            taker_order.price = match taker_order.side {
-                Side::SideBuy => Decimal::MAX,
-                Side::SideSell => Decimal::ZERO,
+    // same as apply_order, but only the match logs are returned, for consumers of the trade tape
+    pub fn apply_order_fills_only(&mut self, order: &Order) -> Vec<MatchLog> {
+        self.apply_order(order)
+            .into_iter()
+            .filter_map(|log| log.into_match_log())
+            .collect()
+    }
+
+    // same as apply_order, along with the ids of the makers the taker matched with (also
+    // the partially filled ones) in the order of matching, for notifying their owners
+    pub fn apply_order_touched(&mut self, order: &Order) -> (Vec<Box<dyn LogTrait>>, Vec<u64>) {
+        let logs = self.apply_order(order);
+        let touched = logs
+            .iter()
+            .filter_map(|log| match log.to_event() {
+                LogEvent::Match(log) => Some(log.maker_order_id),
+                _ => None,
+            })
+            .collect();
+        (logs, touched)
+    }
+
+    // same as apply_order, but if matching fails halfway, the book is restored to
+    // the state before the call and the error is returned instead of panicking
+    pub fn try_apply_order(
+        &mut self,
+        order: &Order,
+    ) -> Result<Vec<Box<dyn LogTrait>>, CustomError> {
+        self.timed(|order_book| order_book.try_apply_order_untimed(order))
+    }
+
+    fn try_apply_order_untimed(
+        &mut self,
+        order: &Order,
+    ) -> Result<Vec<Box<dyn LogTrait>>, CustomError> {
+        self.last_order_rested = false;
+        self.version += 1;
+        if let Err(e) = OrderBook::validate_order(order) {
+            self.count_rejection(RejectReason::InvalidOrder);
+            return Err(e);
+        }
+        if self.halted {
+            self.count_rejection(RejectReason::Halted);
+            return Err(CustomError::Halted);
+        }
+
+        let checkpoint = self.checkpoint();
+        let window_put = match self.order_id_window.put_undoable(order.id) {
+            Ok(put) => put,
+            Err(e) => {
+                self.count_rejection(RejectReason::DuplicateOrderId);
+                return Err(e);
             }
+        };
+
+        let mut journal: Vec<BookOrder> = Vec::new();
+        let mut logs: Vec<Box<dyn LogTrait>> = Vec::new();
+        let reference = self.peg_reference();
+        self.evicted_trades = Some(Vec::new());
+        let result = self.match_order(order, &mut journal, &mut logs);
+        let evicted_trades = self.evicted_trades.take().unwrap_or_default();
+        if result.is_err() {
+            self.roll_back(checkpoint, &journal, evicted_trades);
+            self.order_id_window.undo(window_put);
         }
 
-        match taker_order.side {
-            Side::SideBuy => {
-                for (_, v) in &(self.ask_depths.queue.clone()) {
-                    let mut maker_order = self.ask_depths.orders.get(v).unwrap().clone();
+        result.map(|_| {
+            self.reprice_pegs(reference, &mut logs);
+            logs
+        })
+    }
 
-                    let mut size = Decimal::default();
+    fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            trade_seq: self.trade_seq,
+            log_seq: self.log_seq,
+            session_done_logs: self.session_done_logs.len(),
+            last_trade: self.last_trade,
+            rejections: self.rejections.clone(),
+        }
+    }
 
-                    // check whether there is price crossing between the taker and the maker
-                    if Ordering::Less == Decimal::cmp(&taker_order.price, &maker_order.price) {
-                        break;
-                    }
+    // put the makers in journal back as they were before they were touched, and drop the
+    // trades, logs and rejection counts since the checkpoint, evicted_trades are those pushed out meanwhile
+    fn roll_back(
+        &mut self,
+        checkpoint: Checkpoint,
+        journal: &[BookOrder],
+        evicted_trades: Vec<(Decimal, Decimal)>,
+    ) {
+        for o in journal.iter().rev() {
+            match o.side {
+                Side::SideBuy => self.bid_depths.add(o),
+                Side::SideSell => self.ask_depths.add(o),
+            }
+        }
+        // every trade pushed one onto recent_trades
+        for _ in checkpoint.trade_seq..self.trade_seq {
+            self.recent_trades.pop_back();
+        }
+        for evicted in evicted_trades.into_iter().rev() {
+            self.recent_trades.push_front(evicted);
+        }
+        self.trade_seq = checkpoint.trade_seq;
+        self.log_seq = checkpoint.log_seq;
+        self.session_done_logs
+            .truncate(checkpoint.session_done_logs);
+        self.last_trade = checkpoint.last_trade;
+        self.rejections = checkpoint.rejections;
+    }
 
-                    match taker_order.r#type {
-                        OrderType::OrderTypeLimit => {
-                            if taker_order.size.is_zero() {
-                                break;
-                            }
+    // the rules are those of the order itself, see Order::validate
+    pub fn validate_order(order: &Order) -> Result<(), CustomError> {
+        order.validate()
+    }
 
-                            // Take the minimum size of taker and maker as trade size
-                            size = Decimal::min(taker_order.size, maker_order.size);
+    // the worst price a limit taker matches at with min_price_improvement considered, none
+    // for a market order, which is unbounded rather than given a sentinel price, so that no
+    // arithmetic ever runs on an extreme price
+    fn improved_price(&self, taker_order: &BookOrder) -> Option<Decimal> {
+        if let OrderType::OrderTypeMarket = taker_order.r#type {
+            return None;
+        }
+        Some(match taker_order.side {
+            Side::SideBuy => taker_order.price.sub(self.min_price_improvement),
+            Side::SideSell => taker_order.price + self.min_price_improvement,
+        })
+    }
 
-                            // adjust the size of taker order
-                            taker_order.size = taker_order.size.sub(size);
-                        }
-                        OrderType::OrderTypeMarket => {
-                            if taker_order.funds.is_zero() {
-                                break;
-                            }
+    // a notional limit order, given by funds and price, is turned into a normal one with
+    // the size funds can buy at the price, truncated to base_scale
+    fn new_taker_order(&self, order: &Order) -> BookOrder {
+        let mut taker_order = BookOrder::new_book_order(order);
+        if self.product.normalize_scale {
+            taker_order.normalize(&self.product);
+        }
+        if let OrderType::OrderTypeLimit = taker_order.r#type
+            && taker_order.size.is_zero()
+            && !taker_order.funds.is_zero()
+            && !taker_order.price.is_zero()
+        {
+            taker_order.size = taker_order
+                .funds
+                .div(taker_order.price)
+                .trunc_with_scale(self.product.base_scale as u32);
+            taker_order.funds = Decimal::zero();
+        }
+        taker_order
+    }
 
-                            // calculate the size of taker at current price
-                            let taker_size = taker_order
-                                .funds
-                                .div(maker_order.price)
-                                .trunc_with_scale(self.product.base_scale as u32);
+    // match the taker against the book, every maker is recorded in journal
+    // before it is modified, so that the caller is able to undo the matching.
+    // returns whether the order is filled or rests as submitted, none of it cancelled
+    fn match_order(
+        &mut self,
+        order: &Order,
+        journal: &mut Vec<BookOrder>,
+        logs: &mut Vec<Box<dyn LogTrait>>,
+    ) -> Result<bool, CustomError> {
+        let mut taker_order = self.new_taker_order(order);
 
-                            if taker_size.is_zero() {
-                                break;
-                            }
+        // a pegged order arrives at the price of its reference, it's refused when there is
+        // nothing to peg to
+        if let Some(peg) = &taker_order.peg {
+            match self.peg_price(peg, &taker_order.side) {
+                Some(price) => taker_order.price = price,
+                None => {
+                    logs.extend(self.reject_order(order, RejectReason::InvalidOrder));
+                    return Ok(false);
+                }
+            }
+        }
 
-                            // Take the minimum size of taker and maker as trade size
-                            size = Decimal::min(taker_size, maker_order.size);
-                            let funds = size.mul(maker_order.price);
+        // the funds of a notional limit order can't buy a single unit
+        if let OrderType::OrderTypeLimit = taker_order.r#type
+            && taker_order.size.is_zero()
+        {
+            logs.extend(self.reject_order(order, RejectReason::InvalidOrder));
+            return Ok(false);
+        }
 
-                            // adjust the funds of taker order
-                            taker_order.funds = taker_order.funds.sub(funds);
-                        }
-                    }
+        // false once any of the order is cut off, e.g. down to whole lots
+        let mut whole = true;
 
-                    // adjust the size of maker order
-                    if let Err(e) = self.ask_depths.decr_size(maker_order.order_id, &size) {
-                        panic!("{}", e);
-                    }
-                    maker_order.size = maker_order.size.sub(size);
-
-                    // matched, new match log
-                    let (log_seq, trade_seq) = (self.next_log_seq(), self.next_trade_seq());
-                    logs.push(Box::new(new_match_log(
-                        log_seq,
-                        &self.product.id,
-                        trade_seq,
-                        &taker_order,
-                        &maker_order,
-                        &maker_order.price,
-                        &size,
-                    )));
-
-                    // maker is filled
-                    if maker_order.size.is_zero() {
-                        logs.push(Box::new(new_done_log(
-                            self.next_log_seq(),
-                            &self.product.id,
-                            &maker_order,
-                            &maker_order.size,
-                            &DONE_REASON_FILLED,
-                        )));
-                    }
+        // orders driven by size must be whole lots, market-buy is driven by funds
+        if !self.product.lot_size.is_zero()
+            && !matches!(
+                (&taker_order.r#type, &taker_order.side),
+                (OrderType::OrderTypeMarket, Side::SideBuy)
+            )
+        {
+            let size = taker_order
+                .size
+                .div(self.product.lot_size)
+                .floor()
+                .mul(self.product.lot_size);
+            if size != taker_order.size {
+                if self.product.reject_off_lot || size.is_zero() {
+                    logs.extend(self.reject_order(order, RejectReason::OffLot));
+                    return Ok(false);
                 }
+                taker_order.size = size;
+                whole = false;
             }
-            Side::SideSell => {
-                for (_, v) in &(self.bid_depths.queue.clone()) {
-                    let mut maker_order = self.bid_depths.orders.get(v).unwrap().clone();
+        }
 
-                    // check whether there is price crossing between the taker and the maker
-                    if Ordering::Greater == Decimal::cmp(&taker_order.price, &maker_order.price) {
-                        break;
-                    }
+        if self.auction_mode {
+            let queued = matches!(taker_order.r#type, OrderType::OrderTypeLimit);
+            logs.extend(self.queue_for_auction(order, taker_order)?);
+            return Ok(whole && queued);
+        }
 
-                    if taker_order.size.is_zero() {
-                        break;
-                    }
+        // makers beyond this price don't improve enough on the limit of the taker
+        let mut improved_price = self.improved_price(&taker_order);
 
-                    // Take the minimum size of taker and maker as trade size
-                    let size = Decimal::min(taker_order.size, maker_order.size);
+        // bound the market order to the mid (or the opposite best if the own side is empty)
+        // with the slippage, makers beyond that are not matched and the rest is cancelled
+        if let OrderType::OrderTypeMarket = taker_order.r#type
+            && let Some(bps) = order.max_slippage_bps
+            && let Some(reference) = self.mid_price().or(match taker_order.side {
+                Side::SideBuy => self.best_ask(),
+                Side::SideSell => self.best_bid(),
+            })
+        {
+            let band = reference.mul(bps).div(Decimal::from(10000));
+            improved_price = Some(match taker_order.side {
+                Side::SideBuy => reference + band,
+                Side::SideSell => Decimal::max(reference.sub(band), Decimal::zero()),
+            });
+        }
+        // the worst price the sweep of a limit taker may reach under protection
+        let protection_price = match (&taker_order.r#type, &taker_order.side) {
+            (OrderType::OrderTypeLimit, Side::SideBuy) if self.clamp_to_protection => self
+                .best_ask()
+                .map(|p| p + p.mul(self.protection_bps).div(Decimal::from(10000))),
+            (OrderType::OrderTypeLimit, Side::SideSell) if self.clamp_to_protection => self
+                .best_bid()
+                .map(|p| p.sub(p.mul(self.protection_bps).div(Decimal::from(10000)))),
+            _ => None,
+        };
+        // whether the sweep is stopped by the protection price before the limit
+        let mut clamped = false;
+        // price of the last maker the taker has matched with
+        let mut last_match_price: Option<Decimal> = None;
+        // whether the rest of taker is cancelled by self trade prevention
+        let mut stp_taker = false;
+        // whether the rest of taker is cancelled to not trade through the reference bbo
+        let mut trade_through = false;
+        // used to tell whether the taker is matched at all
+        let origin_trade_seq = self.trade_seq;
+        // an all-or-none taker which can't be filled completely rests without matching
+        let aon_rest = taker_order.all_or_none && !self.is_order_will_full_match(order);
 
-                    // adjust the size of taker order
-                    taker_order.size = taker_order.size.sub(size);
+        let (makers, missing) = match aon_rest {
+            true => (Vec::new(), None),
+            false => self.crossing_makers(&taker_order.side, improved_price),
+        };
+        // the worst price the reference bbo lets the taker reach
+        let reference_price = self.reference_bbo.map(|(bid, ask)| match taker_order.side {
+            Side::SideBuy => ask,
+            Side::SideSell => bid,
+        });
+        // the makers from start on are allocated the rest of the taker
+        let mut start = 0;
+        // whether the walk went past every crossing maker
+        let mut walked_all = false;
+        'plan: loop {
+            let plan = self.matching_strategy.allocate(
+                &taker_order,
+                &makers[start..],
+                self.product.base_scale as u32,
+            )?;
+            let mut fills = plan.into_iter().peekable();
+            for (index, maker_order) in makers.iter().enumerate().skip(start) {
+                // the taker is used up, or it can't fill any of the makers left
+                let fill = match fills.peek() {
+                    None => break 'plan,
+                    Some((order_id, size)) => (*order_id == maker_order.order_id).then_some(*size),
+                };
 
-                    // adjust the size of maker order
-                    if let Err(e) = self.bid_depths.decr_size(maker_order.order_id, &size) {
-                        panic!("{}", e);
-                    }
-                    maker_order.size = maker_order.size.sub(size);
-
-                    // matched, new match log
-                    let (log_seq, trade_seq) = (self.next_log_seq(), self.next_trade_seq());
-                    logs.push(Box::new(new_match_log(
-                        log_seq,
-                        &self.product.id,
-                        trade_seq,
-                        &taker_order,
-                        &maker_order,
-                        &maker_order.price,
-                        &size,
-                    )));
-
-                    // maker is filled
-                    if maker_order.size.is_zero() {
-                        logs.push(Box::new(new_done_log(
-                            self.next_log_seq(),
-                            &self.product.id,
-                            &maker_order,
-                            &maker_order.size,
-                            &DONE_REASON_FILLED,
-                        )));
+                if !is_crossing(&taker_order.side, protection_price, &maker_order.price) {
+                    clamped = true;
+                    break 'plan;
+                }
+
+                if !is_crossing(&taker_order.side, reference_price, &maker_order.price) {
+                    trade_through = true;
+                    break 'plan;
+                }
+
+                if taker_order.is_same_trader(maker_order)
+                    && self.self_trade_prevention != SelfTradePrevention::None
+                {
+                    stp_taker = self.prevent_self_trade(maker_order, journal, logs)?;
+                    if stp_taker {
+                        break 'plan;
                     }
+                    start = index + 1;
+                    continue 'plan;
                 }
+
+                // passed over by the strategy
+                let size = match fill {
+                    Some(size) => size,
+                    None => continue,
+                };
+                fills.next();
+
+                self.fill_maker(&mut taker_order, maker_order, &size, journal, logs)?;
+                last_match_price = Some(maker_order.price);
             }
+            walked_all = true;
+            break;
+        }
+        // order in queue but not found in order book (maybe some fatal issue)
+        if walked_all && let Some(order_id) = missing {
+            return Err(CustomError::NotFound(order_id));
         }
 
-        if let OrderType::OrderTypeLimit = taker_order.r#type
-            && Ordering::Greater == Decimal::cmp(&taker_order.size, &Decimal::zero()) {
-            // If taker has an uncompleted size, put taker in orderBook
-            match taker_order.side {
-                Side::SideBuy => {
-                    self.bid_depths.add(&taker_order);
-                }
-                Side::SideSell => {
-                    self.ask_depths.add(&taker_order);
+        // the makers the taker crossed were at or better than its limit, yet makers passed
+        // over (all-or-none ones it can't complete, or ones short of min_price_improvement)
+        // may still rest at or through the last match price, the rest is kept a tick away
+        // from them so that it never crosses the book
+        let rest_price = match (&self.rest_price_policy, last_match_price) {
+            (RestPricePolicy::LastMatchPrice, Some(price)) => {
+                let tick = Decimal::new(1, self.product.quote_scale as u32);
+                match taker_order.side {
+                    Side::SideBuy => match self.best_ask() {
+                        Some(ask) if ask <= price => ask - tick,
+                        _ => price,
+                    },
+                    Side::SideSell => match self.best_bid() {
+                        Some(bid) if bid >= price => bid + tick,
+                        _ => price,
+                    },
                 }
             }
-            logs.push(Box::new(new_open_log(self.next_log_seq(), &self.product.id, &taker_order)));
+            _ => taker_order.price,
+        };
+        let over_user_limit = !stp_taker && self.is_user_order_limit_reached(&taker_order);
+        let over_notional =
+            !stp_taker && self.is_side_notional_limit_reached(&taker_order, rest_price);
+        // the rest of a partially filled taker is too small to be worth resting, a taker
+        // filled to zero has no rest and is done as filled
+        let dust = self.trade_seq != origin_trade_seq
+            && !taker_order.size.is_zero()
+            && Ordering::Less == Decimal::cmp(&taker_order.size, &self.product.min_size);
+        if !stp_taker
+            && !trade_through
+            && !over_user_limit
+            && !over_notional
+            && !dust
+            && !clamped
+            && let OrderType::OrderTypeLimit = taker_order.r#type
+            && Ordering::Greater == Decimal::cmp(&taker_order.size, &Decimal::zero())
+        {
+            taker_order.price = rest_price;
+
+            // If taker has an uncompleted size, put taker in orderBook
+            self.rest_order(&taker_order)?;
+            logs.push(Box::new(new_open_log(
+                self.next_log_seq(),
+                &self.product.id,
+                &taker_order,
+            )));
         } else {
             let mut remaining_size = taker_order.size;
             let mut reason = DONE_REASON_FILLED;
@@ -400,103 +1095,1010 @@ impl OrderBook {
                 taker_order.price = Decimal::zero();
                 remaining_size = Decimal::zero();
 
-                if let Side::SideSell = taker_order.side && Ordering::Greater == Decimal::cmp(&taker_order.size, &Decimal::zero()) {
+                if let Side::SideSell = taker_order.side
+                    && Ordering::Greater == Decimal::cmp(&taker_order.size, &Decimal::zero())
+                {
                     reason = DONE_REASON_CANCELLED;
-                } else if let Side::SideBuy = taker_order.side && Ordering::Greater == Decimal::cmp(&taker_order.funds, &Decimal::zero()) {
+                } else if let Side::SideBuy = taker_order.side
+                    && Ordering::Greater == Decimal::cmp(&taker_order.funds, &Decimal::zero())
+                {
+                    reason = DONE_REASON_CANCELLED;
+                }
+
+                // a market order which isn't filled at all (e.g. the opposite side is empty)
+                // is cancelled, no matter it's driven by size or funds
+                if self.trade_seq == origin_trade_seq {
                     reason = DONE_REASON_CANCELLED;
                 }
             }
 
-            logs.push(Box::new(new_done_log(
-                self.next_log_seq(),
-                &self.product.id,
-                &taker_order,
-                &remaining_size,
-                &reason,
-            )));
+            if stp_taker {
+                reason = DONE_REASON_STP;
+                self.count_rejection(RejectReason::SelfTrade);
+            } else if trade_through {
+                reason = DONE_REASON_CANCELLED;
+                self.count_rejection(RejectReason::TradeThrough);
+            } else if over_user_limit {
+                reason = DONE_REASON_CANCELLED;
+                self.count_rejection(RejectReason::MaxOrdersPerUser);
+            } else if over_notional {
+                reason = DONE_REASON_CANCELLED;
+                self.count_rejection(RejectReason::MaxNotionalPerSide);
+            } else if dust || clamped {
+                reason = DONE_REASON_CANCELLED;
+            }
+
+            whole = whole && matches!(reason, DoneReason::DoneReasonFilled);
+            logs.push(self.record_done_log(&taker_order, &remaining_size, &reason));
         }
 
-        logs
+        Ok(whole)
     }
 
-    pub fn cancel_order(&mut self, order: &Order) -> Vec<Box<dyn LogTrait>> {
-        let mut logs: Vec<Box<dyn LogTrait>> = Vec::new();
-        let mut f = false;
-        let mut book_order = BookOrder::default();
-        // let mut remaining_size = Decimal::default();
-
-        let _ = self.order_id_window.put(order.id);
-
-        match order.side {
-            Side::SideBuy => {
-                if let Some(r) = self.ask_depths.orders.get(&order.id) {
-                    let o = r.clone();
-                    match self.ask_depths.decr_size(order.id, &o.size) {
-                        Err(e) => {
-                            panic!("{}", e);
-                        }
-                        Ok(()) => {
-                            f = true;
-                            book_order = o;
-                            // remaining_size = book_order.size;
-                            book_order.size = Decimal::zero();
-                        }
-                    }
-                }
-            }
-            Side::SideSell => {
-                if let Some(r) = self.bid_depths.orders.get(&order.id) {
-                    let o = r.clone();
-                    match self.bid_depths.decr_size(order.id, &o.size) {
-                        Err(e) => {
-                            panic!("{}", e);
-                        }
-                        Ok(()) => {
-                            f = true;
-                            book_order = o;
-                            // remaining_size = book_order.size;
-                            book_order.size = Decimal::zero();
-                        }
-                    }
-                }
-            }
+    // the makers of the opposing queue which cross the limit price, in priority order, and
+    // the id of an order found in the queue but not on the book, where they're cut off
+    fn crossing_makers(
+        &self,
+        side: &Side,
+        limit_price: Option<Decimal>,
+    ) -> (Vec<BookOrder>, Option<u64>) {
+        let (order_ids, orders): (Box<dyn Iterator<Item = &u64>>, _) = match side {
+            Side::SideBuy => (
+                Box::new(self.ask_depths.queue.values()),
+                &self.ask_depths.orders,
+            ),
+            Side::SideSell => (
+                Box::new(self.bid_depths.queue.values()),
+                &self.bid_depths.orders,
+            ),
         };
 
-        if f {
-            logs.push(Box::new(new_done_log(
-                self.next_log_seq(),
-                &self.product.id,
-                &book_order,
-                &book_order.size,
-                &DONE_REASON_CANCELLED,
-            )));
+        let mut makers = Vec::new();
+        for order_id in order_ids {
+            let maker_order = match orders.get(order_id) {
+                Some(o) => o,
+                None => return (makers, Some(*order_id)),
+            };
+            if !is_crossing(side, limit_price, &maker_order.price) {
+                break;
+            }
+            makers.push(maker_order.clone());
         }
-
-        logs
+        (makers, None)
     }
 
-    pub fn nullify_order(&mut self, order: &Order) -> Vec<Box<dyn LogTrait>> {
-        let mut logs: Vec<Box<dyn LogTrait>> = Vec::new();
+    // fill the maker with size at its price, the maker is recorded in journal before it
+    // is modified
+    fn fill_maker(
+        &mut self,
+        taker_order: &mut BookOrder,
+        maker_order: &BookOrder,
+        size: &Decimal,
+        journal: &mut Vec<BookOrder>,
+        logs: &mut Vec<Box<dyn LogTrait>>,
+    ) -> Result<(), CustomError> {
+        match (&taker_order.r#type, &taker_order.side) {
+            // adjust the funds of taker order
+            (OrderType::OrderTypeMarket, Side::SideBuy) => {
+                let funds = size
+                    .checked_mul(maker_order.price)
+                    .ok_or(CustomError::Arithmetic {
+                        taker_id: taker_order.order_id,
+                        maker_id: maker_order.order_id,
+                        op: "mul",
+                    })?;
+                taker_order.funds = taker_order.funds.sub(funds);
+            }
+            // adjust the size of taker order
+            _ => taker_order.size = taker_order.size.sub(size),
+        }
 
-        let _ = self.order_id_window.put(order.id);
+        // adjust the size of maker order
+        journal.push(maker_order.clone());
+        match maker_order.side {
+            Side::SideBuy => self.bid_depths.decr_size(maker_order.order_id, size)?,
+            Side::SideSell => self.ask_depths.decr_size(maker_order.order_id, size)?,
+        }
+        let mut maker_order = maker_order.clone();
+        maker_order.size = maker_order.size.sub(size);
 
-        let book_order = BookOrder::new_book_order(order);
-        logs.push(Box::new(new_done_log(
-            self.next_log_seq(),
-            &self.product.id,
-            &book_order,
-            &order.size,
-            &DONE_REASON_CANCELLED,
-        )));
+        // matched, new match log
+        logs.push(self.record_match_log(taker_order, &maker_order, &maker_order.price, size));
 
-        logs
+        // maker is filled
+        if maker_order.size.is_zero() {
+            logs.push(self.record_done_log(&maker_order, &maker_order.size, &DONE_REASON_FILLED));
+        }
+        Ok(())
     }
 
-    pub fn snapshot(&self) -> OrderBookSnapshot {
-        let mut snapshot = OrderBookSnapshot {
-            product_id: self.product.id.clone(),
-            orders: Vec::new(),
-            trade_seq: self.trade_seq,
+    // whether resting the rest of the taker would exceed max_orders_per_user
+    fn is_user_order_limit_reached(&self, taker_order: &BookOrder) -> bool {
+        let max_orders = match self.max_orders_per_user {
+            Some(max_orders) => max_orders,
+            None => return false,
+        };
+        if let OrderType::OrderTypeMarket = taker_order.r#type {
+            return false;
+        }
+        if taker_order.size.is_zero() {
+            return false;
+        }
+
+        let count = match taker_order.side {
+            Side::SideBuy => self.bid_depths.user_order_count(taker_order.user_id),
+            Side::SideSell => self.ask_depths.user_order_count(taker_order.user_id),
+        };
+        count >= max_orders
+    }
+
+    // whether resting the rest of the taker at the price would exceed max_notional_per_side
+    fn is_side_notional_limit_reached(&self, taker_order: &BookOrder, price: Decimal) -> bool {
+        let max_notional = match self.max_notional_per_side {
+            Some(max_notional) => max_notional,
+            None => return false,
+        };
+        if let OrderType::OrderTypeMarket = taker_order.r#type {
+            return false;
+        }
+        if taker_order.size.is_zero() {
+            return false;
+        }
+
+        let notional = match taker_order.side {
+            Side::SideBuy => self.bid_depths.notional,
+            Side::SideSell => self.ask_depths.notional,
+        };
+        Ordering::Greater == Decimal::cmp(&(notional + price * taker_order.size), &max_notional)
+    }
+
+    // limit orders rest on the book without matching, market orders can't take part
+    // in the auction since there is no price to put them in the book
+    fn queue_for_auction(
+        &mut self,
+        order: &Order,
+        book_order: BookOrder,
+    ) -> Result<Vec<Box<dyn LogTrait>>, CustomError> {
+        if let OrderType::OrderTypeMarket = order.r#type {
+            return Ok(self.reject_order(order, RejectReason::MarketInAuction));
+        }
+        self.rest_order(&book_order)?;
+        Ok(vec![Box::new(new_open_log(
+            self.next_log_seq(),
+            &self.product.id,
+            &book_order,
+        ))])
+    }
+
+    // market orders carry a sentinel price, which would corrupt the queue on the book
+    fn rest_order(&mut self, book_order: &BookOrder) -> Result<(), CustomError> {
+        if let OrderType::OrderTypeMarket = book_order.r#type {
+            return Err(CustomError::MarketOrderResting(book_order.order_id));
+        }
+        match book_order.side {
+            Side::SideBuy => self.bid_depths.add(book_order),
+            Side::SideSell => self.ask_depths.add(book_order),
+        }
+        if book_order.peg.is_some() {
+            self.pegs.insert(book_order.order_id);
+        }
+        self.last_order_rested = true;
+        Ok(())
+    }
+
+    // the best (bid, ask) of the orders which aren't pegged, what the pegged orders follow,
+    // so that they never chase each other
+    fn peg_reference(&self) -> (Option<Decimal>, Option<Decimal>) {
+        if self.pegs.is_empty() {
+            return (self.best_bid(), self.best_ask());
+        }
+        let bid = self
+            .bid_depths
+            .queue
+            .iter()
+            .find(|(_, order_id)| !self.pegs.contains(order_id))
+            .map(|(k, _)| k.price);
+        let ask = self
+            .ask_depths
+            .queue
+            .iter()
+            .find(|(_, order_id)| !self.pegs.contains(order_id))
+            .map(|(k, _)| k.price);
+        (bid, ask)
+    }
+
+    // the reference of the peg plus its offset, rounded to quote_scale away from the
+    // opposite side, none if the reference is missing or the price isn't positive
+    fn peg_price(&self, peg: &Peg, side: &Side) -> Option<Decimal> {
+        let (bid, ask) = self.peg_reference();
+        let reference = match peg.reference {
+            PegReference::PegReferenceBestBid => bid?,
+            PegReference::PegReferenceBestAsk => ask?,
+            PegReference::PegReferenceMidpoint => (bid? + ask?).div(Decimal::from(2)),
+        };
+        let strategy = match side {
+            Side::SideBuy => RoundingStrategy::ToNegativeInfinity,
+            Side::SideSell => RoundingStrategy::ToPositiveInfinity,
+        };
+        let price = (reference + peg.offset)
+            .round_dp_with_strategy(self.product.quote_scale as u32, strategy);
+        (price.is_sign_positive() && !price.is_zero()).then_some(price)
+    }
+
+    // move the pegged orders to the prices of their peg once the reference has changed
+    // from the one given, each move is logged by a change log. a pegged order never crosses
+    // the book, it's kept a tick away from the opposite best, and it stays where it is
+    // while its reference is missing
+    fn reprice_pegs(
+        &mut self,
+        reference: (Option<Decimal>, Option<Decimal>),
+        logs: &mut Vec<Box<dyn LogTrait>>,
+    ) {
+        if self.pegs.is_empty() || self.peg_reference() == reference {
+            return;
+        }
+        let tick = Decimal::new(1, self.product.quote_scale as u32);
+        let order_ids: Vec<u64> = self.pegs.iter().copied().collect();
+        for order_id in order_ids {
+            let order = match self
+                .bid_depths
+                .orders
+                .get(&order_id)
+                .or_else(|| self.ask_depths.orders.get(&order_id))
+            {
+                Some(o) if o.peg.is_some() => o.clone(),
+                _ => {
+                    self.pegs.remove(&order_id);
+                    continue;
+                }
+            };
+            let price = match order
+                .peg
+                .as_ref()
+                .and_then(|peg| self.peg_price(peg, &order.side))
+            {
+                Some(price) => price,
+                None => continue,
+            };
+            let price = match (&order.side, self.best_ask(), self.best_bid()) {
+                (Side::SideBuy, Some(ask), _) if price >= ask => ask - tick,
+                (Side::SideSell, _, Some(bid)) if price <= bid => bid + tick,
+                _ => price,
+            };
+            if price == order.price || price.is_sign_negative() || price.is_zero() {
+                continue;
+            }
+
+            let result = match order.side {
+                Side::SideBuy => self.bid_depths.decr_size(order_id, &order.size),
+                Side::SideSell => self.ask_depths.decr_size(order_id, &order.size),
+            };
+            if let Err(e) = result {
+                panic!("{}", e);
+            }
+            let moved = BookOrder {
+                price,
+                ..order.clone()
+            };
+            match moved.side {
+                Side::SideBuy => self.bid_depths.add(&moved),
+                Side::SideSell => self.ask_depths.add(&moved),
+            }
+            logs.push(Box::new(new_change_log(
+                self.next_log_seq(),
+                &self.product.id,
+                &order,
+                &price,
+            )));
+        }
+    }
+
+    // uncross the orders accumulated during auction_mode at a single clearing price,
+    // which maximizes the matched volume, then minimizes the imbalance between the
+    // two sides, then is the lowest. unmatched orders keep resting and the book goes
+    // back to continuous matching. returns zero price if there is no crossing at all.
+    // the matches are flagged as auction ones with the bid recorded as the taker
+    pub fn run_auction(&mut self) -> Result<(Decimal, Vec<Box<dyn LogTrait>>), CustomError> {
+        let mut logs: Vec<Box<dyn LogTrait>> = Vec::new();
+        self.auction_mode = false;
+        self.version += 1;
+
+        // bid levels from the highest price, ask levels from the lowest
+        let bid_levels = self.bid_depths.levels(usize::MAX);
+        let ask_levels = self.ask_depths.levels(usize::MAX);
+        let total_demand: Decimal = bid_levels.iter().map(|(_, size)| *size).sum();
+        let mut prices: Vec<Decimal> = bid_levels
+            .iter()
+            .chain(ask_levels.iter())
+            .map(|(price, _)| *price)
+            .collect();
+        prices.sort();
+        prices.dedup();
+
+        // walking the candidate prices upwards, the supply accumulates the asks at or
+        // below the price and the demand drops the bids below it, one pass per side
+        let mut asks = ask_levels.iter().peekable();
+        let mut bids = bid_levels.iter().rev().peekable();
+        let (mut supply, mut demand_below) = (Decimal::zero(), Decimal::zero());
+        // (price, volume, imbalance)
+        let mut clearing: Option<(Decimal, Decimal, Decimal)> = None;
+        for price in prices {
+            while let Some((p, size)) = asks.peek()
+                && *p <= price
+            {
+                supply += *size;
+                asks.next();
+            }
+            while let Some((p, size)) = bids.peek()
+                && *p < price
+            {
+                demand_below += *size;
+                bids.next();
+            }
+            let demand = total_demand.sub(demand_below);
+            let volume = Decimal::min(demand, supply);
+            let imbalance = demand.sub(supply).abs();
+            if volume.is_zero() {
+                continue;
+            }
+
+            // prices ascend, so a tie on both volume and imbalance keeps the lower one
+            let better = match &clearing {
+                None => true,
+                Some((_, v, i)) => volume > *v || (volume == *v && imbalance < *i),
+            };
+            if better {
+                clearing = Some((price, volume, imbalance));
+            }
+        }
+
+        let (price, mut volume) = match clearing {
+            None => return Ok((Decimal::zero(), logs)),
+            Some((p, v, _)) => (p, v),
+        };
+
+        // both sides are in priority order, so the eligible orders come first
+        let bid_ids: Vec<u64> = self.bid_depths.queue.values().copied().collect();
+        let ask_ids: Vec<u64> = self.ask_depths.queue.values().copied().collect();
+        let (mut bids, mut asks) = (bid_ids.into_iter(), ask_ids.into_iter());
+        let (mut bid, mut ask) = (bids.next(), asks.next());
+        while volume > Decimal::zero() {
+            let (bid_id, ask_id) = match (bid, ask) {
+                (Some(b), Some(a)) => (b, a),
+                _ => break,
+            };
+
+            let mut b = self.bid_depths.orders[&bid_id].clone();
+            let mut a = self.ask_depths.orders[&ask_id].clone();
+            let size = Decimal::min(Decimal::min(b.size, a.size), volume);
+            volume = volume.sub(size);
+            self.bid_depths.decr_size(bid_id, &size)?;
+            self.ask_depths.decr_size(ask_id, &size)?;
+            b.size = b.size.sub(size);
+            a.size = a.size.sub(size);
+
+            let mut log = self.record_match_log(&b, &a, &price, &size);
+            log.auction = true;
+            logs.push(log);
+
+            for o in [&b, &a].iter() {
+                if o.size.is_zero() {
+                    logs.push(self.record_done_log(o, &o.size, &DONE_REASON_FILLED));
+                }
+            }
+
+            if b.size.is_zero() {
+                bid = bids.next();
+            }
+            if a.size.is_zero() {
+                ask = asks.next();
+            }
+        }
+
+        Ok((price, logs))
+    }
+
+    // cancel the crossing maker per self trade prevention policy,
+    // returns true if the rest of taker should be cancelled as well
+    fn prevent_self_trade(
+        &mut self,
+        maker_order: &BookOrder,
+        journal: &mut Vec<BookOrder>,
+        logs: &mut Vec<Box<dyn LogTrait>>,
+    ) -> Result<bool, CustomError> {
+        if self.self_trade_prevention != SelfTradePrevention::CancelTaker {
+            journal.push(maker_order.clone());
+            match maker_order.side {
+                Side::SideBuy => self
+                    .bid_depths
+                    .decr_size(maker_order.order_id, &maker_order.size)?,
+                Side::SideSell => self
+                    .ask_depths
+                    .decr_size(maker_order.order_id, &maker_order.size)?,
+            }
+
+            let mut book_order = maker_order.clone();
+            book_order.size = Decimal::zero();
+            logs.push(self.record_done_log(&book_order, &book_order.size, &DONE_REASON_STP));
+        }
+
+        Ok(self.self_trade_prevention != SelfTradePrevention::CancelMaker)
+    }
+
+    // cancel the resting order, an order which has left the book already is told apart
+    // from one the book has never seen, and an order resting on the other side is an error
+    pub fn cancel_order(&mut self, order: &Order) -> Result<CancelOutcome, CustomError> {
+        self.cancel_order_with_reason(order, DONE_REASON_CANCELLED)
+    }
+
+    // same as cancel_order, with the reason the done log carries, so that e.g. a cancel
+    // forced by risk control is told apart from the one requested by the user
+    pub fn cancel_order_with_reason(
+        &mut self,
+        order: &Order,
+        reason: DoneReason,
+    ) -> Result<CancelOutcome, CustomError> {
+        self.version += 1;
+        self.cancel_order_unversioned(order, reason)
+    }
+
+    fn cancel_order_unversioned(
+        &mut self,
+        order: &Order,
+        reason: DoneReason,
+    ) -> Result<CancelOutcome, CustomError> {
+        // the window is left alone, an id never seen stays free for the order to come
+        let seen = self.order_id_window.contains(order.id);
+
+        // buy orders rest on the bid side, and sell orders on the ask side
+        let (resting, on_other_side) = match order.side {
+            Side::SideBuy => (
+                self.bid_depths.orders.get(&order.id).cloned(),
+                self.ask_depths.orders.contains_key(&order.id),
+            ),
+            Side::SideSell => (
+                self.ask_depths.orders.get(&order.id).cloned(),
+                self.bid_depths.orders.contains_key(&order.id),
+            ),
+        };
+
+        let mut book_order = match resting {
+            Some(o) => o,
+            None if on_other_side => return Err(CustomError::WrongSide(order.id)),
+            None => {
+                info!("cancel order not on book, order_id: {}", order.id);
+                return Ok(if seen {
+                    CancelOutcome::AlreadyDone
+                } else {
+                    CancelOutcome::NotFound
+                });
+            }
+        };
+
+        match order.side {
+            Side::SideBuy => self.bid_depths.decr_size(order.id, &book_order.size)?,
+            Side::SideSell => self.ask_depths.decr_size(order.id, &book_order.size)?,
+        }
+        book_order.size = Decimal::zero();
+
+        let log = self.record_done_log(&book_order, &book_order.size, &reason);
+        Ok(CancelOutcome::Cancelled(*log))
+    }
+
+    // cancel every resting order regardless of its owner, e.g. when delisting the product,
+    // the sequences go on so that the log stream stays contiguous. the orders are taken in
+    // queue (price-time) order, bids first, never in the order of the hash maps, so that
+    // replicas draining the same book emit the same logs
+    pub fn drain(&mut self) -> Vec<DoneLog> {
+        self.version += 1;
+        let mut resting: Vec<BookOrder> = Vec::new();
+        for order_id in self.bid_depths.queue.values() {
+            resting.push(self.bid_depths.orders[order_id].clone());
+        }
+        for order_id in self.ask_depths.queue.values() {
+            resting.push(self.ask_depths.orders[order_id].clone());
+        }
+        self.cancel_resting(resting)
+    }
+
+    // cancel the resting orders on a side priced worse than the price, bids below it or
+    // asks above it, walking the queue from its worst end
+    pub fn cancel_worse_than(&mut self, side: Side, price: Decimal) -> Vec<DoneLog> {
+        self.version += 1;
+        let mut resting: Vec<BookOrder> = match side {
+            Side::SideBuy => self
+                .bid_depths
+                .queue
+                .values()
+                .rev()
+                .map(|order_id| &self.bid_depths.orders[order_id])
+                .take_while(|o| Ordering::Less == Decimal::cmp(&o.price, &price))
+                .cloned()
+                .collect(),
+            Side::SideSell => self
+                .ask_depths
+                .queue
+                .values()
+                .rev()
+                .map(|order_id| &self.ask_depths.orders[order_id])
+                .take_while(|o| Ordering::Greater == Decimal::cmp(&o.price, &price))
+                .cloned()
+                .collect(),
+        };
+        // cancelled in queue order like the others
+        resting.reverse();
+        self.cancel_resting(resting)
+    }
+
+    fn cancel_resting(&mut self, resting: Vec<BookOrder>) -> Vec<DoneLog> {
+        let mut logs: Vec<DoneLog> = Vec::new();
+        for mut book_order in resting {
+            let result = match book_order.side {
+                Side::SideBuy => self
+                    .bid_depths
+                    .decr_size(book_order.order_id, &book_order.size),
+                Side::SideSell => self
+                    .ask_depths
+                    .decr_size(book_order.order_id, &book_order.size),
+            };
+            if let Err(e) = result {
+                panic!("{}", e);
+            }
+            book_order.size = Decimal::zero();
+            logs.push(*self.record_done_log(&book_order, &book_order.size, &DONE_REASON_CANCELLED));
+        }
+        logs
+    }
+
+    // switch to the new parameters of the product on a live book, the resting orders which
+    // don't fit them (price or size beyond the scales, size off the lot) are cancelled
+    pub fn update_product(&mut self, new_product: Product) -> Result<Vec<DoneLog>, CustomError> {
+        if new_product.id != self.product.id {
+            return Err(CustomError::Other(format!(
+                "product {} can't replace {}",
+                new_product.id, self.product.id
+            )));
+        }
+        self.version += 1;
+
+        let fits = |o: &BookOrder| {
+            o.price.round_dp(new_product.quote_scale as u32) == o.price
+                && o.size.round_dp(new_product.base_scale as u32) == o.size
+                && (new_product.lot_size.is_zero() || (o.size % new_product.lot_size).is_zero())
+        };
+        let mut invalid: Vec<BookOrder> = Vec::new();
+        for order_id in self.bid_depths.queue.values() {
+            if !fits(&self.bid_depths.orders[order_id]) {
+                invalid.push(self.bid_depths.orders[order_id].clone());
+            }
+        }
+        for order_id in self.ask_depths.queue.values() {
+            if !fits(&self.ask_depths.orders[order_id]) {
+                invalid.push(self.ask_depths.orders[order_id].clone());
+            }
+        }
+
+        let logs = self.cancel_resting(invalid);
+        self.product = new_product;
+        Ok(logs)
+    }
+
+    // cancel the orders at the price from the front of the queue (the oldest ones) until
+    // the size of the level is at or below max_size
+    pub fn enforce_level_cap(
+        &mut self,
+        side: Side,
+        price: Decimal,
+        max_size: Decimal,
+    ) -> Vec<DoneLog> {
+        self.version += 1;
+        let level: Vec<&BookOrder> = match side {
+            Side::SideBuy => self.bid_depths.level_orders(&price).collect(),
+            Side::SideSell => self.ask_depths.level_orders(&price).collect(),
+        };
+        let mut excess = level
+            .iter()
+            .fold(Decimal::zero(), |acc, o| acc + o.size)
+            .sub(max_size);
+        let over: Vec<BookOrder> = level
+            .into_iter()
+            .take_while(|o| {
+                let over = excess > Decimal::zero();
+                excess = excess.sub(o.size);
+                over
+            })
+            .cloned()
+            .collect();
+        self.cancel_resting(over)
+    }
+
+    // cancel_order in the shape of the logs, nothing is logged if the order isn't on
+    // the book (already filled or cancelled, never seen or on the other side)
+    pub fn cancel_order_logs(&mut self, order: &Order) -> Vec<Box<dyn LogTrait>> {
+        match self.cancel_order(order) {
+            Ok(CancelOutcome::Cancelled(log)) => vec![Box::new(log)],
+            Ok(_) => Vec::new(),
+            Err(CustomError::WrongSide(order_id)) => {
+                info!("cancel order on the other side, order_id: {}", order_id);
+                Vec::new()
+            }
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    // the two-sided quote of a market maker, the resting bid and ask given by id are
+    // cancelled and the new bid and ask are applied in one call. the update either applies
+    // as a whole or leaves the book as it was: it's checked before the book is touched, and
+    // if a new order isn't filled or rested as submitted (e.g. a limit of the book cancels
+    // it) the cancels and the other order are rolled back. a refused quote is counted by
+    // its reason
+    pub fn quote(
+        &mut self,
+        bid: Option<Order>,
+        ask: Option<Order>,
+        cancel_bid: Option<u64>,
+        cancel_ask: Option<u64>,
+    ) -> Result<Vec<Box<dyn LogTrait>>, CustomError> {
+        self.timed(|order_book| order_book.quote_untimed(bid, ask, cancel_bid, cancel_ask))
+    }
+
+    fn quote_untimed(
+        &mut self,
+        bid: Option<Order>,
+        ask: Option<Order>,
+        cancel_bid: Option<u64>,
+        cancel_ask: Option<u64>,
+    ) -> Result<Vec<Box<dyn LogTrait>>, CustomError> {
+        self.last_order_rested = false;
+        self.version += 1;
+        let order_id_window = match self.check_quote(&bid, &ask, cancel_bid, cancel_ask) {
+            Ok(order_id_window) => order_id_window,
+            Err((reason, e)) => {
+                self.count_rejection(reason);
+                return Err(e);
+            }
+        };
+        let checkpoint = self.checkpoint();
+        let window = std::mem::replace(&mut self.order_id_window, order_id_window);
+        let mut journal: Vec<BookOrder> = Vec::new();
+        let mut logs: Vec<Box<dyn LogTrait>> = Vec::new();
+        self.evicted_trades = Some(Vec::new());
+        let result = self.apply_quote(&bid, &ask, cancel_bid, cancel_ask, &mut journal, &mut logs);
+        let evicted_trades = self.evicted_trades.take().unwrap_or_default();
+        if result.is_err() {
+            // the refused quote is counted by the rejection of the order which refused it
+            let rejections = self.rejections.clone();
+            self.roll_back(checkpoint, &journal, evicted_trades);
+            self.rejections = rejections;
+            // the new orders which rested are taken off again
+            let rested: Vec<BookOrder> = bid
+                .iter()
+                .chain(ask.iter())
+                .filter_map(|order| match order.side {
+                    Side::SideBuy => self.bid_depths.orders.get(&order.id),
+                    Side::SideSell => self.ask_depths.orders.get(&order.id),
+                })
+                .cloned()
+                .collect();
+            for o in rested {
+                let result = match o.side {
+                    Side::SideBuy => self.bid_depths.decr_size(o.order_id, &o.size),
+                    Side::SideSell => self.ask_depths.decr_size(o.order_id, &o.size),
+                };
+                if let Err(e) = result {
+                    panic!("{}", e);
+                }
+            }
+            self.order_id_window = window;
+            self.last_order_rested = false;
+        }
+
+        result.map(|_| logs)
+    }
+
+    // check the whole quote before the book is touched, returns the window with the ids of
+    // the new orders put, or why the quote is refused
+    fn check_quote(
+        &self,
+        bid: &Option<Order>,
+        ask: &Option<Order>,
+        cancel_bid: Option<u64>,
+        cancel_ask: Option<u64>,
+    ) -> Result<Window, (RejectReason, CustomError)> {
+        if self.halted {
+            return Err((RejectReason::Halted, CustomError::Halted));
+        }
+        let (bids, asks) = (&self.bid_depths.orders, &self.ask_depths.orders);
+        for (order_id, own, other) in [(cancel_bid, bids, asks), (cancel_ask, asks, bids)].iter() {
+            if let Some(order_id) = order_id
+                && !own.contains_key(order_id)
+            {
+                let e = if other.contains_key(order_id) {
+                    CustomError::WrongSide(*order_id)
+                } else {
+                    CustomError::NotFound(*order_id)
+                };
+                return Err((RejectReason::InvalidCancel, e));
+            }
+        }
+
+        // the ids of both orders must get into the window, one may slide the other out
+        let mut order_id_window = self.order_id_window.clone();
+        for (order, is_bid) in [(bid, true), (ask, false)].iter() {
+            if let Some(order) = order {
+                if let Err(e) = OrderBook::validate_order(order) {
+                    return Err((RejectReason::InvalidOrder, e));
+                }
+                if *is_bid != matches!(order.side, Side::SideBuy) {
+                    let e = CustomError::InvalidOrder {
+                        order_id: order.id,
+                        reason: "is on the other side of the quote".to_string(),
+                    };
+                    return Err((RejectReason::InvalidOrder, e));
+                }
+                if let Err(e) = order_id_window.put(order.id) {
+                    return Err((RejectReason::DuplicateOrderId, e));
+                }
+            }
+        }
+        // the quote is of a single user, who must own the orders it cancels
+        if let (Some(bid), Some(ask)) = (bid, ask)
+            && bid.user_id != ask.user_id
+        {
+            let e = CustomError::InvalidOrder {
+                order_id: ask.id,
+                reason: "is of another user than the bid of the quote".to_string(),
+            };
+            return Err((RejectReason::InvalidOrder, e));
+        }
+        if let Some(user_id) = bid.iter().chain(ask.iter()).map(|o| o.user_id).next() {
+            let cancels = cancel_bid
+                .map(|order_id| &bids[&order_id])
+                .into_iter()
+                .chain(cancel_ask.map(|order_id| &asks[&order_id]));
+            for book_order in cancels {
+                if book_order.user_id != user_id {
+                    let e = CustomError::NotOwner {
+                        order_id: book_order.order_id,
+                        user_id,
+                    };
+                    return Err((RejectReason::InvalidCancel, e));
+                }
+            }
+        }
+        Ok(order_id_window)
+    }
+
+    // cancel the resting orders of the quote and match its new orders, every order touched
+    // is recorded in journal before it is modified. err once a new order is refused by its
+    // time in force or cut off
+    fn apply_quote(
+        &mut self,
+        bid: &Option<Order>,
+        ask: &Option<Order>,
+        cancel_bid: Option<u64>,
+        cancel_ask: Option<u64>,
+        journal: &mut Vec<BookOrder>,
+        logs: &mut Vec<Box<dyn LogTrait>>,
+    ) -> Result<(), CustomError> {
+        for order_id in cancel_bid.iter() {
+            let mut book_order = self.bid_depths.orders[order_id].clone();
+            journal.push(book_order.clone());
+            self.bid_depths.decr_size(*order_id, &book_order.size)?;
+            book_order.size = Decimal::zero();
+            logs.push(self.record_done_log(&book_order, &book_order.size, &DONE_REASON_CANCELLED));
+        }
+        for order_id in cancel_ask.iter() {
+            let mut book_order = self.ask_depths.orders[order_id].clone();
+            journal.push(book_order.clone());
+            self.ask_depths.decr_size(*order_id, &book_order.size)?;
+            book_order.size = Decimal::zero();
+            logs.push(self.record_done_log(&book_order, &book_order.size, &DONE_REASON_CANCELLED));
+        }
+        // the time in force of an order holds within the quote as it does on its own
+        for order in bid.iter().chain(ask.iter()) {
+            if let Some(reason) = self.time_in_force_refusal(order) {
+                self.count_rejection(reason);
+                return Err(CustomError::QuoteRejected(order.id));
+            }
+            if !self.match_order(order, journal, logs)? {
+                return Err(CustomError::QuoteRejected(order.id));
+            }
+            if let TimeInForceType::ImmediateOrCancel = order.time_in_force {
+                self.cancel_ioc_rest(order, logs);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn nullify_order(&mut self, order: &Order) -> Vec<Box<dyn LogTrait>> {
+        self.nullify_order_with_fillable(order, None)
+    }
+
+    // nullify the order, its done log carries the size the book could have filled
+    fn nullify_order_with_fillable(
+        &mut self,
+        order: &Order,
+        fillable_size: Option<Decimal>,
+    ) -> Vec<Box<dyn LogTrait>> {
+        let mut logs: Vec<Box<dyn LogTrait>> = Vec::new();
+
+        let _ = self.order_id_window.put(order.id);
+
+        let book_order = BookOrder::new_book_order(order);
+        logs.push(self.record_done_log_with_fillable(
+            &book_order,
+            &order.size,
+            &DONE_REASON_CANCELLED,
+            fillable_size,
+        ));
+
+        logs
+    }
+
+    // new match log, which also becomes the last trade
+    fn record_match_log(
+        &mut self,
+        taker_order: &BookOrder,
+        maker_order: &BookOrder,
+        price: &Decimal,
+        size: &Decimal,
+    ) -> Box<MatchLog> {
+        let (log_seq, trade_seq) = (self.next_log_seq(), self.next_trade_seq());
+        let log = new_match_log(
+            log_seq,
+            &self.product.id,
+            trade_seq,
+            taker_order,
+            maker_order,
+            price,
+            size,
+        );
+        self.record_trade(log.price, log.size, log.time());
+        Box::new(log)
+    }
+
+    fn record_trade(&mut self, price: Decimal, size: Decimal, time: DateTime<Utc>) {
+        self.last_trade = Some((price, size, time));
+        if self.recent_trades.len() == RECENT_TRADES_CAP {
+            let evicted = self.recent_trades.pop_front();
+            if let (Some(evicted), Some(evicted_trades)) = (evicted, self.evicted_trades.as_mut()) {
+                evicted_trades.push(evicted);
+            }
+        }
+        self.recent_trades.push_back((price, size));
+    }
+
+    // size-weighted average price of the latest lookback trades (all of them if there are
+    // fewer), none without trades, lookback is bounded by RECENT_TRADES_CAP
+    pub fn mark_price(&self, lookback: usize) -> Option<Decimal> {
+        let skip = self.recent_trades.len().saturating_sub(lookback);
+        let (notional, size) = self.recent_trades.iter().skip(skip).fold(
+            (Decimal::zero(), Decimal::zero()),
+            |(notional, size), (p, s)| (notional + p.mul(*s), size + *s),
+        );
+        if size.is_zero() {
+            return None;
+        }
+        Some(notional.div(size))
+    }
+
+    // price, size and time of the most recent match, trades are not in the snapshot,
+    // so it's none after a restore until the next match
+    pub fn last_trade(&self) -> Option<(Decimal, Decimal, DateTime<Utc>)> {
+        self.last_trade
+    }
+
+    fn record_done_log(
+        &mut self,
+        order: &BookOrder,
+        remaining_size: &Decimal,
+        reason: &DoneReason,
+    ) -> Box<DoneLog> {
+        self.record_done_log_with_fillable(order, remaining_size, reason, None)
+    }
+
+    fn record_done_log_with_fillable(
+        &mut self,
+        order: &BookOrder,
+        remaining_size: &Decimal,
+        reason: &DoneReason,
+        fillable_size: Option<Decimal>,
+    ) -> Box<DoneLog> {
+        let mut log = new_done_log(
+            self.next_log_seq(),
+            &self.product.id,
+            order,
+            remaining_size,
+            reason,
+        );
+        log.fillable_size = fillable_size;
+        self.session_done_logs.push(log.clone());
+        Box::new(log)
+    }
+
+    pub fn session_done_logs(&self) -> &[DoneLog] {
+        &self.session_done_logs
+    }
+
+    // start a new session, the day orders are cancelled at the end of the last one, the
+    // other orders keep resting in their priority. returns the done logs of the session
+    // which closed, the day order cancels last, the new one starts with an empty buffer
+    pub fn roll_session(&mut self) -> Vec<DoneLog> {
+        self.version += 1;
+        let mut day_orders: Vec<BookOrder> = Vec::new();
+        for order_id in self.bid_depths.queue.values() {
+            if self.bid_depths.orders[order_id].day_order {
+                day_orders.push(self.bid_depths.orders[order_id].clone());
+            }
+        }
+        for order_id in self.ask_depths.queue.values() {
+            if self.ask_depths.orders[order_id].day_order {
+                day_orders.push(self.ask_depths.orders[order_id].clone());
+            }
+        }
+        self.cancel_resting(day_orders);
+        std::mem::take(&mut self.session_done_logs)
+    }
+
+    // refuse the order without matching, and count it by the reason
+    pub fn reject_order(&mut self, order: &Order, reason: RejectReason) -> Vec<Box<dyn LogTrait>> {
+        self.count_rejection(reason);
+        self.nullify_order(order)
+    }
+
+    fn count_rejection(&mut self, reason: RejectReason) {
+        *self.rejections.entry(reason).or_insert(0) += 1;
+    }
+
+    // rough estimate of the bytes used by the book, it grows with the resting orders
+    pub fn memory_footprint(&self) -> usize {
+        std::mem::size_of::<OrderBook>()
+            + self.ask_depths.memory_footprint()
+            + self.bid_depths.memory_footprint()
+            + self.order_id_window.bit_map.len()
+            + self.rejections.capacity() * (std::mem::size_of::<(RejectReason, u64)>() + 1)
+            + self.recent_trades.capacity() * std::mem::size_of::<(Decimal, Decimal)>()
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    pub fn last_order_rested(&self) -> bool {
+        self.last_order_rested
+    }
+
+    pub fn rejection_breakdown(&self) -> HashMap<RejectReason, u64> {
+        self.rejections.clone()
+    }
+
+    // forget the order ids in the older half of the window which are not resting on the
+    // book, so that they are accepted again. the ids in the newer half and the resting ones
+    // are kept, and ids below the window stay expired. a forgotten id which has been filled
+    // or cancelled can be applied a second time, so only compact when the ids of the older
+    // half are known not to be replayed (e.g. after restore, once in-flight orders are done)
+    pub fn compact_window(&mut self) {
+        let recent = self.order_id_window.max - self.order_id_window.cap / 2;
+        let (bids, asks) = (&self.bid_depths.orders, &self.ask_depths.orders);
+        self.order_id_window
+            .retain(|id| id > recent || bids.contains_key(&id) || asks.contains_key(&id));
+    }
+
+    // reclaim the memory held for orders which are gone, for long running books which have
+    // churned through many orders, the matching priority is left as it is
+    pub fn compact(&mut self) {
+        self.bid_depths.compact();
+        self.ask_depths.compact();
+        self.rejections.shrink_to_fit();
+        self.recent_trades.shrink_to_fit();
+    }
+
+    pub fn snapshot(&self) -> OrderBookSnapshot {
+        let mut snapshot = OrderBookSnapshot {
+            product_id: self.product.id.clone(),
+            orders: Vec::new(),
+            trade_seq: self.trade_seq,
             log_seq: self.log_seq,
             order_id_window: self.order_id_window.clone(),
         };
@@ -514,7 +2116,18 @@ impl OrderBook {
         snapshot
     }
 
+    // take a snapshot together with the log_seq of the last log applied to it,
+    // to recover, restore the snapshot and then replay the logs with seq > the returned one
+    pub fn snapshot_at(&self) -> (OrderBookSnapshot, u64) {
+        let snapshot = self.snapshot();
+        let log_seq = snapshot.log_seq;
+        (snapshot, log_seq)
+    }
+
     pub fn restore(&mut self, snapshot: &OrderBookSnapshot) {
+        self.version += 1;
+        self.last_trade = None;
+        self.recent_trades.clear();
         self.log_seq = snapshot.log_seq;
         self.trade_seq = snapshot.trade_seq;
         self.order_id_window = snapshot.order_id_window.clone();
@@ -532,16 +2145,3317 @@ impl OrderBook {
                     self.ask_depths.add(o);
                 }
             }
+            if o.peg.is_some() {
+                self.pegs.insert(o.order_id);
+            }
         }
     }
 
-    pub fn next_log_seq(&mut self) -> u64 {
-        self.log_seq += 1;
-        self.log_seq
+    // a new book restored from the snapshot, the orders which can't rest on a book are
+    // skipped and returned as errors, so that one corrupt order doesn't block the rest
+    pub fn restore_partial(
+        product: &Product,
+        snapshot: &OrderBookSnapshot,
+    ) -> (OrderBook, Vec<CustomError>) {
+        let mut errors = Vec::new();
+        let mut order_ids = HashSet::new();
+        let mut orders = Vec::new();
+        for o in snapshot.orders.iter() {
+            let result = if !order_ids.insert(o.order_id) {
+                Err(CustomError::OrderIdDuplicate(o.order_id))
+            } else {
+                OrderBook::validate_book_order(o)
+            };
+            match result {
+                Ok(()) => orders.push(o.clone()),
+                Err(e) => {
+                    info!("skip order of snapshot, {}", e);
+                    errors.push(e);
+                }
+            }
+        }
+
+        let mut order_book = OrderBook::new_order_book(product);
+        order_book.restore(&OrderBookSnapshot {
+            product_id: snapshot.product_id.clone(),
+            orders,
+            trade_seq: snapshot.trade_seq,
+            log_seq: snapshot.log_seq,
+            order_id_window: snapshot.order_id_window.clone(),
+        });
+        (order_book, errors)
     }
 
-    pub fn next_trade_seq(&mut self) -> u64 {
-        self.trade_seq += 1;
-        self.trade_seq
+    // rebuild the book of the product from the start of its log stream, the logs of other
+    // products in the stream are skipped. the sequence of the product's logs must run on
+    // from 1 without gaps. the logs don't carry every order field, so the orders rest with
+    // defaults for priority_class, all_or_none, stp_group_id, day_order and peg, the moves
+    // of pegged orders are replayed from their change logs
+    pub fn replay_product(
+        product: &Product,
+        logs: &[Box<dyn LogTrait>],
+        product_id: &str,
+    ) -> Result<OrderBook, CustomError> {
+        let mut order_book = OrderBook::new_order_book(product);
+        for log in logs.iter().filter(|log| log.product_id() == product_id) {
+            if log.get_seq() != order_book.log_seq + 1 {
+                return Err(CustomError::SequenceGap {
+                    product_id: product_id.to_string(),
+                    expected: order_book.log_seq + 1,
+                    actual: log.get_seq(),
+                });
+            }
+            order_book.replay_log(log.to_event())?;
+            order_book.log_seq = log.get_seq();
+        }
+        Ok(order_book)
+    }
+
+    fn replay_log(&mut self, event: LogEvent) -> Result<(), CustomError> {
+        self.version += 1;
+        match event {
+            LogEvent::Open(log) => {
+                let _ = self.order_id_window.put(log.order_id);
+                let order = BookOrder {
+                    order_id: log.order_id,
+                    user_id: log.user_id,
+                    size: log.remaining_size,
+                    price: log.price,
+                    side: log.side,
+                    time_in_force: log.time_in_force,
+                    client_order_id: log.client_order_id,
+                    ..Default::default()
+                };
+                match order.side {
+                    Side::SideBuy => self.bid_depths.add(&order),
+                    Side::SideSell => self.ask_depths.add(&order),
+                }
+            }
+            LogEvent::Match(log) => {
+                match log.side {
+                    Side::SideBuy => self.bid_depths.decr_size(log.maker_order_id, &log.size)?,
+                    Side::SideSell => self.ask_depths.decr_size(log.maker_order_id, &log.size)?,
+                }
+                self.trade_seq = log.trade_seq;
+                self.record_trade(log.price, log.size, log.time());
+            }
+            LogEvent::Done(log) => {
+                // the taker which never rested is done without being on the book
+                let _ = self.order_id_window.put(log.order_id);
+                match log.side {
+                    Side::SideBuy => {
+                        if let Some(o) = self.bid_depths.orders.get(&log.order_id) {
+                            let size = o.size;
+                            self.bid_depths.decr_size(log.order_id, &size)?;
+                        }
+                    }
+                    Side::SideSell => {
+                        if let Some(o) = self.ask_depths.orders.get(&log.order_id) {
+                            let size = o.size;
+                            self.ask_depths.decr_size(log.order_id, &size)?;
+                        }
+                    }
+                }
+            }
+            LogEvent::Change(log) => {
+                let order = match log.side {
+                    Side::SideBuy => self.bid_depths.orders.get(&log.order_id),
+                    Side::SideSell => self.ask_depths.orders.get(&log.order_id),
+                }
+                .cloned()
+                .ok_or(CustomError::NotFound(log.order_id))?;
+                let moved = BookOrder {
+                    price: log.new_price,
+                    ..order.clone()
+                };
+                match log.side {
+                    Side::SideBuy => {
+                        self.bid_depths.decr_size(log.order_id, &order.size)?;
+                        self.bid_depths.add(&moved);
+                    }
+                    Side::SideSell => {
+                        self.ask_depths.decr_size(log.order_id, &order.size)?;
+                        self.ask_depths.add(&moved);
+                    }
+                }
+            }
+            LogEvent::Heartbeat(_) => {}
+        }
+        Ok(())
+    }
+
+    // whether the order is able to rest on a book
+    fn validate_book_order(o: &BookOrder) -> Result<(), CustomError> {
+        if let OrderType::OrderTypeMarket = o.r#type {
+            return Err(CustomError::MarketOrderResting(o.order_id));
+        }
+        for (name, value) in [("price", &o.price), ("size", &o.size)].iter() {
+            if value.is_sign_negative() || value.is_zero() {
+                return Err(CustomError::InvalidOrder {
+                    order_id: o.order_id,
+                    reason: format!("{} {} must be positive", name, value),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    // initialize the book with one resting order per (price, size) level, without
+    // going through matching or emitting any logs, for simulators and backtests
+    pub fn seed_levels(&mut self, bids: &[(Decimal, Decimal)], asks: &[(Decimal, Decimal)]) {
+        self.version += 1;
+        let mut order_id = self
+            .ask_depths
+            .orders
+            .keys()
+            .chain(self.bid_depths.orders.keys())
+            .filter(|id| **id >= SEED_ORDER_ID_BASE)
+            .max()
+            .map_or(SEED_ORDER_ID_BASE, |id| id + 1);
+
+        for (levels, side) in [(bids, SIDE_BUY), (asks, SIDE_SELL)].iter() {
+            for (price, size) in levels.iter() {
+                let book_order = BookOrder {
+                    order_id,
+                    user_id: SEED_USER_ID,
+                    size: *size,
+                    price: *price,
+                    side: side.clone(),
+                    ..Default::default()
+                };
+                match side {
+                    Side::SideBuy => self.bid_depths.add(&book_order),
+                    Side::SideSell => self.ask_depths.add(&book_order),
+                }
+                order_id += 1;
+            }
+        }
+    }
+
+    pub fn best_bid(&self) -> Option<Decimal> {
+        self.bid_depths
+            .queue
+            .first_key_value()
+            .map(|(k, _)| k.price)
+    }
+
+    pub fn best_ask(&self) -> Option<Decimal> {
+        self.ask_depths
+            .queue
+            .first_key_value()
+            .map(|(k, _)| k.price)
+    }
+
+    // (bids, asks) aggregated by price, at most levels price levels per side
+    pub fn l2_depth(&self, levels: usize) -> (Levels, Levels) {
+        (
+            self.bid_depths.levels(levels),
+            self.ask_depths.levels(levels),
+        )
+    }
+
+    // (bids, asks) of at most n price levels per side like l2_depth, borrowed from the
+    // level cache of the depths instead of aggregated on every call
+    pub fn top_levels(&self, n: usize) -> (&[Level], &[Level]) {
+        (self.bid_depths.top_levels(n), self.ask_depths.top_levels(n))
+    }
+
+    // (price, size, order count) of each price level of the side, best first
+    pub fn levels_with_counts(&self, side: Side) -> Vec<(Decimal, Decimal, usize)> {
+        match side {
+            Side::SideBuy => self.bid_depths.levels_with_counts(),
+            Side::SideSell => self.ask_depths.levels_with_counts(),
+        }
+    }
+
+    pub fn book_shape(&self, max_levels: usize) -> BookShape {
+        let (bids, asks) = self.l2_depth(max_levels);
+        let cumulative = |levels: &Vec<(Decimal, Decimal)>| {
+            let mut total = Decimal::zero();
+            levels
+                .iter()
+                .map(|(_, size)| {
+                    total += *size;
+                    total
+                })
+                .collect::<Vec<Decimal>>()
+        };
+
+        BookShape {
+            bid_prices: bids.iter().map(|(price, _)| *price).collect(),
+            bid_sizes: bids.iter().map(|(_, size)| *size).collect(),
+            ask_prices: asks.iter().map(|(price, _)| *price).collect(),
+            ask_sizes: asks.iter().map(|(_, size)| *size).collect(),
+            cumulative_bid: cumulative(&bids),
+            cumulative_ask: cumulative(&asks),
+        }
+    }
+
+    // the worst price a taker of the side reaches to fill the size completely, the price of
+    // the last level it consumes, none if the book is too thin
+    pub fn sweep_price(&self, side: Side, size: Decimal) -> Option<Decimal> {
+        if size.is_sign_negative() || size.is_zero() {
+            return None;
+        }
+        let levels = match side {
+            Side::SideBuy => self.ask_depths.levels(usize::MAX),
+            Side::SideSell => self.bid_depths.levels(usize::MAX),
+        };
+        let mut total = Decimal::zero();
+        for (price, level_size) in levels {
+            total += level_size;
+            if Ordering::Less != Decimal::cmp(&total, &size) {
+                return Some(price);
+            }
+        }
+        None
+    }
+
+    // total resting size of the side priced within [from, to], either bound may come first
+    pub fn depth_in_range(&self, side: Side, from: Decimal, to: Decimal) -> Decimal {
+        let (low, high) = (Decimal::min(from, to), Decimal::max(from, to));
+        match side {
+            Side::SideBuy => self.bid_depths.size_in_range(&low, &high),
+            Side::SideSell => self.ask_depths.size_in_range(&low, &high),
+        }
+    }
+
+    // resting size of the side in buckets of bucket_width by the distance from the best
+    // price, the first bucket holds [best, best + width) away from the spread. the orders
+    // beyond the last bucket are clamped into it. empty for a bucket_width that is not
+    // positive
+    pub fn liquidity_histogram(
+        &self,
+        side: Side,
+        bucket_width: Decimal,
+        buckets: usize,
+    ) -> Vec<Decimal> {
+        self.liquidity_histogram_with_overflow(
+            side,
+            bucket_width,
+            buckets,
+            HistogramOverflow::Clamp,
+        )
+    }
+
+    // liquidity_histogram with the orders beyond the last bucket handled per overflow
+    pub fn liquidity_histogram_with_overflow(
+        &self,
+        side: Side,
+        bucket_width: Decimal,
+        buckets: usize,
+        overflow: HistogramOverflow,
+    ) -> Vec<Decimal> {
+        if buckets == 0 || bucket_width.is_sign_negative() || bucket_width.is_zero() {
+            return Vec::new();
+        }
+        let orders: Vec<&BookOrder> = self.iter_side(side).collect();
+
+        let mut histogram = vec![Decimal::zero(); buckets];
+        let best = match orders.first() {
+            Some(o) => o.price,
+            None => return histogram,
+        };
+        for o in orders {
+            let distance = (o.price - best).abs();
+            let index = match (distance / bucket_width).floor().to_usize() {
+                Some(index) if index < buckets => index,
+                _ => match overflow {
+                    HistogramOverflow::Clamp => buckets - 1,
+                    HistogramOverflow::Drop => continue,
+                },
+            };
+            histogram[index] += o.size;
+        }
+        histogram
+    }
+
+    pub fn depth_response(&self, levels: usize) -> DepthResponse {
+        let (bids, asks) = self.l2_depth(levels);
+        let pairs = |levels: Vec<(Decimal, Decimal)>| {
+            levels
+                .into_iter()
+                .map(|(price, size)| [price, size])
+                .collect::<Vec<[Decimal; 2]>>()
+        };
+        DepthResponse {
+            sequence: self.log_seq,
+            bids: pairs(bids),
+            asks: pairs(asks),
+        }
+    }
+
+    // read-only copy of the depth, which readers can share while the book keeps changing
+    pub fn freeze(&self) -> FrozenBook {
+        let (bids, asks) = self.l2_depth(usize::MAX);
+        FrozenBook::new(bids, asks)
+    }
+
+    // None if either side is empty
+    pub fn mid_price(&self) -> Option<Decimal> {
+        match (self.best_bid(), self.best_ask()) {
+            (Some(bid), Some(ask)) => Some((bid + ask).div(Decimal::from(2))),
+            _ => None,
+        }
+    }
+
+    // None if either side is empty or the mid is zero
+    pub fn spread_bps(&self) -> Option<Decimal> {
+        let (bid, ask, mid) = (self.best_bid()?, self.best_ask()?, self.mid_price()?);
+        if mid.is_zero() {
+            return None;
+        }
+        Some(ask.sub(bid).div(mid).mul(Decimal::from(10000)))
+    }
+
+    // (number of orders, size) ahead of the resting order on its side, which are matched
+    // before it: the better levels and the earlier orders of its own level
+    pub fn queue_position(&self, order_id: u64) -> Option<(usize, Decimal)> {
+        let ahead: Vec<&BookOrder> = if self.bid_depths.orders.contains_key(&order_id) {
+            self.bid_depths
+                .queue
+                .values()
+                .take_while(|id| **id != order_id)
+                .map(|id| &self.bid_depths.orders[id])
+                .collect()
+        } else if self.ask_depths.orders.contains_key(&order_id) {
+            self.ask_depths
+                .queue
+                .values()
+                .take_while(|id| **id != order_id)
+                .map(|id| &self.ask_depths.orders[id])
+                .collect()
+        } else {
+            return None;
+        };
+        Some((ahead.len(), ahead.iter().map(|o| o.size).sum()))
+    }
+
+    // rough chance in [0, 1] that the resting order is filled if recent_volume trades on
+    // its side again: the share of the size ahead plus its own size that the volume covers
+    pub fn fill_probability(&self, order_id: u64, recent_volume: Decimal) -> Option<Decimal> {
+        let (_, size_ahead) = self.queue_position(order_id)?;
+        let size = match self.bid_depths.orders.get(&order_id) {
+            Some(o) => o.size,
+            None => self.ask_depths.orders[&order_id].size,
+        };
+
+        let needed = size_ahead + size;
+        if needed.is_zero() || recent_volume.is_sign_negative() {
+            return Some(Decimal::zero());
+        }
+        Some(Decimal::min(recent_volume.div(needed), Decimal::ONE))
+    }
+
+    // every resting order without cloning, the asks and then the bids, both in the order
+    // they would be matched
+    pub fn iter_all(&self) -> impl Iterator<Item = (Side, &BookOrder)> + '_ {
+        let asks = self
+            .ask_depths
+            .queue
+            .values()
+            .map(move |id| (SIDE_SELL, &self.ask_depths.orders[id]));
+        let bids = self
+            .bid_depths
+            .queue
+            .values()
+            .map(move |id| (SIDE_BUY, &self.bid_depths.orders[id]));
+        asks.chain(bids)
+    }
+
+    // whether both books hold the same resting orders in the same queue order, which also
+    // gives the same depth and top of book, the sequences and the window are not compared
+    pub fn equal_ignoring_seqs(&self, other: &OrderBook) -> bool {
+        self.bid_depths.queue == other.bid_depths.queue
+            && self.ask_depths.queue == other.ask_depths.queue
+            && self.bid_depths.orders == other.bid_depths.orders
+            && self.ask_depths.orders == other.ask_depths.orders
+    }
+
+    // resting order ids of the side, in the order they would be matched
+    pub fn order_ids(&self, side: Side) -> Vec<u64> {
+        match side {
+            Side::SideBuy => self.bid_depths.queue.values().copied().collect(),
+            Side::SideSell => self.ask_depths.queue.values().copied().collect(),
+        }
+    }
+
+    // the resting orders of the side in the order they would be matched. scans go through
+    // the queue and never iterate the hash maps, whose order differs from run to run
+    fn iter_side(&self, side: Side) -> Box<dyn Iterator<Item = &BookOrder> + '_> {
+        match side {
+            Side::SideBuy => Box::new(
+                self.bid_depths
+                    .queue
+                    .values()
+                    .map(move |id| &self.bid_depths.orders[id]),
+            ),
+            Side::SideSell => Box::new(
+                self.ask_depths
+                    .queue
+                    .values()
+                    .map(move |id| &self.ask_depths.orders[id]),
+            ),
+        }
+    }
+
+    // the resting orders of the user, asks then bids, each side in price-time order
+    pub fn orders_for_user(&self, user_id: u64) -> Vec<&BookOrder> {
+        self.iter_side(SIDE_SELL)
+            .chain(self.iter_side(SIDE_BUY))
+            .filter(|o| o.user_id == user_id)
+            .collect()
+    }
+
+    // total price*size the user has resting on the (bid, ask) side, by scanning the orders
+    pub fn user_resting_notional(&self, user_id: u64) -> (Decimal, Decimal) {
+        let notional = |side: Side| {
+            self.iter_side(side)
+                .filter(|o| o.user_id == user_id)
+                .fold(Decimal::zero(), |acc, o| acc + o.price.mul(o.size))
+        };
+        (notional(SIDE_BUY), notional(SIDE_SELL))
+    }
+
+    // the top of the book with the next log sequence, for consumers to tell a quiet book
+    // from a stale one
+    pub fn heartbeat(&mut self) -> HeartbeatLog {
+        new_heartbeat_log(
+            self.next_log_seq(),
+            &self.product.id,
+            self.best_bid(),
+            self.best_ask(),
+        )
+    }
+
+    pub fn next_log_seq(&mut self) -> u64 {
+        self.log_seq += 1;
+        self.log_seq
+    }
+
+    pub fn next_trade_seq(&mut self) -> u64 {
+        self.trade_seq += 1;
+        self.trade_seq
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::str::FromStr;
+
+    use rust_decimal::Decimal;
+
+    use crate::matching::log::{DoneLog, LogEvent, LogTrait};
+    use crate::matching::matching_strategy::ProRataStrategy;
+    use crate::matching::order_book::{
+        BookOrder, CancelOutcome, DepthResponse, HistogramOverflow, OrderBook, RejectReason,
+        RestPricePolicy, SelfTradePrevention, RECENT_TRADES_CAP, SEED_ORDER_ID_BASE, SEED_USER_ID,
+    };
+    use crate::matching::ordering::{OrderingTrait, PriceOrderIdKeyAsc};
+    use crate::models::models::{Order, Peg, Product};
+    use crate::models::types::*;
+    use crate::utils::error::CustomError;
+    use crate::utils::window::Window;
+
+    fn new_product() -> Product {
+        Product {
+            id: "BTC-USD".to_string(),
+            base_currency: "BTC".to_string(),
+            quote_currency: "USD".to_string(),
+            base_scale: 4,
+            quote_scale: 2,
+            ..Default::default()
+        }
+    }
+
+    fn new_order(
+        id: u64,
+        user_id: u64,
+        r#type: OrderType,
+        side: Side,
+        price: &str,
+        size: &str,
+        funds: &str,
+    ) -> Order {
+        Order {
+            id,
+            created_at: 1695783003020967000,
+            product_id: "BTC-USD".to_string(),
+            user_id,
+            client_oid: "".to_string(),
+            price: dec(price),
+            size: dec(size),
+            funds: dec(funds),
+            r#type,
+            side,
+            time_in_force: TimeInForceType::GoodTillCanceled,
+            status: OrderStatus::OrderStatusNew,
+            max_slippage_bps: None,
+            priority_class: 0,
+            all_or_none: false,
+            stp_group_id: None,
+            day_order: false,
+            peg: None,
+        }
+    }
+
+    fn limit(id: u64, side: Side, price: &str, size: &str) -> Order {
+        new_order(id, id, ORDER_TYPE_LIMIT, side, price, size, "0")
+    }
+
+    fn user_limit(id: u64, user_id: u64, side: Side, price: &str, size: &str) -> Order {
+        new_order(id, user_id, ORDER_TYPE_LIMIT, side, price, size, "0")
+    }
+
+    fn market_buy(id: u64, funds: &str) -> Order {
+        new_order(id, id, ORDER_TYPE_MARKET, SIDE_BUY, "0", "0", funds)
+    }
+
+    fn market_sell(id: u64, size: &str) -> Order {
+        new_order(id, id, ORDER_TYPE_MARKET, SIDE_SELL, "0", size, "0")
+    }
+
+    fn dec(s: &str) -> Decimal {
+        Decimal::from_str(s).unwrap()
+    }
+
+    fn to_json(logs: &Vec<Box<dyn LogTrait>>) -> Vec<serde_json::Value> {
+        logs.iter()
+            .map(|log| serde_json::to_value(log).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_fill_or_kill_market_buy_funds() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        order_book.apply_order(&limit(1, SIDE_SELL, "100", "1"));
+        order_book.apply_order(&limit(2, SIDE_SELL, "101", "1"));
+
+        // book can absorb at most 201 of funds
+        assert!(!order_book.is_order_will_full_match(&market_buy(3, "500")));
+
+        // leftover funds can't buy one more unit at the last touched price
+        assert!(order_book.is_order_will_full_match(&market_buy(4, "150")));
+
+        assert!(!order_book.is_order_will_full_match(&market_sell(5, "1")));
+    }
+
+    #[test]
+    fn test_self_trade_prevention() {
+        let policies = [
+            (SelfTradePrevention::CancelTaker, false, true),
+            (SelfTradePrevention::CancelMaker, true, false),
+            (SelfTradePrevention::CancelBoth, true, true),
+        ];
+        for (policy, maker_cancelled, taker_cancelled) in policies.iter() {
+            let mut order_book = OrderBook::new_order_book(&new_product());
+            order_book.self_trade_prevention = policy.clone();
+            order_book.apply_order(&user_limit(1, 7, SIDE_SELL, "100", "1"));
+            order_book.apply_order(&user_limit(2, 8, SIDE_SELL, "101", "1"));
+
+            let logs = to_json(&order_book.apply_order(&user_limit(3, 7, SIDE_BUY, "101", "2")));
+            let stp_ids: Vec<u64> = logs
+                .iter()
+                .filter(|log| log["reason"] == "stp")
+                .map(|log| log["order_id"].as_u64().unwrap())
+                .collect();
+
+            assert_eq!(stp_ids.contains(&1), *maker_cancelled);
+            assert_eq!(stp_ids.contains(&3), *taker_cancelled);
+            // the taker trades with the other user only if it keeps matching
+            let matched = logs.iter().any(|log| log["base"]["type"] == "match");
+            assert_eq!(matched, !*taker_cancelled);
+        }
+    }
+
+    #[test]
+    fn test_try_apply_order_rollback() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        order_book.apply_order(&limit(1, SIDE_SELL, "100", "1"));
+        // inject an order which is queued but missing from the book behind the first maker
+        order_book
+            .ask_depths
+            .queue
+            .insert(PriceOrderIdKeyAsc::new(&dec("101"), 0, 99), 99);
+        let (trade_seq, log_seq) = (order_book.trade_seq, order_book.log_seq);
+
+        assert!(order_book
+            .try_apply_order(&limit(2, SIDE_BUY, "101", "2"))
+            .is_err());
+
+        // the first maker had already been filled before the failure
+        assert_eq!(order_book.ask_depths.orders.get(&1).unwrap().size, dec("1"));
+        assert_eq!(order_book.ask_depths.queue.len(), 2);
+        assert!(order_book.bid_depths.orders.is_empty());
+        assert_eq!(
+            (order_book.trade_seq, order_book.log_seq),
+            (trade_seq, log_seq)
+        );
+        // the same order is still accepted once the book is repaired
+        order_book.ask_depths.queue.clear();
+        order_book
+            .ask_depths
+            .add(&order_book.ask_depths.orders[&1].clone());
+        assert_eq!(
+            order_book
+                .try_apply_order(&limit(2, SIDE_BUY, "101", "2"))
+                .unwrap()
+                .len(),
+            3
+        );
+    }
+
+    #[test]
+    fn test_repeated_invalid_order() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        let mut order = limit(1, SIDE_BUY, "100", "1");
+        order.funds = dec("100");
+
+        let logs = to_json(&order_book.apply_order(&order));
+        assert_eq!(logs.len(), 1);
+        assert_eq!(logs[0]["reason"], "cancelled");
+        // the same id again is a duplicate, with no second done log
+        assert!(order_book.apply_order(&order).is_empty());
+
+        let breakdown = order_book.rejection_breakdown();
+        assert_eq!(breakdown[&RejectReason::InvalidOrder], 1);
+        assert_eq!(breakdown[&RejectReason::DuplicateOrderId], 1);
+        assert_eq!(order_book.session_done_logs().len(), 1);
+    }
+
+    #[test]
+    fn test_roll_back_rejections() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        order_book.count_rejection(RejectReason::OffLot);
+        let checkpoint = order_book.checkpoint();
+        order_book.count_rejection(RejectReason::OffLot);
+        order_book.count_rejection(RejectReason::TradeThrough);
+        order_book.roll_back(checkpoint, &[], Vec::new());
+
+        let breakdown = order_book.rejection_breakdown();
+        assert_eq!(breakdown.len(), 1);
+        assert_eq!(breakdown[&RejectReason::OffLot], 1);
+    }
+
+    #[test]
+    fn test_seed_levels() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        order_book.seed_levels(
+            &[(dec("99"), dec("1")), (dec("98"), dec("2"))],
+            &[(dec("100"), dec("1")), (dec("101"), dec("2"))],
+        );
+        order_book.seed_levels(&[], &[(dec("102"), dec("3"))]);
+        assert_eq!(order_book.bid_depths.orders.len(), 2);
+        assert_eq!(order_book.ask_depths.orders.len(), 3);
+        assert_eq!(order_book.log_seq, 0);
+
+        let logs = to_json(&order_book.apply_order(&limit(1, SIDE_BUY, "101", "2")));
+        let makers: Vec<u64> = logs
+            .iter()
+            .filter(|log| log["base"]["type"] == "match")
+            .map(|log| log["maker_order_id"].as_u64().unwrap())
+            .collect();
+        assert_eq!(makers, vec![SEED_ORDER_ID_BASE + 2, SEED_ORDER_ID_BASE + 3]);
+        assert_eq!(logs[0]["maker_user_id"], SEED_USER_ID);
+        assert!(order_book
+            .ask_depths
+            .orders
+            .contains_key(&(SEED_ORDER_ID_BASE + 4)));
+    }
+
+    #[test]
+    fn test_rejection_breakdown() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        order_book.self_trade_prevention = SelfTradePrevention::CancelTaker;
+        order_book.process_order(&limit(1, SIDE_SELL, "100", "1"));
+
+        order_book.process_order(&limit(1, SIDE_SELL, "100", "1"));
+        let mut post_only = limit(2, SIDE_BUY, "100", "1");
+        post_only.time_in_force = GOOD_TILL_CROSSING;
+        order_book.process_order(&post_only);
+        let mut fill_or_kill = limit(3, SIDE_BUY, "100", "5");
+        fill_or_kill.time_in_force = FILL_OR_KILL;
+        order_book.process_order(&fill_or_kill);
+        order_book.process_order(&user_limit(4, 1, SIDE_BUY, "100", "1"));
+
+        let breakdown = order_book.rejection_breakdown();
+        assert_eq!(breakdown.len(), 4);
+        for reason in [
+            RejectReason::DuplicateOrderId,
+            RejectReason::PostOnlyCross,
+            RejectReason::FillOrKill,
+            RejectReason::SelfTrade,
+        ]
+        .iter()
+        {
+            assert_eq!(breakdown[reason], 1);
+        }
+    }
+
+    #[test]
+    fn test_run_auction() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        order_book.auction_mode = true;
+        for order in [
+            limit(1, SIDE_BUY, "101", "3"),
+            limit(2, SIDE_BUY, "100", "2"),
+            limit(3, SIDE_SELL, "99", "2"),
+            limit(4, SIDE_SELL, "100", "2"),
+            limit(5, SIDE_SELL, "102", "5"),
+        ]
+        .iter()
+        {
+            let logs = to_json(&order_book.apply_order(order));
+            assert_eq!(logs.len(), 1);
+            assert_eq!(logs[0]["base"]["type"], "open");
+        }
+        assert_eq!(
+            to_json(&order_book.apply_order(&market_buy(6, "100")))[0]["reason"],
+            "cancelled"
+        );
+
+        let (price, logs) = order_book.run_auction().unwrap();
+        assert_eq!(price, dec("100"));
+        let matches: Vec<serde_json::Value> = to_json(&logs)
+            .into_iter()
+            .filter(|log| log["base"]["type"] == "match")
+            .collect();
+        assert_eq!(matches.len(), 3);
+        assert!(matches.iter().all(|log| log["price"] == "100"));
+        assert!(matches.iter().all(|log| log["auction"] == true));
+        // the bid is the taker whichever order came in later
+        let pairs: Vec<(u64, u64)> = matches
+            .iter()
+            .map(|log| {
+                let id = |key: &str| log[key].as_u64().unwrap();
+                (id("taker_order_id"), id("maker_order_id"))
+            })
+            .collect();
+        assert_eq!(pairs, vec![(1, 3), (1, 4), (2, 4)]);
+
+        assert!(!order_book.auction_mode);
+        assert_eq!(order_book.bid_depths.queue.len(), 1);
+        assert_eq!(order_book.bid_depths.orders[&2].size, dec("1"));
+        assert_eq!(order_book.ask_depths.queue.len(), 1);
+        assert!(order_book.ask_depths.orders.contains_key(&5));
+    }
+
+    #[test]
+    fn test_order_ids() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        order_book.apply_order(&limit(1, SIDE_BUY, "99", "1"));
+        order_book.apply_order(&limit(2, SIDE_BUY, "100", "1"));
+        order_book.apply_order(&limit(3, SIDE_BUY, "99", "1"));
+        order_book.apply_order(&limit(4, SIDE_BUY, "98", "1"));
+        order_book.apply_order(&limit(5, SIDE_SELL, "101", "1"));
+
+        let order_ids = order_book.order_ids(SIDE_BUY);
+        assert_eq!(order_ids, vec![2, 1, 3, 4]);
+        assert_eq!(order_book.order_ids(SIDE_SELL), vec![5]);
+
+        let logs = to_json(&order_book.apply_order(&limit(6, SIDE_SELL, "98", "4")));
+        let makers: Vec<u64> = logs
+            .iter()
+            .filter(|log| log["base"]["type"] == "match")
+            .map(|log| log["maker_order_id"].as_u64().unwrap())
+            .collect();
+        assert_eq!(makers, order_ids);
+    }
+
+    #[test]
+    fn test_market_order_against_empty_book() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        for order in [
+            market_buy(1, "100"),
+            market_buy(2, "0"),
+            market_sell(3, "1"),
+            market_sell(4, "0"),
+        ]
+        .iter()
+        {
+            let logs = to_json(&order_book.apply_order(order));
+            assert_eq!(logs.len(), 1);
+            assert_eq!(logs[0]["base"]["type"], "done");
+            assert_eq!(logs[0]["reason"], "cancelled");
+        }
+    }
+
+    #[test]
+    fn test_market_order_slippage() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        order_book.apply_order(&limit(1, SIDE_BUY, "99", "1"));
+        for (id, price) in [(2, "100"), (3, "101"), (4, "103")].iter() {
+            order_book.apply_order(&limit(*id, SIDE_SELL, price, "1"));
+        }
+        assert_eq!(order_book.mid_price(), Some(dec("99.5")));
+
+        // mid 99.5 with 2% slippage allows up to 101.49
+        let mut buy = market_buy(5, "1000");
+        buy.max_slippage_bps = Some(dec("200"));
+        let logs = to_json(&order_book.apply_order(&buy));
+        let prices: Vec<&serde_json::Value> = logs
+            .iter()
+            .filter(|log| log["base"]["type"] == "match")
+            .map(|log| &log["price"])
+            .collect();
+        assert_eq!(prices, vec!["100", "101"]);
+        assert_eq!(logs.last().unwrap()["reason"], "cancelled");
+        assert_eq!(order_book.best_ask(), Some(dec("103")));
+
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        order_book.apply_order(&limit(1, SIDE_SELL, "101", "1"));
+        for (id, price) in [(2, "100"), (3, "99"), (4, "97")].iter() {
+            order_book.apply_order(&limit(*id, SIDE_BUY, price, "1"));
+        }
+
+        // mid 100.5 with 2% slippage allows down to 98.49
+        let mut sell = market_sell(5, "5");
+        sell.max_slippage_bps = Some(dec("200"));
+        order_book.apply_order(&sell);
+        assert_eq!(order_book.best_bid(), Some(dec("97")));
+
+        // no bids, falls back to the best ask 100 and allows up to 100.5
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        order_book.apply_order(&limit(1, SIDE_SELL, "100", "1"));
+        order_book.apply_order(&limit(2, SIDE_SELL, "101", "1"));
+        let mut buy = market_buy(3, "1000");
+        buy.max_slippage_bps = Some(dec("50"));
+        order_book.apply_order(&buy);
+        assert_eq!(order_book.order_ids(SIDE_SELL), vec![2]);
+    }
+
+    #[test]
+    fn test_cancel_order_idempotent() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        order_book.apply_order(&limit(1, SIDE_SELL, "100", "1"));
+        order_book.apply_order(&limit(2, SIDE_BUY, "99", "2"));
+
+        // filled, then cancelled
+        order_book.apply_order(&limit(3, SIDE_BUY, "100", "1"));
+        let log_seq = order_book.log_seq;
+        assert!(order_book
+            .cancel_order_logs(&limit(1, SIDE_SELL, "100", "1"))
+            .is_empty());
+        assert_eq!(order_book.log_seq, log_seq);
+
+        // partially filled, then cancelled twice
+        order_book.apply_order(&limit(4, SIDE_SELL, "99", "0.5"));
+        let logs = to_json(&order_book.cancel_order_logs(&limit(2, SIDE_BUY, "99", "2")));
+        assert_eq!(logs.len(), 1);
+        assert_eq!(logs[0]["order_id"], 2);
+        assert_eq!(logs[0]["reason"], "cancelled");
+        assert!(order_book.bid_depths.orders.is_empty());
+        assert!(order_book
+            .cancel_order_logs(&limit(2, SIDE_BUY, "99", "2"))
+            .is_empty());
+    }
+
+    #[test]
+    fn test_lot_size() {
+        let mut product = new_product();
+        product.lot_size = dec("0.1");
+
+        let mut order_book = OrderBook::new_order_book(&product);
+        let logs = to_json(&order_book.apply_order(&limit(1, SIDE_BUY, "100", "1.333")));
+        assert_eq!(logs[0]["base"]["type"], "open");
+        assert_eq!(order_book.bid_depths.orders[&1].size, dec("1.3"));
+        let logs = to_json(&order_book.apply_order(&limit(2, SIDE_BUY, "100", "0.05")));
+        assert_eq!(logs[0]["reason"], "cancelled");
+
+        product.reject_off_lot = true;
+        let mut order_book = OrderBook::new_order_book(&product);
+        let logs = to_json(&order_book.apply_order(&limit(1, SIDE_BUY, "100", "1.333")));
+        assert_eq!(logs[0]["base"]["type"], "done");
+        assert_eq!(logs[0]["reason"], "cancelled");
+        assert!(order_book.bid_depths.orders.is_empty());
+        assert_eq!(order_book.rejection_breakdown()[&RejectReason::OffLot], 1);
+
+        order_book.apply_order(&limit(2, SIDE_BUY, "100", "1.30"));
+        assert_eq!(order_book.bid_depths.orders[&2].size, dec("1.3"));
+    }
+
+    #[test]
+    fn test_apply_order_fills_only() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        assert!(order_book
+            .apply_order_fills_only(&limit(1, SIDE_SELL, "100", "1"))
+            .is_empty());
+        order_book.apply_order(&limit(2, SIDE_SELL, "101", "1"));
+
+        let fills = order_book.apply_order_fills_only(&limit(3, SIDE_BUY, "101", "2.5"));
+        assert_eq!(fills.len(), 2);
+        assert_eq!(fills[0].maker_order_id, 1);
+        assert_eq!(fills[1].maker_order_id, 2);
+        assert_eq!(fills[1].size, dec("1"));
+        assert!(order_book.ask_depths.orders.is_empty());
+        assert_eq!(order_book.bid_depths.orders[&3].size, dec("0.5"));
+    }
+
+    #[test]
+    fn test_last_order_rested() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        order_book.process_order(&limit(1, SIDE_SELL, "100", "1"));
+        assert!(order_book.last_order_rested());
+
+        let mut ioc = limit(2, SIDE_BUY, "100", "2");
+        ioc.time_in_force = TimeInForceType::ImmediateOrCancel;
+        order_book.process_order(&ioc);
+        assert!(!order_book.last_order_rested());
+        assert!(order_book.bid_depths.orders.is_empty());
+
+        order_book.process_order(&limit(3, SIDE_SELL, "100", "1"));
+        assert!(order_book.last_order_rested());
+        order_book.process_order(&limit(4, SIDE_BUY, "100", "1"));
+        assert!(!order_book.last_order_rested());
+
+        order_book.process_order(&limit(5, SIDE_BUY, "99", "1"));
+        assert!(order_book.last_order_rested());
+    }
+
+    #[test]
+    fn test_max_orders_per_user() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        order_book.max_orders_per_user = Some(2);
+
+        order_book.apply_order(&user_limit(1, 7, SIDE_BUY, "99", "1"));
+        order_book.apply_order(&user_limit(2, 7, SIDE_BUY, "98", "1"));
+        let logs = to_json(&order_book.apply_order(&user_limit(3, 7, SIDE_BUY, "97", "1")));
+        assert_eq!(logs.len(), 1);
+        assert_eq!(logs[0]["base"]["type"], "done");
+        assert_eq!(logs[0]["reason"], "cancelled");
+        assert_eq!(order_book.order_ids(SIDE_BUY), vec![1, 2]);
+        assert_eq!(
+            order_book.rejection_breakdown()[&RejectReason::MaxOrdersPerUser],
+            1
+        );
+
+        // the other side and the other users are counted apart
+        order_book.apply_order(&user_limit(4, 7, SIDE_SELL, "101", "1"));
+        order_book.apply_order(&user_limit(5, 8, SIDE_BUY, "97", "1"));
+        order_book.apply_order(&user_limit(6, 8, SIDE_BUY, "96", "1"));
+        assert_eq!(order_book.order_ids(SIDE_BUY), vec![1, 2, 5, 6]);
+        assert_eq!(order_book.order_ids(SIDE_SELL), vec![4]);
+
+        // once an order of the user is filled, a new one can rest again
+        order_book.apply_order(&user_limit(7, 9, SIDE_SELL, "99", "1"));
+        order_book.apply_order(&user_limit(8, 7, SIDE_BUY, "97", "1"));
+        assert_eq!(order_book.order_ids(SIDE_BUY), vec![2, 5, 8, 6]);
+    }
+
+    #[test]
+    fn test_latency_observer() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        order_book.apply_order(&limit(1, SIDE_SELL, "100", "1"));
+
+        let elapsed = Rc::new(RefCell::new(Vec::new()));
+        let observed = elapsed.clone();
+        order_book.set_latency_observer(Box::new(move |d| observed.borrow_mut().push(d)));
+
+        order_book.apply_order(&limit(2, SIDE_BUY, "100", "1"));
+        order_book.apply_order(&limit(2, SIDE_BUY, "100", "1"));
+        order_book.process_order(&limit(3, SIDE_BUY, "99", "1"));
+        order_book
+            .try_apply_order(&limit(4, SIDE_BUY, "98", "1"))
+            .unwrap();
+        assert_eq!(elapsed.borrow().len(), 4);
+    }
+
+    #[test]
+    fn test_reference_bbo_trade_through() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        order_book.apply_order(&limit(1, SIDE_BUY, "100", "1"));
+        order_book.apply_order(&limit(2, SIDE_BUY, "98", "1"));
+        order_book.set_reference_bbo(Some((dec("99"), dec("101"))));
+
+        // the market sell stops before the bid below the reference bid
+        let logs = to_json(&order_book.apply_order(&market_sell(3, "2")));
+        assert_eq!(logs.len(), 3);
+        assert_eq!(logs[0]["price"], "100");
+        assert_eq!(logs[2]["order_id"], 3);
+        assert_eq!(logs[2]["reason"], "cancelled");
+        assert_eq!(order_book.order_ids(SIDE_BUY), vec![2]);
+        assert_eq!(
+            order_book.rejection_breakdown()[&RejectReason::TradeThrough],
+            1
+        );
+
+        // the rest of a limit order is cancelled instead of resting through the reference
+        order_book.apply_order(&limit(4, SIDE_SELL, "102", "1"));
+        let logs = to_json(&order_book.apply_order(&limit(5, SIDE_BUY, "102", "1")));
+        assert_eq!(logs.len(), 1);
+        assert_eq!(logs[0]["reason"], "cancelled");
+        assert_eq!(order_book.order_ids(SIDE_SELL), vec![4]);
+
+        order_book.set_reference_bbo(None);
+        let logs = to_json(&order_book.apply_order(&limit(6, SIDE_BUY, "102", "1")));
+        assert_eq!(logs[0]["price"], "102");
+        assert!(order_book.ask_depths.orders.is_empty());
+    }
+
+    #[test]
+    fn test_memory_footprint() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        let mut footprint = order_book.memory_footprint();
+        assert!(footprint >= order_book.order_id_window.bit_map.len());
+
+        for id in 1..200 {
+            order_book.apply_order(&limit(id, SIDE_BUY, &(id % 50 + 1).to_string(), "1"));
+            let grown = order_book.memory_footprint();
+            assert!(grown > footprint);
+            footprint = grown;
+        }
+    }
+
+    #[test]
+    fn test_validate_order() {
+        let mut limit_with_funds = limit(1, SIDE_BUY, "100", "1");
+        limit_with_funds.funds = dec("100");
+        let mut market_buy_with_size = market_buy(2, "100");
+        market_buy_with_size.size = dec("1");
+        let mut market_sell_with_funds = market_sell(3, "1");
+        market_sell_with_funds.funds = dec("100");
+        let malformed = vec![
+            limit_with_funds,
+            limit(4, SIDE_SELL, "100", "0"),
+            limit(5, SIDE_SELL, "100", "-1"),
+            market_buy_with_size,
+            market_buy(6, "0"),
+            market_sell_with_funds,
+            market_sell(7, "0"),
+        ];
+
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        order_book.apply_order(&limit(8, SIDE_SELL, "100", "10"));
+        for order in &malformed {
+            assert!(OrderBook::validate_order(order).is_err());
+            assert!(order_book.try_apply_order(order).is_err());
+            let logs = to_json(&order_book.apply_order(order));
+            assert_eq!(logs.len(), 1);
+            assert_eq!(logs[0]["reason"], "cancelled");
+        }
+        assert_eq!(
+            order_book.rejection_breakdown()[&RejectReason::InvalidOrder],
+            14
+        );
+        assert_eq!(order_book.trade_seq, 0);
+
+        assert!(OrderBook::validate_order(&limit(9, SIDE_BUY, "100", "1")).is_ok());
+        assert!(OrderBook::validate_order(&market_buy(10, "100")).is_ok());
+        assert!(OrderBook::validate_order(&market_sell(11, "1")).is_ok());
+    }
+
+    #[test]
+    fn test_spread_bps() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        assert_eq!(order_book.spread_bps(), None);
+        order_book.apply_order(&limit(1, SIDE_BUY, "99.99", "1"));
+        assert_eq!(order_book.spread_bps(), None);
+
+        // two ticks wide around 100
+        order_book.apply_order(&limit(2, SIDE_SELL, "100.01", "1"));
+        assert_eq!(order_book.spread_bps(), Some(dec("2")));
+
+        // one tick wide
+        order_book.apply_order(&limit(3, SIDE_SELL, "100.00", "1"));
+        assert_eq!(order_book.spread_bps().unwrap().round_dp(2), dec("1.00"));
+    }
+
+    #[test]
+    fn test_priority_class() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        order_book.apply_order(&limit(1, SIDE_SELL, "100", "1"));
+        order_book.apply_order(&limit(2, SIDE_SELL, "99", "1"));
+        let mut market_maker = limit(3, SIDE_SELL, "100", "1");
+        market_maker.priority_class = 1;
+        order_book.apply_order(&market_maker);
+        let mut market_maker = limit(4, SIDE_BUY, "98", "1");
+        market_maker.priority_class = 1;
+        order_book.apply_order(&market_maker);
+        order_book.apply_order(&limit(5, SIDE_BUY, "98", "1"));
+        order_book.apply_order(&limit(6, SIDE_BUY, "98.5", "1"));
+
+        // price still goes first, then the class, then the arrival
+        assert_eq!(order_book.order_ids(SIDE_SELL), vec![2, 3, 1]);
+        assert_eq!(order_book.order_ids(SIDE_BUY), vec![6, 4, 5]);
+
+        let logs = to_json(&order_book.apply_order(&limit(7, SIDE_BUY, "100", "2")));
+        assert_eq!(logs[0]["maker_order_id"], 2);
+        assert_eq!(logs[2]["maker_order_id"], 3);
+        assert_eq!(order_book.order_ids(SIDE_SELL), vec![1]);
+
+        // the key is rebuilt with the class when the order leaves the book
+        order_book
+            .cancel_order(&limit(4, SIDE_BUY, "98", "1"))
+            .unwrap();
+        assert_eq!(order_book.order_ids(SIDE_BUY), vec![6, 5]);
+    }
+
+    #[test]
+    fn test_preview_apply() {
+        let without_time = |logs: &Vec<Box<dyn LogTrait>>| {
+            let mut logs = to_json(logs);
+            for log in logs.iter_mut() {
+                log["base"]["time"] = serde_json::Value::Null;
+            }
+            logs
+        };
+
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        order_book.apply_order(&limit(1, SIDE_SELL, "100", "1"));
+        order_book.apply_order(&limit(2, SIDE_SELL, "101", "1"));
+
+        let order = limit(3, SIDE_BUY, "101", "2.5");
+        let preview = order_book.preview_apply(&order);
+        assert_eq!(preview.len(), 5);
+        assert_eq!(order_book.order_ids(SIDE_SELL), vec![1, 2]);
+        assert!(order_book.bid_depths.orders.is_empty());
+        assert_eq!((order_book.trade_seq, order_book.log_seq), (0, 2));
+
+        let logs = order_book.apply_order(&order);
+        assert_eq!(without_time(&preview), without_time(&logs));
+
+        // the order id is now taken, previewing it again shows nothing
+        assert!(order_book.preview_apply(&order).is_empty());
+    }
+
+    #[test]
+    fn test_preview_view() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        order_book.self_trade_prevention = SelfTradePrevention::CancelBoth;
+        order_book.auction_mode = true;
+        order_book.rest_price_policy = RestPricePolicy::LastMatchPrice;
+        order_book.min_price_improvement = dec("0.01");
+        order_book.clamp_to_protection = true;
+        order_book.protection_bps = dec("50");
+        order_book.max_orders_per_user = Some(3);
+        order_book.max_notional_per_side = Some(dec("1000"));
+        order_book.report_fok_fillable = true;
+        order_book.set_reference_bbo(Some((dec("99"), dec("101"))));
+        order_book.set_latency_observer(Box::new(|_| {}));
+        order_book.apply_order(&limit(1, SIDE_SELL, "100", "1"));
+        order_book.apply_order(&limit(2, SIDE_BUY, "99", "1"));
+        order_book.count_rejection(RejectReason::OffLot);
+        order_book.auction_mode = false;
+        order_book.apply_order(&limit(3, SIDE_BUY, "100", "1"));
+        order_book.halted = true;
+
+        let view = order_book.preview_view();
+        assert_eq!(view.product.id, order_book.product.id);
+        assert!(view.equal_ignoring_seqs(&order_book));
+        assert_eq!(view.trade_seq, order_book.trade_seq);
+        assert_eq!(view.log_seq, order_book.log_seq);
+        assert_eq!(view.order_id_window.max, order_book.order_id_window.max);
+        assert!(view.order_id_window.contains(3));
+        assert_eq!(view.self_trade_prevention, SelfTradePrevention::CancelBoth);
+        assert!(!view.auction_mode);
+        assert!(matches!(
+            view.rest_price_policy,
+            RestPricePolicy::LastMatchPrice
+        ));
+        assert_eq!(view.min_price_improvement, dec("0.01"));
+        assert!(view.clamp_to_protection);
+        assert_eq!(view.protection_bps, dec("50"));
+        assert!(view.halted);
+        assert_eq!(view.max_orders_per_user, Some(3));
+        assert_eq!(view.max_notional_per_side, Some(dec("1000")));
+        assert!(view.report_fok_fillable);
+        assert_eq!(view.reference_bbo, Some((dec("99"), dec("101"))));
+        assert_eq!(view.last_trade(), order_book.last_trade());
+        assert_eq!(view.recent_trades, order_book.recent_trades);
+        assert_eq!(view.version(), order_book.version());
+        // what the preview adds to is not carried over
+        assert!(view.rejection_breakdown().is_empty());
+        assert!(!view.last_order_rested());
+        assert!(view.latency_observer.is_none());
+        assert!(view.session_done_logs().is_empty());
+        assert!(view.evicted_trades.is_none());
+    }
+
+    #[test]
+    fn test_market_buy_remaining_funds() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        order_book.apply_order(&limit(1, SIDE_SELL, "3", "10"));
+
+        // 3.3333 is bought for 9.9999, the rest can't buy another unit
+        let logs = to_json(&order_book.apply_order(&market_buy(2, "10")));
+        assert_eq!(logs.len(), 2);
+        assert_eq!(logs[0]["size"], "3.3333");
+        assert_eq!(logs[1]["order_id"], 2);
+        assert_eq!(logs[1]["reason"], "cancelled");
+        assert_eq!(logs[1]["remaining_size"], "0");
+        assert_eq!(logs[1]["remaining_funds"], "0.0001");
+
+        let logs = to_json(&order_book.apply_order(&limit(3, SIDE_BUY, "3", "1")));
+        assert_eq!(logs[1]["order_id"], 3);
+        assert_eq!(logs[1]["remaining_funds"], "0");
+    }
+
+    #[test]
+    fn test_freeze() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        order_book.apply_order(&limit(1, SIDE_BUY, "99", "1"));
+        order_book.apply_order(&limit(2, SIDE_BUY, "99", "2"));
+        order_book.apply_order(&limit(3, SIDE_BUY, "98", "1"));
+        order_book.apply_order(&limit(4, SIDE_SELL, "101", "1"));
+        assert_eq!(
+            order_book.l2_depth(1),
+            (vec![(dec("99"), dec("3"))], vec![(dec("101"), dec("1"))])
+        );
+
+        let frozen = order_book.freeze();
+        order_book.apply_order(&limit(5, SIDE_SELL, "99", "3"));
+        order_book.apply_order(&limit(6, SIDE_SELL, "100", "1"));
+
+        assert_eq!(frozen.best_bid(), Some(dec("99")));
+        assert_eq!(frozen.best_ask(), Some(dec("101")));
+        assert_eq!(
+            frozen.l2_depth(10),
+            (
+                vec![(dec("99"), dec("3")), (dec("98"), dec("1"))],
+                vec![(dec("101"), dec("1"))]
+            )
+        );
+        assert_eq!(order_book.best_bid(), Some(dec("98")));
+        assert_eq!(order_book.best_ask(), Some(dec("100")));
+    }
+
+    #[test]
+    fn test_market_order_never_rests() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        for order in [market_buy(1, "100"), market_sell(2, "1")].iter() {
+            let mut book_order = BookOrder::new_book_order(order);
+            book_order.size = dec("1");
+            assert_eq!(
+                order_book.rest_order(&book_order),
+                Err(CustomError::MarketOrderResting(order.id))
+            );
+        }
+        assert!(order_book.bid_depths.queue.is_empty());
+        assert!(order_book.ask_depths.queue.is_empty());
+        assert!(!order_book.last_order_rested());
+
+        assert!(order_book
+            .rest_order(&BookOrder::new_book_order(&limit(3, SIDE_BUY, "99", "1")))
+            .is_ok());
+        assert_eq!(order_book.best_bid(), Some(dec("99")));
+    }
+
+    #[test]
+    fn test_notional_limit_order() {
+        let notional = |id: u64, side: Side, price: &str, funds: &str| {
+            new_order(id, id, ORDER_TYPE_LIMIT, side, price, "0", funds)
+        };
+        assert!(OrderBook::validate_order(&notional(1, SIDE_BUY, "3", "10")).is_ok());
+        assert!(OrderBook::validate_order(&notional(1, SIDE_BUY, "0", "10")).is_err());
+
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        order_book.apply_order(&limit(1, SIDE_SELL, "3", "1"));
+
+        // 10 buys 3.3333 at 3, one unit is filled and the rest rests with the computed size
+        let logs = to_json(&order_book.apply_order(&notional(2, SIDE_BUY, "3", "10")));
+        assert_eq!(logs[0]["size"], "1");
+        assert_eq!(logs[2]["base"]["type"], "open");
+        assert_eq!(logs[2]["remaining_size"], "2.3333");
+        assert_eq!(order_book.bid_depths.orders[&2].size, dec("2.3333"));
+        assert!(order_book.bid_depths.orders[&2].funds.is_zero());
+
+        let mut fok = notional(3, SIDE_SELL, "3", "6.9999");
+        fok.time_in_force = TimeInForceType::FillOrKill;
+        assert!(order_book.is_order_will_full_match(&fok));
+        let mut fok = notional(4, SIDE_SELL, "3", "7.0002");
+        fok.time_in_force = TimeInForceType::FillOrKill;
+        assert!(!order_book.is_order_will_full_match(&fok));
+
+        // too little funds for a single unit
+        let logs = to_json(&order_book.apply_order(&notional(5, SIDE_SELL, "3", "0.0001")));
+        assert_eq!(logs.len(), 1);
+        assert_eq!(logs[0]["reason"], "cancelled");
+        assert_eq!(logs[0]["remaining_funds"], "0.0001");
+        assert_eq!(order_book.order_ids(SIDE_BUY), vec![2]);
+    }
+
+    #[test]
+    fn test_session_done_logs() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        order_book.apply_order(&limit(1, SIDE_SELL, "100", "1"));
+        order_book.apply_order(&limit(2, SIDE_SELL, "101", "1"));
+        assert!(order_book.session_done_logs().is_empty());
+
+        order_book.apply_order(&limit(3, SIDE_BUY, "100", "1"));
+        order_book
+            .cancel_order(&limit(2, SIDE_SELL, "101", "1"))
+            .unwrap();
+        order_book.apply_order(&market_sell(4, "1"));
+        let done: Vec<(u64, String)> = order_book
+            .session_done_logs()
+            .iter()
+            .map(|log| {
+                let reason = serde_json::to_value(log).unwrap()["reason"].clone();
+                (log.order_id, reason.as_str().unwrap().to_string())
+            })
+            .collect();
+        assert_eq!(
+            done,
+            vec![
+                (1, "filled".to_string()),
+                (3, "filled".to_string()),
+                (2, "cancelled".to_string()),
+                (4, "cancelled".to_string()),
+            ]
+        );
+
+        order_book.roll_session();
+        assert!(order_book.session_done_logs().is_empty());
+        order_book.apply_order(&limit(5, SIDE_BUY, "100", "0"));
+        assert_eq!(order_book.session_done_logs()[0].order_id, 5);
+    }
+
+    #[test]
+    fn test_cancel_outcome() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        order_book.apply_order(&limit(1, SIDE_SELL, "100", "1"));
+        order_book.apply_order(&limit(2, SIDE_BUY, "99", "1"));
+        order_book.apply_order(&limit(3, SIDE_BUY, "100", "1"));
+
+        match order_book.cancel_order(&limit(2, SIDE_BUY, "99", "1")) {
+            Ok(CancelOutcome::Cancelled(log)) => {
+                assert_eq!(log.order_id, 2);
+                assert!(log.remaining_size.is_zero());
+            }
+            _ => panic!("order 2 is not cancelled"),
+        }
+        // cancelled before, and filled
+        for id in [2u64, 1].iter() {
+            let side = if *id == 1 { SIDE_SELL } else { SIDE_BUY };
+            assert!(matches!(
+                order_book.cancel_order(&limit(*id, side, "100", "1")),
+                Ok(CancelOutcome::AlreadyDone)
+            ));
+        }
+        // never seen, also when asked again
+        for _ in 0..2 {
+            assert!(matches!(
+                order_book.cancel_order(&limit(9, SIDE_BUY, "100", "1")),
+                Ok(CancelOutcome::NotFound)
+            ));
+        }
+        // the id is still free for the order
+        let logs = to_json(&order_book.apply_order(&limit(9, SIDE_BUY, "98", "1")));
+        assert_eq!(logs[0]["base"]["type"], "open");
+
+        order_book.apply_order(&limit(4, SIDE_SELL, "101", "1"));
+        assert_eq!(
+            order_book
+                .cancel_order(&limit(4, SIDE_BUY, "101", "1"))
+                .err(),
+            Some(CustomError::WrongSide(4))
+        );
+        assert!(order_book
+            .cancel_order_logs(&limit(4, SIDE_BUY, "101", "1"))
+            .is_empty());
+        assert_eq!(order_book.order_ids(SIDE_SELL), vec![4]);
+    }
+
+    #[test]
+    fn test_book_shape() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        for (id, side, price, size) in [
+            (1, SIDE_BUY, "99", "1"),
+            (2, SIDE_BUY, "99", "0.5"),
+            (3, SIDE_BUY, "98", "2"),
+            (4, SIDE_BUY, "97", "1"),
+            (5, SIDE_SELL, "101", "3"),
+            (6, SIDE_SELL, "102", "1"),
+        ]
+        .iter()
+        {
+            order_book.apply_order(&limit(*id, side.clone(), price, size));
+        }
+
+        let shape = order_book.book_shape(2);
+        assert_eq!(shape.bid_prices, vec![dec("99"), dec("98")]);
+        assert_eq!(shape.bid_sizes, vec![dec("1.5"), dec("2")]);
+        assert_eq!(shape.cumulative_bid, vec![dec("1.5"), dec("3.5")]);
+        assert_eq!(shape.ask_prices, vec![dec("101"), dec("102")]);
+        assert_eq!(shape.cumulative_ask, vec![dec("3"), dec("4")]);
+
+        let shape = order_book.book_shape(10);
+        for cumulative in [&shape.cumulative_bid, &shape.cumulative_ask].iter() {
+            assert!(cumulative.windows(2).all(|w| w[0] < w[1]));
+        }
+        assert_eq!(shape.cumulative_bid.last(), Some(&dec("4.5")));
+        assert!(OrderBook::new_order_book(&new_product())
+            .book_shape(10)
+            .cumulative_ask
+            .is_empty());
+    }
+
+    #[test]
+    fn test_normalize_scale() {
+        let mut product = new_product();
+        product.normalize_scale = true;
+        let mut order_book = OrderBook::new_order_book(&product);
+
+        order_book.apply_order(&limit(1, SIDE_BUY, "100", "1"));
+        order_book.apply_order(&limit(2, SIDE_BUY, "100.000", "1.00000"));
+        order_book.apply_order(&limit(3, SIDE_BUY, "99.5", "0.12345"));
+        let repr = |id: u64| {
+            let o = &order_book.bid_depths.orders[&id];
+            (o.price.to_string(), o.size.to_string(), o.funds.to_string())
+        };
+        assert_eq!(repr(1), repr(2));
+        assert_eq!(
+            repr(1),
+            (
+                "100.00".to_string(),
+                "1.0000".to_string(),
+                "0.00".to_string()
+            )
+        );
+        // the value is never changed by normalization
+        assert_eq!(order_book.bid_depths.orders[&3].size.to_string(), "0.12345");
+
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        order_book.apply_order(&limit(1, SIDE_BUY, "100.000", "1"));
+        assert_eq!(
+            order_book.bid_depths.orders[&1].price.to_string(),
+            "100.000"
+        );
+    }
+
+    #[test]
+    fn test_compact_window() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        order_book.order_id_window = Window::new(0, 10);
+        order_book.apply_order(&limit(1, SIDE_SELL, "100", "1"));
+        order_book.apply_order(&limit(2, SIDE_BUY, "99", "1"));
+        order_book.apply_order(&limit(3, SIDE_BUY, "100", "1"));
+        order_book.apply_order(&limit(8, SIDE_BUY, "98", "1"));
+
+        let mut restored = OrderBook::new_order_book(&new_product());
+        restored.restore(&order_book.snapshot());
+        restored.compact_window();
+
+        // 1 and 3 are done and aged out, 2 still rests and 8 is recent
+        assert!(!restored.order_id_window.contains(1));
+        assert!(!restored.order_id_window.contains(3));
+        assert!(restored.order_id_window.contains(2));
+        assert!(restored.order_id_window.contains(8));
+        assert!(restored
+            .apply_order(&limit(2, SIDE_BUY, "99", "1"))
+            .is_empty());
+        assert!(restored
+            .apply_order(&limit(8, SIDE_BUY, "98", "1"))
+            .is_empty());
+        let logs = to_json(&restored.apply_order(&limit(3, SIDE_BUY, "97", "1")));
+        assert_eq!(logs[0]["base"]["type"], "open");
+    }
+
+    #[test]
+    fn test_match_log_taker_side() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        order_book.apply_order(&limit(1, SIDE_SELL, "100", "1"));
+        order_book.apply_order(&limit(2, SIDE_BUY, "99", "1"));
+
+        let logs = to_json(&order_book.apply_order(&limit(3, SIDE_BUY, "100", "1")));
+        assert_eq!(logs[0]["side"], "sell");
+        assert_eq!(logs[0]["taker_side"], "buy");
+        let logs = to_json(&order_book.apply_order(&market_sell(4, "1")));
+        assert_eq!(logs[0]["side"], "buy");
+        assert_eq!(logs[0]["taker_side"], "sell");
+    }
+
+    #[test]
+    fn test_halt_and_drain() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        order_book.apply_order(&limit(1, SIDE_BUY, "99", "1"));
+        order_book.apply_order(&user_limit(2, 7, SIDE_BUY, "98", "1"));
+        order_book.apply_order(&user_limit(3, 8, SIDE_SELL, "101", "1"));
+
+        order_book.halted = true;
+        let logs = to_json(&order_book.process_order(&limit(4, SIDE_SELL, "99", "1")));
+        assert_eq!(logs[0]["reason"], "cancelled");
+        assert_eq!(
+            order_book
+                .try_apply_order(&limit(5, SIDE_SELL, "99", "1"))
+                .err(),
+            Some(CustomError::Halted)
+        );
+        assert_eq!(order_book.order_ids(SIDE_BUY), vec![1, 2]);
+
+        let log_seq = order_book.log_seq;
+        let logs = order_book.drain();
+        let ids: Vec<u64> = logs.iter().map(|log| log.order_id).collect();
+        assert_eq!(ids, vec![1, 2, 3]);
+        for (i, log) in logs.iter().enumerate() {
+            assert_eq!(log.base.sequence, log_seq + i as u64 + 1);
+            assert!(log.remaining_size.is_zero());
+            assert_eq!(serde_json::to_value(log).unwrap()["reason"], "cancelled");
+        }
+        assert!(order_book.bid_depths.orders.is_empty());
+        assert!(order_book.ask_depths.queue.is_empty());
+        assert!(order_book.drain().is_empty());
+    }
+
+    #[test]
+    fn test_new_order_book_with_seqs() {
+        let mut order_book = OrderBook::new_order_book_with_seqs(&new_product(), 40, 100);
+        let logs = order_book.apply_order(&limit(1, SIDE_SELL, "100", "1"));
+        assert_eq!(logs[0].get_seq(), 101);
+
+        let logs = to_json(&order_book.apply_order(&limit(2, SIDE_BUY, "100", "1")));
+        assert_eq!(logs[0]["base"]["sequence"], 102);
+        assert_eq!(logs[0]["trade_seq"], 41);
+    }
+
+    #[test]
+    fn test_min_price_improvement() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        order_book.min_price_improvement = dec("0.05");
+        order_book.apply_order(&limit(1, SIDE_SELL, "99.99", "1"));
+        order_book.apply_order(&limit(2, SIDE_SELL, "99.95", "1"));
+
+        // 99.95 improves by 0.05 on 100, while 99.99 barely crosses and is skipped
+        let logs = to_json(&order_book.apply_order(&limit(3, SIDE_BUY, "100", "2")));
+        assert_eq!(logs.len(), 3);
+        assert_eq!(logs[0]["maker_order_id"], 2);
+        assert_eq!(logs[2]["base"]["type"], "open");
+        assert_eq!(order_book.order_ids(SIDE_SELL), vec![1]);
+
+        let mut fok = limit(4, SIDE_SELL, "99.99", "1");
+        fok.time_in_force = TimeInForceType::FillOrKill;
+        assert!(!order_book.is_order_will_full_match(&fok));
+        assert!(order_book.is_order_will_not_match(&fok));
+        fok.price = dec("99.95");
+        assert!(order_book.is_order_will_full_match(&fok));
+
+        // market orders have no limit to improve on
+        let logs = to_json(&order_book.apply_order(&market_buy(5, "99.99")));
+        assert_eq!(logs[0]["maker_order_id"], 1);
+    }
+
+    #[test]
+    fn test_client_order_id() {
+        let with_client_oid = |mut order: Order, client_oid: &str| {
+            order.client_oid = client_oid.to_string();
+            order
+        };
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        let logs = to_json(
+            &order_book.apply_order(&with_client_oid(limit(1, SIDE_SELL, "100", "1"), "maker-1")),
+        );
+        assert_eq!(logs[0]["client_order_id"], "maker-1");
+
+        let logs = to_json(
+            &order_book.apply_order(&with_client_oid(limit(2, SIDE_BUY, "100", "1"), "taker-2")),
+        );
+        assert_eq!(logs[0]["taker_client_order_id"], "taker-2");
+        assert_eq!(logs[0]["maker_client_order_id"], "maker-1");
+        assert_eq!(logs[1]["order_id"], 1);
+        assert_eq!(logs[1]["client_order_id"], "maker-1");
+        assert_eq!(logs[2]["order_id"], 2);
+        assert_eq!(logs[2]["client_order_id"], "taker-2");
+
+        // orders without one log nothing extra
+        let logs = to_json(&order_book.apply_order(&limit(3, SIDE_BUY, "99", "1")));
+        assert!(logs[0].get("client_order_id").is_none());
+    }
+
+    #[test]
+    fn test_fill_probability() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        order_book.apply_order(&limit(1, SIDE_BUY, "100", "2"));
+        order_book.apply_order(&limit(2, SIDE_BUY, "99", "3"));
+        order_book.apply_order(&limit(3, SIDE_BUY, "100", "1"));
+        order_book.apply_order(&limit(4, SIDE_BUY, "98", "4"));
+        order_book.apply_order(&limit(5, SIDE_SELL, "101", "1"));
+
+        assert_eq!(order_book.queue_position(1), Some((0, dec("0"))));
+        assert_eq!(order_book.queue_position(2), Some((2, dec("3"))));
+        assert_eq!(order_book.queue_position(4), Some((3, dec("6"))));
+        assert_eq!(order_book.queue_position(5), Some((0, dec("0"))));
+        assert_eq!(order_book.queue_position(6), None);
+
+        let volume = dec("4");
+        let front = order_book.fill_probability(1, volume).unwrap();
+        let deep = order_book.fill_probability(4, volume).unwrap();
+        assert_eq!(front, dec("1"));
+        assert_eq!(deep, dec("0.4"));
+        assert!(front > order_book.fill_probability(2, volume).unwrap());
+        assert_eq!(order_book.fill_probability(4, dec("0")), Some(dec("0")));
+        assert_eq!(order_book.fill_probability(6, volume), None);
+    }
+
+    #[test]
+    fn test_iter_all() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        assert_eq!(order_book.iter_all().count(), 0);
+        for (id, side, price) in [
+            (1, SIDE_BUY, "98"),
+            (2, SIDE_SELL, "102"),
+            (3, SIDE_BUY, "99"),
+            (4, SIDE_SELL, "101"),
+            (5, SIDE_BUY, "99"),
+            (6, SIDE_SELL, "101"),
+        ]
+        .iter()
+        {
+            order_book.apply_order(&limit(*id, side.clone(), price, "1"));
+        }
+
+        let all: Vec<(String, u64)> = order_book
+            .iter_all()
+            .map(|(side, o)| (format!("{:?}", side), o.order_id))
+            .collect();
+        let sides: Vec<&str> = all.iter().map(|(side, _)| side.as_str()).collect();
+        assert_eq!(
+            sides,
+            vec!["SideSell", "SideSell", "SideSell", "SideBuy", "SideBuy", "SideBuy"]
+        );
+        let ids: Vec<u64> = all.iter().map(|(_, id)| *id).collect();
+        let mut expected = order_book.order_ids(SIDE_SELL);
+        expected.extend(order_book.order_ids(SIDE_BUY));
+        assert_eq!(ids, expected);
+        assert_eq!(ids, vec![4, 6, 2, 3, 5, 1]);
+    }
+
+    #[test]
+    fn test_rest_price_policy() {
+        for (policy, rest_price) in [
+            (RestPricePolicy::LimitPrice, "102"),
+            (RestPricePolicy::LastMatchPrice, "101"),
+        ]
+        .iter()
+        {
+            let mut order_book = OrderBook::new_order_book(&new_product());
+            order_book.rest_price_policy = policy.clone();
+            order_book.apply_order(&limit(1, SIDE_SELL, "100", "1"));
+            order_book.apply_order(&limit(2, SIDE_SELL, "101", "1"));
+            order_book.apply_order(&limit(3, SIDE_SELL, "103", "1"));
+
+            let logs = to_json(&order_book.apply_order(&limit(4, SIDE_BUY, "102", "3")));
+            let open = logs.last().unwrap();
+            assert_eq!(open["base"]["type"], "open");
+            assert_eq!(open["price"], *rest_price);
+            assert_eq!(order_book.best_bid(), Some(dec(rest_price)));
+            assert_eq!(order_book.best_ask(), Some(dec("103")));
+
+            // nothing crossed, the limit price is kept whatever the policy
+            order_book.apply_order(&limit(5, SIDE_BUY, "99", "1"));
+            assert_eq!(order_book.bid_depths.orders[&5].price, dec("99"));
+        }
+    }
+
+    #[test]
+    fn test_last_match_price_never_crosses() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        order_book.rest_price_policy = RestPricePolicy::LastMatchPrice;
+        let mut aon = limit(1, SIDE_SELL, "100", "5");
+        aon.all_or_none = true;
+        order_book.apply_order(&aon);
+        order_book.apply_order(&limit(2, SIDE_SELL, "100.5", "1"));
+
+        // passes over the aon it can't complete, fills at 100.5 and rests the other 1
+        let logs = to_json(&order_book.apply_order(&limit(3, SIDE_BUY, "102", "2")));
+        assert_eq!(logs[0]["maker_order_id"], 2);
+        assert_eq!(logs.last().unwrap()["base"]["type"], "open");
+        assert_eq!(order_book.best_ask(), Some(dec("100")));
+        assert!(order_book.best_bid().unwrap() < order_book.best_ask().unwrap());
+        assert_eq!(order_book.bid_depths.orders[&3].price, dec("99.99"));
+
+        // the same on the sell side
+        let mut aon = limit(4, SIDE_BUY, "90", "5");
+        aon.all_or_none = true;
+        order_book.apply_order(&aon);
+        order_book.apply_order(&limit(5, SIDE_BUY, "89.5", "1"));
+        order_book
+            .cancel_order(&limit(3, SIDE_BUY, "99.99", "1"))
+            .unwrap();
+        order_book.apply_order(&limit(6, SIDE_SELL, "88", "2"));
+        assert_eq!(order_book.best_bid(), Some(dec("90")));
+        assert_eq!(order_book.ask_depths.orders[&6].price, dec("90.01"));
+    }
+
+    #[test]
+    fn test_all_or_none() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        order_book.apply_order(&limit(1, SIDE_SELL, "100", "1"));
+
+        // can't be filled completely on arrival, rests whole
+        let mut aon = limit(2, SIDE_BUY, "101", "3");
+        aon.all_or_none = true;
+        let logs = to_json(&order_book.apply_order(&aon));
+        assert_eq!(logs.len(), 1);
+        assert_eq!(logs[0]["base"]["type"], "open");
+        assert_eq!(logs[0]["remaining_size"], "3");
+        assert_eq!(order_book.best_bid(), Some(dec("101")));
+        assert_eq!(order_book.best_ask(), Some(dec("100")));
+
+        // too small to complete the resting aon, it's passed over
+        let logs = to_json(&order_book.apply_order(&limit(3, SIDE_SELL, "101", "2")));
+        assert_eq!(logs.len(), 1);
+        assert_eq!(logs[0]["base"]["type"], "open");
+        assert!(!order_book.is_order_will_full_match(&limit(4, SIDE_SELL, "101", "2")));
+
+        // completes the aon
+        let logs = to_json(&order_book.apply_order(&limit(5, SIDE_SELL, "101", "4")));
+        assert_eq!(logs[0]["base"]["type"], "match");
+        assert_eq!(logs[0]["maker_order_id"], 2);
+        assert_eq!(logs[0]["size"], "3");
+        assert_eq!(logs[1]["base"]["type"], "done");
+        assert_eq!(logs[1]["order_id"], 2);
+        assert_eq!(logs[2]["base"]["type"], "open");
+        assert_eq!(logs[2]["remaining_size"], "1");
+        assert!(order_book.bid_depths.orders.is_empty());
+
+        // fillable on arrival, matched as a normal limit order
+        let mut aon = limit(6, SIDE_BUY, "101", "2");
+        aon.all_or_none = true;
+        let logs = to_json(&order_book.apply_order(&aon));
+        assert_eq!(logs.len(), 4);
+        assert_eq!(logs[3]["base"]["type"], "done");
+        assert_eq!(logs[3]["order_id"], 6);
+
+        let mut market = market_sell(7, "1");
+        market.all_or_none = true;
+        assert!(OrderBook::validate_order(&market).is_err());
+    }
+
+    #[test]
+    fn test_last_trade() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        assert!(order_book.last_trade().is_none());
+
+        order_book.apply_order(&limit(1, SIDE_SELL, "100", "1"));
+        order_book.apply_order(&limit(2, SIDE_SELL, "101", "2"));
+        assert!(order_book.last_trade().is_none());
+
+        let logs = order_book.apply_order_fills_only(&limit(3, SIDE_BUY, "101", "1.5"));
+        let (price, size, time) = order_book.last_trade().unwrap();
+        assert_eq!((price, size), (dec("101"), dec("0.5")));
+        assert_eq!(time, logs.last().unwrap().time());
+
+        // no match, the last trade is kept
+        order_book.apply_order(&limit(4, SIDE_BUY, "99", "1"));
+        assert_eq!(order_book.last_trade().unwrap().0, dec("101"));
+
+        order_book.apply_order(&market_sell(5, "0.25"));
+        let (price, size, _) = order_book.last_trade().unwrap();
+        assert_eq!((price, size), (dec("99"), dec("0.25")));
+
+        let snapshot = order_book.snapshot();
+        let mut restored = OrderBook::new_order_book(&new_product());
+        restored.restore(&snapshot);
+        assert!(restored.last_trade().is_none());
+    }
+
+    #[test]
+    fn test_user_resting_notional() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        assert_eq!(
+            order_book.user_resting_notional(7),
+            (Decimal::ZERO, Decimal::ZERO)
+        );
+
+        order_book.apply_order(&user_limit(1, 7, SIDE_BUY, "99", "2"));
+        order_book.apply_order(&user_limit(2, 7, SIDE_BUY, "98.5", "1"));
+        order_book.apply_order(&user_limit(3, 7, SIDE_SELL, "101", "0.5"));
+        order_book.apply_order(&user_limit(4, 8, SIDE_BUY, "99", "10"));
+        order_book.apply_order(&user_limit(5, 8, SIDE_SELL, "102", "3"));
+        assert_eq!(
+            order_book.user_resting_notional(7),
+            (dec("296.5"), dec("50.5"))
+        );
+
+        // partially filled by another user
+        order_book.apply_order(&user_limit(6, 9, SIDE_SELL, "99", "1"));
+        assert_eq!(
+            order_book.user_resting_notional(7),
+            (dec("197.5"), dec("50.5"))
+        );
+        assert_eq!(
+            order_book.user_resting_notional(8),
+            (dec("990"), dec("306"))
+        );
+    }
+
+    #[test]
+    fn test_restore_partial() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        order_book.apply_order(&limit(1, SIDE_BUY, "99", "1"));
+        order_book.apply_order(&limit(2, SIDE_BUY, "98", "2"));
+        order_book.apply_order(&limit(3, SIDE_SELL, "101", "1"));
+        let mut snapshot = order_book.snapshot();
+
+        let mut zero_price = snapshot.orders[0].clone();
+        zero_price.order_id = 4;
+        zero_price.price = Decimal::ZERO;
+        let duplicate = snapshot.orders[1].clone();
+        snapshot.orders.insert(1, zero_price);
+        snapshot.orders.push(duplicate.clone());
+
+        let (restored, errors) = OrderBook::restore_partial(&new_product(), &snapshot);
+        assert_eq!(errors.len(), 2);
+        assert!(matches!(
+            errors[0],
+            CustomError::InvalidOrder { order_id: 4, .. }
+        ));
+        assert_eq!(errors[1], CustomError::OrderIdDuplicate(duplicate.order_id));
+
+        assert_eq!(restored.bid_depths.orders.len(), 2);
+        assert_eq!(restored.ask_depths.orders.len(), 1);
+        assert_eq!(restored.best_bid(), Some(dec("99")));
+        assert_eq!(restored.best_ask(), Some(dec("101")));
+        assert_eq!(restored.bid_depths.orders[&2].size, dec("2"));
+        assert_eq!(
+            (restored.log_seq, restored.trade_seq),
+            (order_book.log_seq, order_book.trade_seq)
+        );
+    }
+
+    #[test]
+    fn test_no_zero_size_fill() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        order_book.apply_order(&limit(1, SIDE_SELL, "100", "0.01"));
+        order_book.apply_order(&limit(2, SIDE_SELL, "100000", "1"));
+
+        // 0.5 left after the first level buys 0.000005 at the second, truncated to zero
+        let logs = to_json(&order_book.apply_order(&market_buy(3, "1.5")));
+        let matches: Vec<_> = logs
+            .iter()
+            .filter(|log| log["base"]["type"] == "match")
+            .collect();
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0]["size"], "0.01");
+        assert_eq!(order_book.trade_seq, 1);
+        assert_eq!(order_book.ask_depths.orders[&2].size, dec("1"));
+
+        let logs = to_json(&order_book.apply_order(&market_buy(4, "5")));
+        assert_eq!(logs.len(), 1);
+        assert_eq!(logs[0]["base"]["type"], "done");
+        assert_eq!(logs[0]["reason"], "cancelled");
+        assert_eq!(order_book.trade_seq, 1);
+    }
+
+    #[test]
+    fn test_depth_response() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        order_book.apply_order(&limit(1, SIDE_BUY, "99", "1"));
+        order_book.apply_order(&limit(2, SIDE_BUY, "99", "0.5"));
+        order_book.apply_order(&limit(3, SIDE_BUY, "98", "2"));
+        order_book.apply_order(&limit(4, SIDE_SELL, "101", "3"));
+
+        let depth = order_book.depth_response(1);
+        assert_eq!(depth.sequence, order_book.log_seq);
+        assert_eq!(depth.bids, vec![[dec("99"), dec("1.5")]]);
+        assert_eq!(depth.asks, vec![[dec("101"), dec("3")]]);
+
+        let json = serde_json::to_value(&depth).unwrap();
+        assert_eq!(json["bids"][0][0], "99");
+        assert_eq!(json["bids"][0][1], "1.5");
+        let decoded: DepthResponse = serde_json::from_value(json.clone()).unwrap();
+        assert_eq!(serde_json::to_value(&decoded).unwrap(), json);
+    }
+
+    #[test]
+    fn test_enforce_level_cap() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        order_book.apply_order(&limit(1, SIDE_SELL, "101", "1"));
+        order_book.apply_order(&limit(2, SIDE_SELL, "101", "2"));
+        order_book.apply_order(&limit(3, SIDE_SELL, "101", "3"));
+        order_book.apply_order(&limit(4, SIDE_SELL, "102", "5"));
+
+        // within the cap, nothing to do
+        assert!(order_book
+            .enforce_level_cap(SIDE_SELL, dec("101"), dec("6"))
+            .is_empty());
+
+        // the oldest order is enough
+        let logs = order_book.enforce_level_cap(SIDE_SELL, dec("101"), dec("5"));
+        assert_eq!(logs.len(), 1);
+        assert_eq!(logs[0].order_id, 1);
+        assert_eq!(order_book.order_ids(SIDE_SELL), vec![2, 3, 4]);
+
+        // the newest order at the level is trimmed as well to get under the cap
+        let logs = order_book.enforce_level_cap(SIDE_SELL, dec("101"), dec("1"));
+        assert_eq!(
+            logs.iter().map(|log| log.order_id).collect::<Vec<u64>>(),
+            vec![2, 3]
+        );
+        assert_eq!(order_book.order_ids(SIDE_SELL), vec![4]);
+        assert_eq!(
+            serde_json::to_value(&logs[0]).unwrap()["reason"],
+            "cancelled"
+        );
+    }
+
+    #[test]
+    fn test_update_product() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        order_book.apply_order(&limit(1, SIDE_BUY, "99.5", "1"));
+        order_book.apply_order(&limit(2, SIDE_BUY, "99.25", "1"));
+        order_book.apply_order(&limit(3, SIDE_SELL, "101", "0.3"));
+        order_book.apply_order(&limit(4, SIDE_SELL, "102", "1.5"));
+
+        let other = Product {
+            id: "ETH-USD".to_string(),
+            ..new_product()
+        };
+        assert!(order_book.update_product(other).is_err());
+
+        let tighter = Product {
+            quote_scale: 1,
+            lot_size: dec("0.5"),
+            ..new_product()
+        };
+        let logs = order_book.update_product(tighter).unwrap();
+        assert_eq!(
+            logs.iter().map(|log| log.order_id).collect::<Vec<u64>>(),
+            vec![2, 3]
+        );
+        assert_eq!(order_book.order_ids(SIDE_BUY), vec![1]);
+        assert_eq!(order_book.order_ids(SIDE_SELL), vec![4]);
+        assert_eq!(order_book.product.quote_scale, 1);
+        assert_eq!(order_book.product.lot_size, dec("0.5"));
+
+        // new orders are held to the new lot
+        let logs = to_json(&order_book.apply_order(&limit(5, SIDE_BUY, "98", "0.7")));
+        assert_eq!(logs[0]["remaining_size"], "0.5");
+    }
+
+    #[test]
+    fn test_heartbeat() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        let log = order_book.heartbeat();
+        assert_eq!(log.base.sequence, 1);
+        assert_eq!((log.best_bid, log.best_ask), (None, None));
+
+        order_book.apply_order(&limit(1, SIDE_BUY, "99", "1"));
+        order_book.apply_order(&limit(2, SIDE_SELL, "101", "1"));
+        let log_seq = order_book.log_seq;
+        let log = order_book.heartbeat();
+        assert_eq!(log.base.sequence, log_seq + 1);
+        assert_eq!(order_book.log_seq, log_seq + 1);
+        assert_eq!(log.best_bid, Some(dec("99")));
+        assert_eq!(log.best_ask, Some(dec("101")));
+        assert_eq!(
+            to_json(&vec![Box::new(log) as Box<dyn LogTrait>])[0]["base"]["type"],
+            "heartbeat"
+        );
+
+        // the next log continues after the heartbeat
+        let logs = to_json(&order_book.apply_order(&limit(3, SIDE_BUY, "98", "1")));
+        assert_eq!(logs[0]["base"]["sequence"], log_seq + 2);
+    }
+
+    #[test]
+    fn test_min_size_dust() {
+        let mut product = new_product();
+        product.min_size = dec("0.01");
+        let mut order_book = OrderBook::new_order_book(&product);
+        order_book.apply_order(&limit(1, SIDE_SELL, "100", "1"));
+
+        // fills all but 0.005, which is cancelled
+        let logs = to_json(&order_book.apply_order(&limit(2, SIDE_BUY, "100", "1.005")));
+        assert_eq!(logs.len(), 3);
+        assert_eq!(logs[2]["base"]["type"], "done");
+        assert_eq!(logs[2]["order_id"], 2);
+        assert_eq!(logs[2]["reason"], "cancelled");
+        assert_eq!(logs[2]["remaining_size"], "0.005");
+        assert!(order_book.bid_depths.orders.is_empty());
+
+        // a rest of min_size is kept
+        order_book.apply_order(&limit(3, SIDE_SELL, "100", "1"));
+        let logs = to_json(&order_book.apply_order(&limit(4, SIDE_BUY, "100", "1.01")));
+        assert_eq!(logs[2]["base"]["type"], "open");
+        assert_eq!(order_book.bid_depths.orders[&4].size, dec("0.01"));
+    }
+
+    #[test]
+    fn test_equal_ignoring_seqs() {
+        let orders = [
+            limit(1, SIDE_BUY, "99", "1"),
+            limit(2, SIDE_BUY, "99", "2"),
+            limit(3, SIDE_SELL, "101", "1"),
+            limit(4, SIDE_BUY, "101", "0.5"),
+        ];
+        let mut original = OrderBook::new_order_book(&new_product());
+        let mut replayed = OrderBook::new_order_book_with_seqs(&new_product(), 100, 1000);
+        for order in orders.iter() {
+            original.apply_order(order);
+            replayed.apply_order(order);
+        }
+        assert_ne!(original.log_seq, replayed.log_seq);
+        assert!(original.equal_ignoring_seqs(&replayed));
+        assert!(replayed.equal_ignoring_seqs(&original));
+
+        // same depth, but different orders make it up
+        let mut other = OrderBook::new_order_book(&new_product());
+        other.apply_order(&limit(1, SIDE_BUY, "99", "3"));
+        other.apply_order(&limit(3, SIDE_SELL, "101", "0.5"));
+        assert_eq!(other.l2_depth(10), original.l2_depth(10));
+        assert!(!original.equal_ignoring_seqs(&other));
+
+        replayed.apply_order(&limit(5, SIDE_SELL, "102", "1"));
+        assert!(!original.equal_ignoring_seqs(&replayed));
+    }
+
+    #[test]
+    fn test_clamp_to_protection() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        order_book.clamp_to_protection = true;
+        order_book.protection_bps = dec("100");
+        order_book.apply_order(&limit(1, SIDE_SELL, "100", "1"));
+        order_book.apply_order(&limit(2, SIDE_SELL, "101", "1"));
+        order_book.apply_order(&limit(3, SIDE_SELL, "105", "1"));
+
+        // the limit allows 105, the sweep stops at 101 = 100 + 1%
+        let logs = to_json(&order_book.apply_order(&limit(4, SIDE_BUY, "110", "3")));
+        assert_eq!(logs.len(), 5);
+        assert_eq!(logs[2]["base"]["type"], "match");
+        assert_eq!(logs[2]["price"], "101");
+        assert_eq!(logs[4]["base"]["type"], "done");
+        assert_eq!(logs[4]["reason"], "cancelled");
+        assert_eq!(logs[4]["remaining_size"], "1");
+        assert!(order_book.bid_depths.orders.is_empty());
+        assert_eq!(order_book.best_ask(), Some(dec("105")));
+
+        // stopped by its own limit, the rest rests as usual
+        order_book.apply_order(&limit(5, SIDE_BUY, "99", "1"));
+        let logs = to_json(&order_book.apply_order(&limit(6, SIDE_SELL, "98", "2")));
+        assert_eq!(logs.last().unwrap()["base"]["type"], "open");
+        assert_eq!(order_book.best_ask(), Some(dec("98")));
+
+        // without clamping the same sweep goes on to the limit
+        order_book.clamp_to_protection = false;
+        order_book.apply_order(&limit(7, SIDE_SELL, "99", "1"));
+        let logs = to_json(&order_book.apply_order(&limit(8, SIDE_BUY, "110", "3")));
+        assert_eq!(logs[4]["price"], "105");
+    }
+
+    #[test]
+    fn test_sweep_price() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        order_book.apply_order(&limit(1, SIDE_SELL, "100", "1"));
+        order_book.apply_order(&limit(2, SIDE_SELL, "101", "1"));
+        order_book.apply_order(&limit(3, SIDE_SELL, "101", "0.5"));
+        order_book.apply_order(&limit(4, SIDE_SELL, "103", "2"));
+        order_book.apply_order(&limit(5, SIDE_BUY, "99", "1"));
+
+        assert_eq!(
+            order_book.sweep_price(SIDE_BUY, dec("0.5")),
+            Some(dec("100"))
+        );
+        // exactly exhausts the first two levels
+        assert_eq!(
+            order_book.sweep_price(SIDE_BUY, dec("2.5")),
+            Some(dec("101"))
+        );
+        assert_eq!(
+            order_book.sweep_price(SIDE_BUY, dec("2.6")),
+            Some(dec("103"))
+        );
+        assert_eq!(
+            order_book.sweep_price(SIDE_BUY, dec("4.5")),
+            Some(dec("103"))
+        );
+        assert_eq!(order_book.sweep_price(SIDE_BUY, dec("4.6")), None);
+
+        assert_eq!(order_book.sweep_price(SIDE_SELL, dec("1")), Some(dec("99")));
+        assert_eq!(order_book.sweep_price(SIDE_SELL, dec("2")), None);
+        assert_eq!(order_book.sweep_price(SIDE_SELL, Decimal::ZERO), None);
+    }
+
+    #[test]
+    fn test_stp_group() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        order_book.self_trade_prevention = SelfTradePrevention::CancelMaker;
+        let mut sub_account = user_limit(1, 7, SIDE_SELL, "100", "1");
+        sub_account.stp_group_id = Some(70);
+        order_book.apply_order(&sub_account);
+        let mut other_group = user_limit(2, 8, SIDE_SELL, "100", "1");
+        other_group.stp_group_id = Some(80);
+        order_book.apply_order(&other_group);
+        order_book.apply_order(&user_limit(3, 9, SIDE_SELL, "100", "1"));
+
+        // user 10 is in the group of user 7, but not of user 8 or 9
+        let mut taker = user_limit(4, 10, SIDE_BUY, "100", "2");
+        taker.stp_group_id = Some(70);
+        let logs = to_json(&order_book.apply_order(&taker));
+        assert_eq!(logs[0]["base"]["type"], "done");
+        assert_eq!(logs[0]["order_id"], 1);
+        assert_eq!(logs[0]["reason"], "stp");
+        let makers: Vec<u64> = logs
+            .iter()
+            .filter(|log| log["base"]["type"] == "match")
+            .map(|log| log["maker_order_id"].as_u64().unwrap())
+            .collect();
+        assert_eq!(makers, vec![2, 3]);
+
+        // without a group on both sides only the user ids are compared
+        let a = BookOrder {
+            user_id: 1,
+            stp_group_id: Some(5),
+            ..Default::default()
+        };
+        let b = BookOrder {
+            user_id: 2,
+            ..Default::default()
+        };
+        assert!(!a.is_same_trader(&b));
+        assert!(a.is_same_trader(&BookOrder {
+            user_id: 1,
+            ..Default::default()
+        }));
+    }
+
+    #[test]
+    fn test_drain_deterministic() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        for (id, side, price) in [
+            (9, SIDE_BUY, "98"),
+            (3, SIDE_BUY, "99"),
+            (7, SIDE_SELL, "102"),
+            (5, SIDE_BUY, "99"),
+            (1, SIDE_SELL, "101"),
+            (8, SIDE_SELL, "101"),
+        ]
+        .iter()
+        {
+            order_book.apply_order(&limit(*id, side.clone(), price, "1"));
+        }
+        // the replica holds the same orders in hash maps built in another order
+        let mut replica = OrderBook::new_order_book(&new_product());
+        replica.restore(&order_book.snapshot());
+
+        let drained = |logs: Vec<DoneLog>| {
+            logs.iter()
+                .map(|log| (log.order_id, log.base.sequence))
+                .collect::<Vec<(u64, u64)>>()
+        };
+        let logs = drained(order_book.drain());
+        assert_eq!(logs, drained(replica.drain()));
+        assert_eq!(
+            logs.iter().map(|(id, _)| *id).collect::<Vec<u64>>(),
+            vec![3, 5, 9, 1, 8, 7]
+        );
+    }
+
+    #[test]
+    fn test_version() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        assert_eq!(order_book.version(), 0);
+
+        order_book.apply_order(&limit(1, SIDE_SELL, "100", "1"));
+        assert_eq!(order_book.version(), 1);
+        order_book.apply_order(&limit(2, SIDE_BUY, "100", "0.5"));
+        assert_eq!(order_book.version(), 2);
+
+        // rejected and duplicate orders are processed all the same
+        order_book.apply_order(&limit(2, SIDE_BUY, "99", "1"));
+        assert_eq!(order_book.version(), 3);
+        let mut post_only = limit(3, SIDE_BUY, "100", "1");
+        post_only.time_in_force = TimeInForceType::GoodTillCrossing;
+        order_book.process_order(&post_only);
+        assert_eq!(order_book.version(), 4);
+        assert!(order_book
+            .try_apply_order(&limit(4, SIDE_BUY, "99", "0"))
+            .is_err());
+        assert_eq!(order_book.version(), 5);
+
+        assert!(order_book
+            .cancel_order(&limit(1, SIDE_SELL, "100", "1"))
+            .is_ok());
+        assert_eq!(order_book.version(), 6);
+        assert!(order_book
+            .cancel_order(&limit(1, SIDE_SELL, "100", "1"))
+            .is_ok());
+        assert_eq!(order_book.version(), 7);
+
+        order_book.seed_levels(&[(dec("99"), dec("1"))], &[]);
+        order_book.drain();
+        assert_eq!(order_book.version(), 9);
+
+        // the rest of an immediate-or-cancel order is cancelled within the order
+        let mut ioc = limit(6, SIDE_BUY, "98", "1");
+        ioc.time_in_force = TimeInForceType::ImmediateOrCancel;
+        assert_eq!(order_book.process_order(&ioc).len(), 2);
+        assert_eq!(order_book.version(), 10);
+
+        // so are the cancels and the new orders of a quote
+        let user = 7;
+        order_book.apply_order(&user_limit(7, user, SIDE_BUY, "97", "1"));
+        assert_eq!(order_book.version(), 11);
+        let logs = order_book
+            .quote(
+                Some(user_limit(8, user, SIDE_BUY, "97.5", "1")),
+                Some(user_limit(9, user, SIDE_SELL, "101", "1")),
+                Some(7),
+                None,
+            )
+            .unwrap();
+        assert_eq!(logs.len(), 3);
+        assert_eq!(order_book.version(), 12);
+        // a refused quote is processed all the same
+        assert!(order_book.quote(None, None, Some(7), None).is_err());
+        assert_eq!(order_book.version(), 13);
+
+        // reading the book doesn't change it
+        order_book.preview_apply(&limit(5, SIDE_BUY, "99", "1"));
+        order_book.best_bid();
+        assert_eq!(order_book.version(), 13);
+    }
+
+    #[test]
+    fn test_mark_price() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        assert_eq!(order_book.mark_price(10), None);
+
+        order_book.apply_order(&limit(1, SIDE_SELL, "100", "1"));
+        order_book.apply_order(&limit(2, SIDE_SELL, "102", "3"));
+        order_book.apply_order(&limit(3, SIDE_SELL, "110", "1"));
+
+        // a single trade is its own mark
+        order_book.apply_order(&limit(4, SIDE_BUY, "100", "1"));
+        assert_eq!(order_book.mark_price(10), Some(dec("100")));
+
+        order_book.apply_order(&limit(5, SIDE_BUY, "102", "3"));
+        order_book.apply_order(&limit(6, SIDE_BUY, "110", "0.5"));
+        // (100 * 1 + 102 * 3 + 110 * 0.5) / 4.5
+        assert_eq!(
+            order_book.mark_price(10).unwrap().round_dp(4),
+            dec("102.4444")
+        );
+        // (102 * 3 + 110 * 0.5) / 3.5
+        assert_eq!(
+            order_book.mark_price(2).unwrap().round_dp(4),
+            dec("103.1429")
+        );
+        assert_eq!(order_book.mark_price(1), Some(dec("110")));
+        assert_eq!(order_book.mark_price(0), None);
+
+        let mut restored = OrderBook::new_order_book(&new_product());
+        restored.restore(&order_book.snapshot());
+        assert_eq!(restored.mark_price(10), None);
+    }
+
+    #[test]
+    fn test_cancel_worse_than() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        order_book.apply_order(&limit(1, SIDE_BUY, "99", "1"));
+        order_book.apply_order(&limit(2, SIDE_BUY, "97", "1"));
+        order_book.apply_order(&limit(3, SIDE_BUY, "98", "1"));
+        order_book.apply_order(&limit(4, SIDE_BUY, "96", "1"));
+        order_book.apply_order(&limit(5, SIDE_SELL, "101", "1"));
+        order_book.apply_order(&limit(6, SIDE_SELL, "103", "1"));
+        order_book.apply_order(&limit(7, SIDE_SELL, "102", "1"));
+
+        let ids = |logs: Vec<DoneLog>| logs.iter().map(|log| log.order_id).collect::<Vec<u64>>();
+        // orders at the price itself stay
+        assert_eq!(
+            ids(order_book.cancel_worse_than(SIDE_BUY, dec("98"))),
+            vec![2, 4]
+        );
+        assert_eq!(order_book.order_ids(SIDE_BUY), vec![1, 3]);
+
+        assert_eq!(
+            ids(order_book.cancel_worse_than(SIDE_SELL, dec("101.5"))),
+            vec![7, 6]
+        );
+        assert_eq!(order_book.order_ids(SIDE_SELL), vec![5]);
+
+        assert!(order_book
+            .cancel_worse_than(SIDE_SELL, dec("101"))
+            .is_empty());
+        assert!(order_book.cancel_worse_than(SIDE_BUY, dec("90")).is_empty());
+        assert_eq!(order_book.order_ids(SIDE_BUY), vec![1, 3]);
+    }
+
+    #[test]
+    fn test_arithmetic_error() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        // bad data injected on the book, bypassing validation
+        let mut maker = BookOrder::new_book_order(&limit(1, SIDE_SELL, "0", "1"));
+        maker.price = Decimal::ZERO;
+        order_book.ask_depths.add(&maker);
+
+        assert!(!order_book.is_order_will_full_match(&market_buy(2, "100")));
+        assert_eq!(
+            order_book.try_apply_order(&market_buy(2, "100")).err(),
+            Some(CustomError::Arithmetic {
+                taker_id: 2,
+                maker_id: 1,
+                op: "div",
+            })
+        );
+        // rolled back
+        assert_eq!(order_book.ask_depths.orders[&1].size, dec("1"));
+        assert_eq!(order_book.trade_seq, 0);
+    }
+
+    #[test]
+    fn test_max_notional_per_side() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        order_book.max_notional_per_side = Some(dec("500"));
+        order_book.apply_order(&limit(1, SIDE_BUY, "100", "3"));
+        order_book.apply_order(&limit(2, SIDE_BUY, "99", "2"));
+        assert_eq!(order_book.bid_depths.notional, dec("498"));
+
+        // 498 + 98 > 500
+        let logs = to_json(&order_book.apply_order(&limit(3, SIDE_BUY, "98", "1")));
+        assert_eq!(logs.len(), 1);
+        assert_eq!(logs[0]["base"]["type"], "done");
+        assert_eq!(logs[0]["reason"], "cancelled");
+        assert_eq!(order_book.bid_depths.notional, dec("498"));
+        assert_eq!(
+            order_book.rejection_breakdown()[&RejectReason::MaxNotionalPerSide],
+            1
+        );
+
+        // the other side has its own cap
+        order_book.apply_order(&limit(4, SIDE_SELL, "101", "4"));
+        assert_eq!(order_book.ask_depths.notional, dec("404"));
+
+        // fills free up room
+        order_book.apply_order(&limit(5, SIDE_SELL, "100", "1"));
+        assert_eq!(order_book.bid_depths.notional, dec("398"));
+        order_book.apply_order(&limit(6, SIDE_BUY, "1", "2"));
+        assert_eq!(order_book.bid_depths.notional, dec("400"));
+        assert!(order_book.bid_depths.notional <= dec("500"));
+    }
+
+    #[test]
+    fn test_compact() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        for id in 1..=5000 {
+            let price = format!("{}", 90 + id % 10);
+            order_book.apply_order(&limit(id, SIDE_BUY, &price, "1"));
+        }
+        // cancel all but a few orders
+        for id in 1..=5000 {
+            if id % 1000 != 7 {
+                let price = format!("{}", 90 + id % 10);
+                order_book
+                    .cancel_order(&limit(id, SIDE_BUY, &price, "1"))
+                    .unwrap();
+            }
+        }
+        let order_ids = order_book.order_ids(SIDE_BUY);
+        let best_bid = order_book.best_bid();
+        let before = order_book.memory_footprint();
+
+        order_book.compact();
+        assert!(order_book.memory_footprint() < before);
+        assert_eq!(order_book.order_ids(SIDE_BUY), order_ids);
+        assert_eq!(
+            order_book.order_ids(SIDE_BUY),
+            vec![7, 1007, 2007, 3007, 4007]
+        );
+        assert_eq!(order_book.best_bid(), best_bid);
+
+        // matching goes on in the same priority
+        let logs = to_json(&order_book.apply_order(&limit(5001, SIDE_SELL, "97", "2")));
+        assert_eq!(logs[0]["maker_order_id"], 7);
+        assert_eq!(logs[2]["maker_order_id"], 1007);
+    }
+
+    #[test]
+    fn test_apply_order_touched() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        order_book.apply_order(&limit(1, SIDE_SELL, "100", "1"));
+        order_book.apply_order(&limit(2, SIDE_SELL, "101", "1"));
+        order_book.apply_order(&limit(3, SIDE_SELL, "101", "2"));
+        order_book.apply_order(&limit(4, SIDE_SELL, "102", "1"));
+
+        let (logs, touched) = order_book.apply_order_touched(&limit(5, SIDE_BUY, "101", "2.5"));
+        let makers: Vec<u64> = to_json(&logs)
+            .iter()
+            .filter(|log| log["base"]["type"] == "match")
+            .map(|log| log["maker_order_id"].as_u64().unwrap())
+            .collect();
+        assert_eq!(touched, makers);
+        // 3 is only partially filled
+        assert_eq!(touched, vec![1, 2, 3]);
+
+        let (logs, touched) = order_book.apply_order_touched(&limit(6, SIDE_BUY, "99", "1"));
+        assert_eq!(logs.len(), 1);
+        assert!(touched.is_empty());
+    }
+
+    #[test]
+    fn test_market_order_unbounded() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        order_book.max_notional_per_side = Some(dec("1000"));
+        order_book.clamp_to_protection = true;
+        order_book.protection_bps = dec("100");
+        order_book.min_price_improvement = dec("0.5");
+        order_book.apply_order(&limit(1, SIDE_SELL, "100", "1"));
+        order_book.apply_order(&limit(3, SIDE_BUY, "99", "1"));
+
+        let buy = market_buy(4, "150");
+        assert!(!order_book.is_order_will_not_match(&buy));
+        assert!(!order_book.is_order_will_full_match(&buy));
+        let logs = to_json(&order_book.apply_order(&buy));
+        assert_eq!(logs[0]["base"]["type"], "match");
+        assert_eq!(logs[0]["price"], "100");
+        let done = logs.last().unwrap();
+        assert_eq!(done["order_id"], 4);
+        assert_eq!(done["price"], "0");
+
+        let sell = market_sell(5, "1");
+        assert!(order_book.is_order_will_full_match(&sell));
+        let logs = to_json(&order_book.apply_order(&sell));
+        assert_eq!(logs[0]["price"], "99");
+        assert_eq!(logs.last().unwrap()["reason"], "filled");
+    }
+
+    #[test]
+    fn test_depth_in_range() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        order_book.apply_order(&limit(1, SIDE_BUY, "99", "1"));
+        order_book.apply_order(&limit(2, SIDE_BUY, "98", "2"));
+        order_book.apply_order(&limit(3, SIDE_BUY, "98", "0.5"));
+        order_book.apply_order(&limit(4, SIDE_BUY, "97", "4"));
+        order_book.apply_order(&limit(5, SIDE_SELL, "101", "1"));
+        order_book.apply_order(&limit(6, SIDE_SELL, "102", "3"));
+        order_book.apply_order(&limit(7, SIDE_SELL, "104", "5"));
+
+        // inclusive on both ends
+        assert_eq!(
+            order_book.depth_in_range(SIDE_BUY, dec("98"), dec("99")),
+            dec("3.5")
+        );
+        assert_eq!(
+            order_book.depth_in_range(SIDE_BUY, dec("99"), dec("97")),
+            dec("7.5")
+        );
+        assert_eq!(
+            order_book.depth_in_range(SIDE_SELL, dec("101.5"), dec("104")),
+            dec("8")
+        );
+        assert_eq!(
+            order_book.depth_in_range(SIDE_SELL, dec("100"), dec("110")),
+            dec("9")
+        );
+
+        // no levels within
+        assert!(order_book
+            .depth_in_range(SIDE_SELL, dec("102.5"), dec("103.5"))
+            .is_zero());
+        assert!(order_book
+            .depth_in_range(SIDE_BUY, dec("100"), dec("101"))
+            .is_zero());
+        assert!(order_book
+            .depth_in_range(SIDE_BUY, dec("90"), dec("90"))
+            .is_zero());
+    }
+
+    #[test]
+    fn test_roll_session_day_orders() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        let day = |order: Order| Order {
+            day_order: true,
+            ..order
+        };
+        order_book.apply_order(&limit(1, SIDE_BUY, "99", "1"));
+        order_book.apply_order(&day(limit(2, SIDE_BUY, "99", "1")));
+        order_book.apply_order(&limit(3, SIDE_BUY, "99", "1"));
+        order_book.apply_order(&day(limit(4, SIDE_SELL, "101", "1")));
+        order_book.apply_order(&limit(5, SIDE_SELL, "102", "1"));
+        order_book.apply_order(&limit(7, SIDE_BUY, "98", "1"));
+        assert!(order_book
+            .cancel_order(&limit(7, SIDE_BUY, "98", "1"))
+            .is_ok());
+
+        // the closing session holds every order which left the book, the day orders too
+        let logs = order_book.roll_session();
+        assert_eq!(
+            logs.iter().map(|log| log.order_id).collect::<Vec<u64>>(),
+            vec![7, 2, 4]
+        );
+        for log in logs.iter() {
+            assert_eq!(serde_json::to_value(log).unwrap()["reason"], "cancelled");
+        }
+        assert!(order_book.session_done_logs().is_empty());
+        assert_eq!(order_book.order_ids(SIDE_BUY), vec![1, 3]);
+        assert_eq!(order_book.order_ids(SIDE_SELL), vec![5]);
+
+        // the gtc orders keep their priority
+        let logs = to_json(&order_book.apply_order(&limit(6, SIDE_SELL, "99", "1")));
+        assert_eq!(logs[0]["maker_order_id"], 1);
+        // no day order is left, only the fills of the session are returned
+        let mut order_ids: Vec<u64> = order_book
+            .roll_session()
+            .iter()
+            .map(|log| log.order_id)
+            .collect();
+        order_ids.sort();
+        assert_eq!(order_ids, vec![1, 6]);
+        assert!(order_book.roll_session().is_empty());
+    }
+
+    #[test]
+    fn test_maker_remaining_size() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        order_book.apply_order(&limit(1, SIDE_SELL, "100", "3"));
+
+        let mut remaining: Vec<Decimal> = Vec::new();
+        for (id, size) in [(2, "1"), (3, "1.5"), (4, "2")].iter() {
+            for log in to_json(&order_book.apply_order(&limit(*id, SIDE_BUY, "100", size))) {
+                if log["base"]["type"] == "match" {
+                    remaining.push(dec(log["maker_remaining_size"].as_str().unwrap()));
+                }
+            }
+        }
+        assert_eq!(remaining, vec![dec("2"), dec("0.5"), Decimal::ZERO]);
+        assert!(order_book.ask_depths.orders.is_empty());
+    }
+
+    #[test]
+    fn test_replay_product() {
+        let eth = Product {
+            id: "ETH-USD".to_string(),
+            ..new_product()
+        };
+        let mut btc_book = OrderBook::new_order_book(&new_product());
+        let mut eth_book = OrderBook::new_order_book(&eth);
+
+        let btc_orders = [
+            limit(1, SIDE_SELL, "100", "2"),
+            limit(2, SIDE_SELL, "101", "1"),
+            limit(3, SIDE_BUY, "100.5", "3"),
+            limit(4, SIDE_BUY, "99", "1"),
+        ];
+        let eth_orders = [
+            limit(1, SIDE_BUY, "10", "5"),
+            limit(2, SIDE_SELL, "9", "2"),
+            market_sell(3, "1"),
+            limit(4, SIDE_SELL, "11", "1"),
+        ];
+        let mut stream: Vec<Box<dyn LogTrait>> = Vec::new();
+        for (btc_order, eth_order) in btc_orders.iter().zip(eth_orders.iter()) {
+            stream.extend(btc_book.apply_order(btc_order));
+            stream.extend(eth_book.apply_order(eth_order));
+        }
+
+        for (product, original) in [(new_product(), &btc_book), (eth.clone(), &eth_book)].iter() {
+            let replayed = OrderBook::replay_product(product, &stream, &product.id).unwrap();
+            assert!(replayed.equal_ignoring_seqs(original));
+            assert_eq!(
+                (replayed.log_seq, replayed.trade_seq),
+                (original.log_seq, original.trade_seq)
+            );
+            assert_eq!(replayed.last_trade(), original.last_trade());
+        }
+
+        // a product log missing from the stream is a gap
+        let gap = stream
+            .iter()
+            .position(|log| log.product_id() == "ETH-USD" && log.get_seq() == 3)
+            .unwrap();
+        stream.remove(gap);
+        assert_eq!(
+            OrderBook::replay_product(&eth, &stream, "ETH-USD").err(),
+            Some(CustomError::SequenceGap {
+                product_id: "ETH-USD".to_string(),
+                expected: 3,
+                actual: 4,
+            })
+        );
+        assert!(OrderBook::replay_product(&new_product(), &stream, "BTC-USD").is_ok());
+    }
+
+    #[test]
+    fn test_liquidity_histogram() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        order_book.apply_order(&limit(1, SIDE_BUY, "100", "1"));
+        order_book.apply_order(&limit(2, SIDE_BUY, "99.5", "2"));
+        order_book.apply_order(&limit(3, SIDE_BUY, "99", "3"));
+        order_book.apply_order(&limit(4, SIDE_BUY, "97.5", "4"));
+        order_book.apply_order(&limit(5, SIDE_BUY, "90", "5"));
+        order_book.apply_order(&limit(6, SIDE_SELL, "101", "1"));
+        order_book.apply_order(&limit(7, SIDE_SELL, "102", "2"));
+
+        let histogram = |side: Side, overflow: HistogramOverflow| {
+            order_book.liquidity_histogram_with_overflow(side, dec("1"), 3, overflow)
+        };
+        assert_eq!(
+            histogram(SIDE_BUY, HistogramOverflow::Drop),
+            vec![dec("3"), dec("3"), dec("4")]
+        );
+        assert_eq!(
+            histogram(SIDE_BUY, HistogramOverflow::Clamp),
+            vec![dec("3"), dec("3"), dec("9")]
+        );
+        assert_eq!(
+            histogram(SIDE_SELL, HistogramOverflow::Drop),
+            vec![dec("1"), dec("2"), Decimal::ZERO]
+        );
+        // clamped by default
+        assert_eq!(
+            order_book.liquidity_histogram(SIDE_BUY, dec("1"), 3),
+            histogram(SIDE_BUY, HistogramOverflow::Clamp)
+        );
+        assert!(order_book
+            .liquidity_histogram(SIDE_BUY, Decimal::ZERO, 3)
+            .is_empty());
+    }
+
+    #[test]
+    fn test_limit_exactly_fills_book() {
+        for min_size in ["0", "0.01"].iter() {
+            let mut product = new_product();
+            product.min_size = dec(min_size);
+            let mut order_book = OrderBook::new_order_book(&product);
+            order_book.apply_order(&limit(1, SIDE_SELL, "100", "1"));
+            order_book.apply_order(&limit(2, SIDE_SELL, "101", "2"));
+
+            // consumes the whole ask side to exactly zero
+            let logs = to_json(&order_book.apply_order(&limit(3, SIDE_BUY, "101", "3")));
+            let types: Vec<_> = logs.iter().map(|l| l["base"]["type"].clone()).collect();
+            assert_eq!(types, vec!["match", "done", "match", "done", "done"]);
+            let done = &logs[4];
+            assert_eq!(done["order_id"], 3);
+            assert_eq!(done["reason"], "filled");
+            assert_eq!(dec(done["remaining_size"].as_str().unwrap()), Decimal::ZERO);
+
+            assert!(order_book.ask_depths.orders.is_empty());
+            assert!(order_book.bid_depths.orders.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_levels_with_counts() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        order_book.apply_order(&limit(1, SIDE_BUY, "99", "5"));
+        order_book.apply_order(&limit(2, SIDE_BUY, "100", "1"));
+        order_book.apply_order(&limit(3, SIDE_BUY, "99", "7.5"));
+        order_book.apply_order(&limit(4, SIDE_BUY, "99", "2.5"));
+        order_book.apply_order(&limit(5, SIDE_SELL, "101", "1"));
+
+        assert_eq!(
+            order_book.levels_with_counts(SIDE_BUY),
+            vec![(dec("100"), dec("1"), 1), (dec("99"), dec("15"), 3)]
+        );
+        assert_eq!(
+            order_book.levels_with_counts(SIDE_SELL),
+            vec![(dec("101"), dec("1"), 1)]
+        );
+    }
+
+    #[test]
+    fn test_apply_order_into() {
+        let orders = [
+            limit(1, SIDE_SELL, "100", "1"),
+            limit(2, SIDE_SELL, "101", "2"),
+            limit(3, SIDE_BUY, "101", "1.5"),
+            market_buy(4, "500"),
+            limit(4, SIDE_BUY, "99", "1"),
+        ];
+        let mut returning = OrderBook::new_order_book(&new_product());
+        let mut appending = OrderBook::new_order_book(&new_product());
+
+        let mut expected: Vec<Box<dyn LogTrait>> = Vec::new();
+        let mut buffer: Vec<Box<dyn LogTrait>> = Vec::new();
+        for order in orders.iter() {
+            expected.extend(returning.apply_order(order));
+            // appends after the logs of the orders before
+            appending.apply_order_into(order, &mut buffer);
+        }
+        // the logs are stamped with the clock
+        let untimed = |logs: &Vec<Box<dyn LogTrait>>| {
+            let mut logs = to_json(logs);
+            for log in logs.iter_mut() {
+                log["base"]["time"] = serde_json::Value::Null;
+            }
+            logs
+        };
+        assert_eq!(untimed(&buffer), untimed(&expected));
+        assert!(appending.equal_ignoring_seqs(&returning));
+    }
+
+    #[test]
+    fn test_cancel_order_with_reason() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        let reasons = [
+            (DONE_REASON_CANCELLED, "cancelled"),
+            (DONE_REASON_RISK, "risk"),
+            (DONE_REASON_EXPIRED, "expired"),
+            (DONE_REASON_ADMIN, "admin"),
+        ];
+        for (id, (reason, expected)) in reasons.iter().enumerate() {
+            let order = limit(id as u64 + 1, SIDE_BUY, "99", "1");
+            order_book.apply_order(&order);
+            match order_book.cancel_order_with_reason(&order, reason.clone()) {
+                Ok(CancelOutcome::Cancelled(log)) => {
+                    assert_eq!(serde_json::to_value(&log).unwrap()["reason"], *expected);
+                    let json = serde_json::to_string(&log).unwrap();
+                    let log: DoneLog = serde_json::from_str(&json).unwrap();
+                    assert_eq!(serde_json::to_value(&log).unwrap()["reason"], *expected);
+                }
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+        assert!(order_book.bid_depths.orders.is_empty());
+    }
+
+    #[test]
+    fn test_quote() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        let user = 7;
+        order_book.apply_order(&user_limit(1, user, SIDE_BUY, "99", "1"));
+        order_book.apply_order(&user_limit(2, user, SIDE_SELL, "101", "1"));
+
+        // full two-sided refresh
+        let logs = order_book
+            .quote(
+                Some(user_limit(3, user, SIDE_BUY, "99.5", "2")),
+                Some(user_limit(4, user, SIDE_SELL, "100.5", "2")),
+                Some(1),
+                Some(2),
+            )
+            .unwrap();
+        let logs = to_json(&logs);
+        let summary: Vec<_> = logs
+            .iter()
+            .map(|l| (l["base"]["type"].clone(), l["order_id"].clone()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("done".into(), 1.into()),
+                ("done".into(), 2.into()),
+                ("open".into(), 3.into()),
+                ("open".into(), 4.into()),
+            ]
+        );
+        assert_eq!(order_book.order_ids(SIDE_BUY), vec![3]);
+        assert_eq!(order_book.order_ids(SIDE_SELL), vec![4]);
+
+        // bid only, the ask stays
+        let logs = order_book
+            .quote(
+                Some(user_limit(5, user, SIDE_BUY, "99.8", "1")),
+                None,
+                Some(3),
+                None,
+            )
+            .unwrap();
+        assert_eq!(logs.len(), 2);
+        assert_eq!(order_book.order_ids(SIDE_BUY), vec![5]);
+        assert_eq!(order_book.order_ids(SIDE_SELL), vec![4]);
+    }
+
+    #[test]
+    fn test_quote_all_or_nothing() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        order_book.apply_order(&limit(1, SIDE_BUY, "99", "1"));
+        order_book.apply_order(&limit(2, SIDE_SELL, "101", "1"));
+        let log_seq = order_book.log_seq;
+
+        // the new ask is invalid, so the bid is neither cancelled nor replaced
+        let result = order_book.quote(
+            Some(limit(3, SIDE_BUY, "99.5", "1")),
+            Some(limit(4, SIDE_SELL, "100.5", "0")),
+            Some(1),
+            Some(2),
+        );
+        assert!(matches!(
+            result,
+            Err(CustomError::InvalidOrder { order_id: 4, .. })
+        ));
+        // the order to cancel is not on its side of the book
+        let result = order_book.quote(None, None, Some(2), None);
+        assert_eq!(result.err(), Some(CustomError::WrongSide(2)));
+        // the new bid reuses a seen id
+        let result = order_book.quote(Some(limit(1, SIDE_BUY, "98", "1")), None, None, None);
+        assert_eq!(result.err(), Some(CustomError::OrderIdDuplicate(1)));
+        // every refused quote is counted by its reason
+        let breakdown = order_book.rejection_breakdown();
+        for reason in [
+            RejectReason::InvalidOrder,
+            RejectReason::InvalidCancel,
+            RejectReason::DuplicateOrderId,
+        ]
+        .iter()
+        {
+            assert_eq!(breakdown[reason], 1);
+        }
+
+        assert_eq!(order_book.log_seq, log_seq);
+        assert_eq!(order_book.order_ids(SIDE_BUY), vec![1]);
+        assert_eq!(order_book.order_ids(SIDE_SELL), vec![2]);
+        // the ids of the failed quotes are not taken
+        let logs = order_book.apply_order(&limit(3, SIDE_BUY, "99.5", "1"));
+        assert_eq!(to_json(&logs)[0]["base"]["type"], "open");
+    }
+
+    #[test]
+    fn test_quote_rollback() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        let user = 7;
+        order_book.apply_order(&user_limit(1, user, SIDE_BUY, "99", "1"));
+        order_book.apply_order(&user_limit(2, user, SIDE_SELL, "101", "1"));
+        order_book.apply_order(&user_limit(5, 8, SIDE_SELL, "99.4", "1"));
+        order_book.max_notional_per_side = Some(dec("500"));
+        let (log_seq, trade_seq, version) = (
+            order_book.log_seq,
+            order_book.trade_seq,
+            order_book.version(),
+        );
+        let done_logs = order_book.session_done_logs().len();
+
+        // the bid trades with 5 and rests, then the ask is cancelled by the notional limit
+        let result = order_book.quote(
+            Some(user_limit(3, user, SIDE_BUY, "99.5", "2")),
+            Some(user_limit(4, user, SIDE_SELL, "100.5", "10")),
+            Some(1),
+            Some(2),
+        );
+        assert_eq!(result.err(), Some(CustomError::QuoteRejected(4)));
+        assert_eq!(
+            order_book.rejection_breakdown()[&RejectReason::MaxNotionalPerSide],
+            1
+        );
+
+        assert_eq!(order_book.order_ids(SIDE_BUY), vec![1]);
+        assert_eq!(order_book.order_ids(SIDE_SELL), vec![5, 2]);
+        assert_eq!(order_book.ask_depths.orders[&5].size, dec("1"));
+        assert_eq!(order_book.bid_depths.notional, dec("99"));
+        assert_eq!(order_book.ask_depths.notional, dec("200.4"));
+        assert_eq!(order_book.log_seq, log_seq);
+        assert_eq!(order_book.trade_seq, trade_seq);
+        assert_eq!(order_book.version(), version + 1);
+        assert!(order_book.last_trade().is_none());
+        assert!(order_book.mark_price(10).is_none());
+        assert_eq!(order_book.session_done_logs().len(), done_logs);
+        assert!(!order_book.last_order_rested());
+
+        // the ids of the rolled back orders are free again
+        let logs = order_book.apply_order(&user_limit(4, user, SIDE_SELL, "102", "1"));
+        assert_eq!(to_json(&logs)[0]["base"]["type"], "open");
+        let logs = order_book.apply_order(&user_limit(3, user, SIDE_BUY, "98", "1"));
+        assert_eq!(to_json(&logs)[0]["base"]["type"], "open");
+    }
+
+    #[test]
+    fn test_quote_cancel_of_another_user() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        order_book.apply_order(&user_limit(1, 7, SIDE_BUY, "99", "1"));
+        order_book.apply_order(&user_limit(2, 8, SIDE_BUY, "98", "1"));
+        order_book.apply_order(&user_limit(3, 8, SIDE_SELL, "101", "1"));
+        let log_seq = order_book.log_seq;
+
+        let result = order_book.quote(
+            Some(user_limit(4, 7, SIDE_BUY, "99.5", "1")),
+            None,
+            Some(2),
+            None,
+        );
+        assert_eq!(
+            result.err(),
+            Some(CustomError::NotOwner {
+                order_id: 2,
+                user_id: 7
+            })
+        );
+        let result = order_book.quote(
+            None,
+            Some(user_limit(4, 7, SIDE_SELL, "100.5", "1")),
+            None,
+            Some(3),
+        );
+        assert_eq!(
+            result.err(),
+            Some(CustomError::NotOwner {
+                order_id: 3,
+                user_id: 7
+            })
+        );
+        // the two orders of a quote are of one user
+        let result = order_book.quote(
+            Some(user_limit(4, 7, SIDE_BUY, "99.5", "1")),
+            Some(user_limit(5, 8, SIDE_SELL, "100.5", "1")),
+            None,
+            None,
+        );
+        assert!(matches!(
+            result,
+            Err(CustomError::InvalidOrder { order_id: 5, .. })
+        ));
+
+        let breakdown = order_book.rejection_breakdown();
+        assert_eq!(breakdown[&RejectReason::InvalidCancel], 2);
+        assert_eq!(breakdown[&RejectReason::InvalidOrder], 1);
+
+        assert_eq!(order_book.log_seq, log_seq);
+        assert_eq!(order_book.order_ids(SIDE_BUY), vec![1, 2]);
+        assert_eq!(order_book.order_ids(SIDE_SELL), vec![3]);
+    }
+
+    #[test]
+    fn test_quote_time_in_force() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        let user = 7;
+        order_book.apply_order(&user_limit(1, user, SIDE_BUY, "99", "1"));
+        order_book.apply_order(&user_limit(2, 8, SIDE_SELL, "100", "1"));
+        let log_seq = order_book.log_seq;
+
+        // a post-only bid crossing the ask refuses the whole quote
+        let mut post_only = user_limit(3, user, SIDE_BUY, "100", "1");
+        post_only.time_in_force = GOOD_TILL_CROSSING;
+        let result = order_book.quote(Some(post_only), None, Some(1), None);
+        assert_eq!(result.err(), Some(CustomError::QuoteRejected(3)));
+        assert_eq!(
+            order_book.rejection_breakdown()[&RejectReason::PostOnlyCross],
+            1
+        );
+        // so does a fill-or-kill bid the ask can't fill
+        let mut fill_or_kill = user_limit(3, user, SIDE_BUY, "100", "2");
+        fill_or_kill.time_in_force = FILL_OR_KILL;
+        let result = order_book.quote(Some(fill_or_kill), None, Some(1), None);
+        assert_eq!(result.err(), Some(CustomError::QuoteRejected(3)));
+        assert_eq!(
+            order_book.rejection_breakdown()[&RejectReason::FillOrKill],
+            1
+        );
+        assert_eq!(order_book.log_seq, log_seq);
+        assert_eq!(order_book.order_ids(SIDE_BUY), vec![1]);
+        assert_eq!(order_book.order_ids(SIDE_SELL), vec![2]);
+
+        // the rest of an immediate-or-cancel bid is cancelled instead of resting
+        let mut ioc = user_limit(3, user, SIDE_BUY, "100", "2");
+        ioc.time_in_force = IMMEDIATE_OR_CANCEL;
+        let logs = to_json(&order_book.quote(Some(ioc), None, Some(1), None).unwrap());
+        let summary: Vec<_> = logs
+            .iter()
+            .map(|l| (l["base"]["type"].clone(), l["order_id"].clone()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("done".into(), 1.into()),
+                ("match".into(), serde_json::Value::Null),
+                ("done".into(), 2.into()),
+                ("open".into(), 3.into()),
+                ("done".into(), 3.into()),
+            ]
+        );
+        assert_eq!(logs[4]["reason"], "cancelled");
+        assert!(order_book.order_ids(SIDE_BUY).is_empty());
+        assert!(order_book.order_ids(SIDE_SELL).is_empty());
+        assert!(!order_book.last_order_rested());
+    }
+
+    #[test]
+    fn test_orders_for_user() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        let user = 7;
+        order_book.apply_order(&user_limit(1, user, SIDE_BUY, "98", "1"));
+        order_book.apply_order(&user_limit(2, 8, SIDE_BUY, "99", "1"));
+        order_book.apply_order(&user_limit(3, user, SIDE_SELL, "102", "1"));
+        order_book.apply_order(&user_limit(4, user, SIDE_BUY, "99", "1"));
+        order_book.apply_order(&user_limit(5, user, SIDE_SELL, "101", "1"));
+        order_book.apply_order(&user_limit(6, user, SIDE_BUY, "98", "1"));
+
+        let ids = |order_book: &OrderBook| -> Vec<u64> {
+            order_book
+                .orders_for_user(user)
+                .iter()
+                .map(|o| o.order_id)
+                .collect()
+        };
+        let first = ids(&order_book);
+        assert_eq!(first, vec![5, 3, 4, 1, 6]);
+        for _ in 0..10 {
+            assert_eq!(ids(&order_book), first);
+        }
+        assert!(order_book.orders_for_user(9).is_empty());
+    }
+
+    #[test]
+    fn test_fill_or_kill_fillable() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        order_book.apply_order(&limit(1, SIDE_SELL, "99", "20"));
+        order_book.apply_order(&limit(2, SIDE_SELL, "100", "40"));
+        order_book.apply_order(&limit(3, SIDE_SELL, "101", "10"));
+
+        let mut fok = limit(4, SIDE_BUY, "100", "100");
+        fok.time_in_force = FILL_OR_KILL;
+        let logs = to_json(&order_book.process_order(&fok));
+        assert_eq!(logs.len(), 1);
+        assert_eq!(logs[0]["reason"], "cancelled");
+        assert!(logs[0].get("fillable_size").is_none());
+
+        order_book.report_fok_fillable = true;
+        fok.id = 5;
+        let logs = to_json(&order_book.process_order(&fok));
+        assert_eq!(logs.len(), 1);
+        assert_eq!(logs[0]["order_id"], 5);
+        assert_eq!(logs[0]["reason"], "cancelled");
+        assert_eq!(dec(logs[0]["fillable_size"].as_str().unwrap()), dec("60"));
+        assert_eq!(
+            order_book.rejection_breakdown()[&RejectReason::FillOrKill],
+            2
+        );
+        assert_eq!(order_book.ask_depths.orders.len(), 3);
+
+        // a repeated id is dropped before any fill is searched for
+        let logs = order_book.process_order(&fok);
+        assert!(logs.is_empty());
+        assert_eq!(
+            order_book.rejection_breakdown()[&RejectReason::DuplicateOrderId],
+            1
+        );
+
+        // a malformed order is rejected as invalid, without the fillable size
+        let mut malformed = limit(6, SIDE_BUY, "100", "-1");
+        malformed.time_in_force = FILL_OR_KILL;
+        let logs = to_json(&order_book.process_order(&malformed));
+        assert_eq!(logs.len(), 1);
+        assert!(logs[0].get("fillable_size").is_none());
+        assert_eq!(
+            order_book.rejection_breakdown()[&RejectReason::InvalidOrder],
+            1
+        );
+        assert_eq!(
+            order_book.rejection_breakdown()[&RejectReason::FillOrKill],
+            2
+        );
+        assert_eq!(order_book.session_done_logs().len(), 3);
+    }
+
+    #[test]
+    fn test_try_apply_order_rollback_trades() {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        // fill up recent_trades, so that a new trade pushes the oldest out
+        let mut id = 0;
+        for i in 0..RECENT_TRADES_CAP {
+            let price = (100 + i % 7).to_string();
+            order_book.apply_order(&limit(id + 1, SIDE_SELL, &price, "1"));
+            order_book.apply_order(&limit(id + 2, SIDE_BUY, &price, "1"));
+            id += 2;
+        }
+        order_book.apply_order(&limit(id + 1, SIDE_SELL, "100", "1"));
+        order_book
+            .ask_depths
+            .queue
+            .insert(PriceOrderIdKeyAsc::new(&dec("101"), 0, 99999), 99999);
+        let recent_trades = order_book.recent_trades.clone();
+        let last_trade = order_book.last_trade();
+
+        let taker = limit(id + 2, SIDE_BUY, "101", "2");
+        assert!(order_book.try_apply_order(&taker).is_err());
+        assert_eq!(order_book.recent_trades, recent_trades);
+        assert_eq!(order_book.last_trade(), last_trade);
+        assert!(!order_book.order_id_window.contains(taker.id));
+        assert!(order_book.order_id_window.contains(id + 1));
+    }
+
+    #[test]
+    fn test_top_levels_random() {
+        // xorshift, so the operations are the same on every run
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut next = move |bound: u64| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state % bound
+        };
+
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        let mut placed: Vec<Order> = Vec::new();
+        // the most levels seen on both sides at once
+        let mut both_sides = 0;
+        for order_id in 1..=2000 {
+            let price = (95 + next(10)).to_string();
+            let size = (1 + next(4)).to_string();
+            match next(6) {
+                0 | 1 => {
+                    let order = limit(order_id, SIDE_BUY, &price, &size);
+                    order_book.apply_order(&order);
+                    placed.push(order);
+                }
+                2 | 3 => {
+                    let order = limit(order_id, SIDE_SELL, &price, &size);
+                    order_book.apply_order(&order);
+                    placed.push(order);
+                }
+                4 if !placed.is_empty() => {
+                    let order = placed.swap_remove(next(placed.len() as u64) as usize);
+                    let _ = order_book.cancel_order(&order);
+                }
+                _ => {
+                    let order = match next(2) {
+                        0 => market_buy(order_id, &(price + "0")),
+                        _ => market_sell(order_id, &size),
+                    };
+                    order_book.apply_order(&order);
+                }
+            }
+
+            let (bids, asks) = order_book.l2_depth(usize::MAX);
+            assert_eq!(order_book.top_levels(usize::MAX), (&bids[..], &asks[..]));
+            both_sides = usize::max(both_sides, usize::min(bids.len(), asks.len()));
+            let (bids, asks) = order_book.l2_depth(3);
+            assert_eq!(order_book.top_levels(3), (&bids[..], &asks[..]));
+        }
+        assert!(both_sides > 3);
+    }
+
+    fn bench_book() -> OrderBook {
+        let mut order_book = OrderBook::new_order_book(&new_product());
+        for order_id in 1..=1000u64 {
+            let price = (order_id % 100).to_string();
+            order_book.apply_order(&limit(order_id, SIDE_BUY, &price, "1"));
+            let price = (200 + order_id % 100).to_string();
+            order_book.apply_order(&limit(1000 + order_id, SIDE_SELL, &price, "1"));
+        }
+        order_book
+    }
+
+    #[bench]
+    fn bench_top_levels(b: &mut test::Bencher) {
+        let order_book = bench_book();
+        b.iter(|| {
+            let (bids, asks) = order_book.top_levels(10);
+            test::black_box(bids.len() + asks.len())
+        });
+    }
+
+    #[bench]
+    fn bench_l2_depth(b: &mut test::Bencher) {
+        let order_book = bench_book();
+        b.iter(|| {
+            let (bids, asks) = order_book.l2_depth(10);
+            test::black_box(bids.len() + asks.len())
+        });
+    }
+
+    #[test]
+    fn test_matching_strategy() {
+        // (pro-rata, size of the fills of maker 1 and 2)
+        let cases = [(false, ["1", "1"]), (true, ["0.5", "1.5"])];
+        for (pro_rata, sizes) in cases.iter() {
+            let new_book = || {
+                let mut order_book = OrderBook::new_order_book(&new_product());
+                if *pro_rata {
+                    order_book.set_matching_strategy(Rc::new(ProRataStrategy));
+                }
+                order_book.self_trade_prevention = SelfTradePrevention::CancelMaker;
+                order_book.apply_order(&user_limit(1, 7, SIDE_SELL, "100", "1"));
+                order_book.apply_order(&user_limit(2, 8, SIDE_SELL, "100", "3"));
+                order_book
+            };
+            let fills = |logs: &Vec<serde_json::Value>| {
+                logs.iter()
+                    .filter(|log| log["base"]["type"] == "match")
+                    .map(|log| {
+                        let size = dec(log["size"].as_str().unwrap());
+                        (log["maker_order_id"].as_u64().unwrap(), size)
+                    })
+                    .collect::<Vec<(u64, Decimal)>>()
+            };
+
+            let mut order_book = new_book();
+            let logs = to_json(&order_book.apply_order(&user_limit(3, 9, SIDE_BUY, "100", "2")));
+            assert_eq!(fills(&logs), vec![(1, dec(sizes[0])), (2, dec(sizes[1]))]);
+            let left: Decimal = order_book.ask_depths.orders.values().map(|o| o.size).sum();
+            assert_eq!(left, dec("2"));
+            assert_eq!(logs.last().unwrap()["reason"], "filled");
+
+            // maker 1 is cancelled by self trade prevention, all of the taker goes to maker 2
+            let mut order_book = new_book();
+            let logs = to_json(&order_book.apply_order(&user_limit(3, 7, SIDE_BUY, "100", "2")));
+            assert_eq!(logs[0]["reason"], "stp");
+            assert_eq!(fills(&logs), vec![(2, dec("2"))]);
+            assert_eq!(order_book.ask_depths.orders[&2].size, dec("1"));
+        }
+    }
+
+    #[test]
+    fn test_pegged_order() {
+        let pegged = |id: u64, side: Side, reference: PegReference, offset: &str| Order {
+            peg: Some(Peg {
+                reference,
+                offset: dec(offset),
+            }),
+            ..limit(id, side, "0", "1")
+        };
+        // (order id, old price, new price) of the change logs
+        let changes = |logs: &Vec<Box<dyn LogTrait>>| -> Vec<(u64, Decimal, Decimal)> {
+            logs.iter()
+                .filter_map(|log| match log.to_event() {
+                    LogEvent::Change(log) => Some((log.order_id, log.old_price, log.new_price)),
+                    _ => None,
+                })
+                .collect()
+        };
+
+        let product = new_product();
+        let mut order_book = OrderBook::new_order_book(&product);
+        let mut stream: Vec<Box<dyn LogTrait>> = Vec::new();
+
+        // nothing to peg to yet
+        let logs = order_book.apply_order(&pegged(1, SIDE_BUY, PEG_REFERENCE_BEST_BID, "0.5"));
+        assert_eq!(to_json(&logs)[0]["reason"], "cancelled");
+        assert_eq!(
+            order_book.rejection_breakdown()[&RejectReason::InvalidOrder],
+            1
+        );
+        stream.extend(logs);
+
+        stream.extend(order_book.apply_order(&limit(2, SIDE_BUY, "99", "1")));
+        stream.extend(order_book.apply_order(&limit(3, SIDE_SELL, "101", "1")));
+        let logs = order_book.apply_order(&pegged(4, SIDE_BUY, PEG_REFERENCE_BEST_BID, "0.5"));
+        assert_eq!(order_book.bid_depths.orders[&4].price, dec("99.5"));
+        stream.extend(logs);
+        let logs = order_book.apply_order(&pegged(5, SIDE_SELL, PEG_REFERENCE_MIDPOINT, "0.25"));
+        assert_eq!(order_book.ask_depths.orders[&5].price, dec("100.25"));
+        stream.extend(logs);
+
+        // a better bid moves both in the order of their ids, the buy is kept a tick below
+        // the sell, which hasn't moved yet
+        let logs = order_book.apply_order(&limit(6, SIDE_BUY, "100", "1"));
+        assert_eq!(
+            changes(&logs),
+            vec![
+                (4, dec("99.5"), dec("100.24")),
+                (5, dec("100.25"), dec("100.75"))
+            ]
+        );
+        assert_eq!(order_book.best_bid(), Some(dec("100.24")));
+        stream.extend(logs);
+
+        // an order which doesn't change the reference leaves them alone
+        let logs = order_book.apply_order(&limit(7, SIDE_BUY, "98", "1"));
+        assert!(changes(&logs).is_empty());
+        stream.extend(logs);
+
+        // cancelling the bid moves them back
+        let mut cancel = limit(6, SIDE_BUY, "100", "1");
+        cancel.status = ORDER_STATUS_CANCELLING;
+        let logs = order_book.process_order(&cancel);
+        assert_eq!(
+            changes(&logs),
+            vec![
+                (4, dec("100.24"), dec("99.5")),
+                (5, dec("100.75"), dec("100.25"))
+            ]
+        );
+        stream.extend(logs);
+
+        let replayed = OrderBook::replay_product(&product, &stream, &product.id).unwrap();
+        assert_eq!(replayed.l2_depth(10), order_book.l2_depth(10));
+        assert_eq!(replayed.log_seq, order_book.log_seq);
+
+        // a filled pegged order is dropped from the pegs on the next reprice
+        order_book.apply_order(&market_sell(8, "1"));
+        assert!(!order_book.bid_depths.orders.contains_key(&4));
+        let logs = order_book.apply_order(&limit(9, SIDE_BUY, "99.9", "1"));
+        assert_eq!(changes(&logs), vec![(5, dec("100.25"), dec("100.7"))]);
+        assert_eq!(order_book.pegs.iter().copied().collect::<Vec<_>>(), vec![5]);
     }
 }
@@ -4,19 +4,23 @@ use serde_derive::{Deserialize, Serialize};
 use std::cmp::Ordering;
 
 pub trait OrderingTrait {
-    fn new(price: &Decimal, order_id: u64) -> Self;
+    fn new(price: &Decimal, priority_class: u8, order_id: u64) -> Self;
 }
 
 #[derive(Default, Debug, Serialize, Deserialize, Clone)]
 pub struct PriceOrderIdKeyAsc {
     pub price: Decimal,
+    // higher class goes first at the same price
+    #[serde(default)]
+    pub priority_class: u8,
     pub order_id: u64,
 }
 
 impl OrderingTrait for PriceOrderIdKeyAsc {
-    fn new(price: &Decimal, order_id: u64) -> Self {
+    fn new(price: &Decimal, priority_class: u8, order_id: u64) -> Self {
         PriceOrderIdKeyAsc {
             price: price.clone(),
+            priority_class,
             order_id,
         }
     }
@@ -26,7 +30,9 @@ impl Eq for PriceOrderIdKeyAsc {}
 
 impl PartialEq<Self> for PriceOrderIdKeyAsc {
     fn eq(&self, other: &Self) -> bool {
-        self.price.eq(&other.price) && self.order_id.eq(&other.order_id)
+        self.price.eq(&other.price)
+            && self.priority_class.eq(&other.priority_class)
+            && self.order_id.eq(&other.order_id)
     }
 }
 
@@ -41,10 +47,14 @@ impl Ord for PriceOrderIdKeyAsc {
         return match self.price.cmp(&other.price) {
             Ordering::Less => Ordering::Less,
             Ordering::Greater => Ordering::Greater,
-            Ordering::Equal => match self.order_id.cmp(&other.order_id) {
-                Ordering::Less => Ordering::Less,
-                Ordering::Greater => Ordering::Greater,
-                Ordering::Equal => Ordering::Equal,
+            Ordering::Equal => match self.priority_class.cmp(&other.priority_class) {
+                Ordering::Less => Ordering::Greater,
+                Ordering::Greater => Ordering::Less,
+                Ordering::Equal => match self.order_id.cmp(&other.order_id) {
+                    Ordering::Less => Ordering::Less,
+                    Ordering::Greater => Ordering::Greater,
+                    Ordering::Equal => Ordering::Equal,
+                },
             },
         };
     }
@@ -53,13 +63,17 @@ impl Ord for PriceOrderIdKeyAsc {
 #[derive(Default, Debug, Serialize, Deserialize, Clone)]
 pub struct PriceOrderIdKeyDesc {
     pub price: Decimal,
+    // higher class goes first at the same price
+    #[serde(default)]
+    pub priority_class: u8,
     pub order_id: u64,
 }
 
 impl OrderingTrait for PriceOrderIdKeyDesc {
-    fn new(price: &Decimal, order_id: u64) -> Self {
+    fn new(price: &Decimal, priority_class: u8, order_id: u64) -> Self {
         PriceOrderIdKeyDesc {
             price: price.clone(),
+            priority_class,
             order_id,
         }
     }
@@ -69,7 +83,9 @@ impl Eq for PriceOrderIdKeyDesc {}
 
 impl PartialEq<Self> for PriceOrderIdKeyDesc {
     fn eq(&self, other: &Self) -> bool {
-        self.price.eq(&other.price) && self.order_id.eq(&other.order_id)
+        self.price.eq(&other.price)
+            && self.priority_class.eq(&other.priority_class)
+            && self.order_id.eq(&other.order_id)
     }
 }
 
@@ -84,10 +100,14 @@ impl Ord for PriceOrderIdKeyDesc {
         return match self.price.cmp(&other.price) {
             Ordering::Less => Ordering::Greater,
             Ordering::Greater => Ordering::Less,
-            Ordering::Equal => match self.order_id.cmp(&other.order_id) {
-                Ordering::Less => Ordering::Less,
-                Ordering::Greater => Ordering::Greater,
-                Ordering::Equal => Ordering::Equal,
+            Ordering::Equal => match self.priority_class.cmp(&other.priority_class) {
+                Ordering::Less => Ordering::Greater,
+                Ordering::Greater => Ordering::Less,
+                Ordering::Equal => match self.order_id.cmp(&other.order_id) {
+                    Ordering::Less => Ordering::Less,
+                    Ordering::Greater => Ordering::Greater,
+                    Ordering::Equal => Ordering::Equal,
+                },
             },
         };
     }
@@ -0,0 +1,228 @@
+use std::collections::HashMap;
+
+use crate::matching::log::LogTrait;
+use crate::matching::order_book::{OrderBook, OrderBookSnapshot};
+use crate::models::models::{Order, Product};
+use crate::utils::error::CustomError;
+
+// EngineRegistry holds one order book per product and routes orders to them by product id
+#[derive(Default)]
+pub struct EngineRegistry {
+    pub books: HashMap<String, OrderBook>,
+}
+
+impl EngineRegistry {
+    pub fn new() -> Self {
+        EngineRegistry {
+            books: HashMap::new(),
+        }
+    }
+
+    pub fn add_product(&mut self, product: &Product) -> Result<(), CustomError> {
+        if self.books.contains_key(&product.id) {
+            return Err(CustomError::ProductExists(product.id.clone()));
+        }
+        self.books
+            .insert(product.id.clone(), OrderBook::new_order_book(product));
+        Ok(())
+    }
+
+    pub fn remove_product(&mut self, product_id: &str) -> Option<OrderBook> {
+        self.books.remove(product_id)
+    }
+
+    pub fn route_order(&mut self, order: &Order) -> Result<Vec<Box<dyn LogTrait>>, CustomError> {
+        match self.books.get_mut(&order.product_id) {
+            Some(order_book) => Ok(order_book.process_order(order)),
+            None => Err(CustomError::UnknownProduct(order.product_id.clone())),
+        }
+    }
+
+    pub fn snapshot_all(&self) -> HashMap<String, OrderBookSnapshot> {
+        self.books
+            .iter()
+            .map(|(product_id, order_book)| (product_id.clone(), order_book.snapshot()))
+            .collect()
+    }
+
+    // restore the books of all products together, every snapshot must belong to one of
+    // the given products and be keyed by its own product id, products without a snapshot
+    // start with an empty book
+    pub fn restore_all(
+        products: &[Product],
+        snapshots: &HashMap<String, OrderBookSnapshot>,
+    ) -> Result<EngineRegistry, CustomError> {
+        if let Some(product_id) = snapshots
+            .keys()
+            .find(|product_id| !products.iter().any(|p| &p.id == *product_id))
+        {
+            return Err(CustomError::UnknownProduct(product_id.clone()));
+        }
+        // a snapshot filed under another product would load its orders into the wrong book
+        if let Some((product_id, snapshot)) = snapshots
+            .iter()
+            .find(|(product_id, snapshot)| snapshot.product_id != **product_id)
+        {
+            return Err(CustomError::SnapshotMismatch {
+                product_id: product_id.clone(),
+                snapshot_product_id: snapshot.product_id.clone(),
+            });
+        }
+
+        let mut registry = EngineRegistry::new();
+        for product in products {
+            let mut order_book = OrderBook::new_order_book(product);
+            if let Some(snapshot) = snapshots.get(&product.id) {
+                order_book.restore(snapshot);
+            }
+            if registry.books.contains_key(&product.id) {
+                return Err(CustomError::ProductExists(product.id.clone()));
+            }
+            registry.books.insert(product.id.clone(), order_book);
+        }
+
+        Ok(registry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use rust_decimal::Decimal;
+
+    use crate::matching::order_book::OrderBookSnapshot;
+    use crate::matching::registry::EngineRegistry;
+    use crate::models::models::{Order, Product};
+    use crate::models::types::*;
+    use crate::utils::error::CustomError;
+
+    fn new_product(id: &str) -> Product {
+        Product {
+            id: id.to_string(),
+            base_currency: id[..3].to_string(),
+            quote_currency: "USD".to_string(),
+            base_scale: 4,
+            quote_scale: 2,
+            ..Default::default()
+        }
+    }
+
+    fn limit(id: u64, product_id: &str, side: Side, price: &str, size: &str) -> Order {
+        Order {
+            id,
+            created_at: 1695783003020967000,
+            product_id: product_id.to_string(),
+            user_id: id,
+            client_oid: "".to_string(),
+            price: Decimal::from_str(price).unwrap(),
+            size: Decimal::from_str(size).unwrap(),
+            funds: Decimal::ZERO,
+            r#type: ORDER_TYPE_LIMIT,
+            side,
+            time_in_force: GOOD_TILL_CANCELED,
+            status: ORDER_STATUS_NEW,
+            max_slippage_bps: None,
+            priority_class: 0,
+            all_or_none: false,
+            stp_group_id: None,
+            day_order: false,
+            peg: None,
+        }
+    }
+
+    #[test]
+    fn test_route_order() {
+        let mut registry = EngineRegistry::new();
+        registry.add_product(&new_product("BTC-USD")).unwrap();
+        registry.add_product(&new_product("ETH-USD")).unwrap();
+        assert!(registry.add_product(&new_product("ETH-USD")).is_err());
+
+        registry
+            .route_order(&limit(1, "BTC-USD", SIDE_SELL, "100", "1"))
+            .unwrap();
+        let logs = registry
+            .route_order(&limit(2, "ETH-USD", SIDE_BUY, "100", "1"))
+            .unwrap();
+        // no crossing with the BTC-USD sell
+        assert_eq!(logs.len(), 1);
+        assert_eq!(registry.books["BTC-USD"].ask_depths.orders.len(), 1);
+        assert_eq!(registry.books["ETH-USD"].bid_depths.orders.len(), 1);
+
+        assert!(registry
+            .route_order(&limit(3, "LTC-USD", SIDE_BUY, "100", "1"))
+            .is_err());
+
+        let snapshots = registry.snapshot_all();
+        assert_eq!(snapshots.len(), 2);
+        assert_eq!(snapshots["ETH-USD"].orders[0].order_id, 2);
+
+        assert!(registry.remove_product("BTC-USD").is_some());
+        assert!(registry
+            .route_order(&limit(4, "BTC-USD", SIDE_BUY, "100", "1"))
+            .is_err());
+    }
+
+    fn order_ids(snapshot: &OrderBookSnapshot) -> Vec<u64> {
+        let mut order_ids: Vec<u64> = snapshot.orders.iter().map(|o| o.order_id).collect();
+        order_ids.sort();
+        order_ids
+    }
+
+    #[test]
+    fn test_snapshot_restore_all() {
+        let products = vec![
+            new_product("BTC-USD"),
+            new_product("ETH-USD"),
+            new_product("LTC-USD"),
+        ];
+        let mut registry = EngineRegistry::new();
+        for product in &products {
+            registry.add_product(product).unwrap();
+        }
+        registry
+            .route_order(&limit(1, "BTC-USD", SIDE_SELL, "100", "2"))
+            .unwrap();
+        registry
+            .route_order(&limit(2, "BTC-USD", SIDE_BUY, "100", "1"))
+            .unwrap();
+        registry
+            .route_order(&limit(3, "ETH-USD", SIDE_BUY, "10", "1"))
+            .unwrap();
+        registry
+            .route_order(&limit(4, "ETH-USD", SIDE_SELL, "11", "1"))
+            .unwrap();
+
+        let snapshots = registry.snapshot_all();
+        let restored = EngineRegistry::restore_all(&products, &snapshots).unwrap();
+        let restored_snapshots = restored.snapshot_all();
+        assert_eq!(restored_snapshots.len(), 3);
+        for (product_id, snapshot) in &snapshots {
+            let restored_snapshot = &restored_snapshots[product_id];
+            assert_eq!(order_ids(restored_snapshot), order_ids(snapshot));
+            assert_eq!(restored_snapshot.log_seq, snapshot.log_seq);
+            assert_eq!(restored_snapshot.trade_seq, snapshot.trade_seq);
+        }
+        assert_eq!(order_ids(&restored_snapshots["BTC-USD"]), vec![1]);
+        assert_eq!(restored.books["BTC-USD"].trade_seq, 1);
+        assert!(order_ids(&restored_snapshots["LTC-USD"]).is_empty());
+
+        // BTC-USD snapshot has no product to restore to
+        assert_eq!(
+            EngineRegistry::restore_all(&products[1..], &snapshots).err(),
+            Some(CustomError::UnknownProduct("BTC-USD".to_string()))
+        );
+
+        // the ETH-USD snapshot filed under LTC-USD
+        let mut mismatched = snapshots.clone();
+        let eth = mismatched["ETH-USD"].clone();
+        mismatched.insert("LTC-USD".to_string(), eth);
+        assert_eq!(
+            EngineRegistry::restore_all(&products, &mismatched).err(),
+            Some(CustomError::SnapshotMismatch {
+                product_id: "LTC-USD".to_string(),
+                snapshot_product_id: "ETH-USD".to_string(),
+            })
+        );
+    }
+}
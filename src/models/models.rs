@@ -2,16 +2,31 @@ use rust_decimal::prelude::*;
 use serde::{Deserialize, Serialize};
 
 use crate::models::types::*;
+use crate::utils::error::CustomError;
 
 // use serde::{Deserializer, Serializer};
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Default, Debug, Serialize, Deserialize, Clone)]
 pub struct Product {
     pub id: String,
     pub base_currency: String,
     pub quote_currency: String,
     pub base_scale: i32,
     pub quote_scale: i32,
+    // order sizes must be multiples of lot_size, zero means no lot restriction
+    #[serde(default)]
+    pub lot_size: Decimal,
+    // reject the off-lot orders instead of rounding their size down to the lot
+    #[serde(default)]
+    pub reject_off_lot: bool,
+    // bring price and funds to quote_scale and size to base_scale on input, so that
+    // value-equal orders rest with the same representation
+    #[serde(default)]
+    pub normalize_scale: bool,
+    // the rest of a partially filled limit order below min_size is cancelled instead of
+    // resting as dust, zero means no minimum
+    #[serde(default)]
+    pub min_size: Decimal,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -37,6 +52,238 @@ pub struct Order {
     #[serde(serialize_with = "serialize_order_status")]
     #[serde(deserialize_with = "deserialize_order_status")]
     pub status: OrderStatus,
+    // for market orders, stop matching once the price moves further than this from the mid
+    #[serde(default)]
+    pub max_slippage_bps: Option<Decimal>,
+    // designated market makers get queue priority over others at the same price
+    #[serde(default)]
+    pub priority_class: u8,
+    // all-or-none, the limit order is only ever filled completely in one trade, it rests
+    // whole when the book can't fill it on arrival
+    #[serde(default)]
+    pub all_or_none: bool,
+    // sub-accounts of one trading entity share the group, self trade prevention
+    // regards the orders of the group as orders of one user
+    #[serde(default)]
+    pub stp_group_id: Option<u64>,
+    // a day order is cancelled when the session rolls, otherwise it rests until cancelled
+    #[serde(default)]
+    pub day_order: bool,
+    // the price of a pegged order is set by the book from its reference, on arrival and
+    // whenever the reference moves
+    #[serde(default)]
+    pub peg: Option<Peg>,
+}
+
+// the price is the reference plus offset, a negative offset sits below the reference
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Peg {
+    #[serde(serialize_with = "serialize_peg_reference")]
+    #[serde(deserialize_with = "deserialize_peg_reference")]
+    pub reference: PegReference,
+    #[serde(default)]
+    pub offset: Decimal,
+}
+
+impl Order {
+    // a limit order is driven by size only (or funds only if it's notional), a market
+    // buy by funds only and a market sell by size only, any other combination is ambiguous
+    pub fn validate(&self) -> Result<(), CustomError> {
+        // a pegged order is a good-till-canceled limit order given by size, priced by the book
+        if self.peg.is_some()
+            && (!matches!(self.r#type, OrderType::OrderTypeLimit)
+                || !matches!(self.time_in_force, TimeInForceType::GoodTillCanceled)
+                || self.all_or_none
+                || !self.price.is_zero()
+                || self.size.is_zero())
+        {
+            return Err(CustomError::InvalidOrder {
+                order_id: self.id,
+                reason: "a pegged order is a good-till-canceled limit order of size only"
+                    .to_string(),
+            });
+        }
+
+        if self.all_or_none
+            && let OrderType::OrderTypeMarket = self.r#type
+        {
+            return Err(CustomError::InvalidOrder {
+                order_id: self.id,
+                reason: "all-or-none applies to limit orders only".to_string(),
+            });
+        }
+
+        let (size_expected, funds_expected) = match (&self.r#type, &self.side) {
+            (OrderType::OrderTypeLimit, _) if self.size.is_zero() && !self.funds.is_zero() => {
+                if self.price.is_sign_negative() || self.price.is_zero() {
+                    return Err(CustomError::InvalidOrder {
+                        order_id: self.id,
+                        reason: format!("notional price {} must be positive", self.price),
+                    });
+                }
+                (false, true)
+            }
+            (OrderType::OrderTypeLimit, _) => (true, false),
+            (OrderType::OrderTypeMarket, Side::SideBuy) => (false, true),
+            (OrderType::OrderTypeMarket, Side::SideSell) => (true, false),
+        };
+
+        let check = |name: &str, value: &Decimal, expected: bool| {
+            if expected && (value.is_sign_negative() || value.is_zero()) {
+                Err(CustomError::InvalidOrder {
+                    order_id: self.id,
+                    reason: format!("{} {} must be positive", name, value),
+                })
+            } else if !expected && !value.is_zero() {
+                Err(CustomError::InvalidOrder {
+                    order_id: self.id,
+                    reason: format!("{} {} must be zero", name, value),
+                })
+            } else {
+                Ok(())
+            }
+        };
+        check("size", &self.size, size_expected)?;
+        check("funds", &self.funds, funds_expected)
+    }
+}
+
+// builds an order with the fields its type and side call for, limit orders take price and
+// size, market buys take funds and market sells take size, build validates the order the
+// same way the book does before accepting it
+pub struct OrderBuilder {
+    order: Order,
+}
+
+impl OrderBuilder {
+    pub fn new(id: u64, product_id: &str, user_id: u64) -> Self {
+        OrderBuilder {
+            order: Order {
+                id,
+                created_at: 0,
+                product_id: product_id.to_string(),
+                user_id,
+                client_oid: "".to_string(),
+                price: Decimal::ZERO,
+                size: Decimal::ZERO,
+                funds: Decimal::ZERO,
+                r#type: OrderType::OrderTypeLimit,
+                side: Side::SideBuy,
+                time_in_force: TimeInForceType::GoodTillCanceled,
+                status: OrderStatus::OrderStatusNew,
+                max_slippage_bps: None,
+                priority_class: 0,
+                all_or_none: false,
+                stp_group_id: None,
+                day_order: false,
+                peg: None,
+            },
+        }
+    }
+
+    pub fn limit(mut self, side: Side, price: Decimal, size: Decimal) -> Self {
+        self.order.r#type = OrderType::OrderTypeLimit;
+        self.order.side = side;
+        self.order.price = price;
+        self.order.size = size;
+        self.order.funds = Decimal::ZERO;
+        self
+    }
+
+    // a notional limit order, the book works out the size funds buy at the price
+    pub fn limit_funds(mut self, side: Side, price: Decimal, funds: Decimal) -> Self {
+        self.order.r#type = OrderType::OrderTypeLimit;
+        self.order.side = side;
+        self.order.price = price;
+        self.order.size = Decimal::ZERO;
+        self.order.funds = funds;
+        self
+    }
+
+    // a limit order priced by the book at the reference plus offset
+    pub fn pegged(mut self, side: Side, peg: Peg, size: Decimal) -> Self {
+        self.order.r#type = OrderType::OrderTypeLimit;
+        self.order.side = side;
+        self.order.price = Decimal::ZERO;
+        self.order.size = size;
+        self.order.funds = Decimal::ZERO;
+        self.order.peg = Some(peg);
+        self
+    }
+
+    pub fn market_buy_funds(mut self, funds: Decimal) -> Self {
+        self.order.r#type = OrderType::OrderTypeMarket;
+        self.order.side = Side::SideBuy;
+        self.order.price = Decimal::ZERO;
+        self.order.size = Decimal::ZERO;
+        self.order.funds = funds;
+        self
+    }
+
+    pub fn market_sell_size(mut self, size: Decimal) -> Self {
+        self.order.r#type = OrderType::OrderTypeMarket;
+        self.order.side = Side::SideSell;
+        self.order.price = Decimal::ZERO;
+        self.order.size = size;
+        self.order.funds = Decimal::ZERO;
+        self
+    }
+
+    pub fn created_at(mut self, created_at: u64) -> Self {
+        self.order.created_at = created_at;
+        self
+    }
+
+    pub fn client_oid(mut self, client_oid: &str) -> Self {
+        self.order.client_oid = client_oid.to_string();
+        self
+    }
+
+    pub fn time_in_force(mut self, time_in_force: TimeInForceType) -> Self {
+        self.order.time_in_force = time_in_force;
+        self
+    }
+
+    pub fn max_slippage_bps(mut self, bps: Decimal) -> Self {
+        self.order.max_slippage_bps = Some(bps);
+        self
+    }
+
+    pub fn priority_class(mut self, priority_class: u8) -> Self {
+        self.order.priority_class = priority_class;
+        self
+    }
+
+    pub fn all_or_none(mut self) -> Self {
+        self.order.all_or_none = true;
+        self
+    }
+
+    pub fn stp_group_id(mut self, group_id: u64) -> Self {
+        self.order.stp_group_id = Some(group_id);
+        self
+    }
+
+    pub fn day_order(mut self) -> Self {
+        self.order.day_order = true;
+        self
+    }
+
+    pub fn build(self) -> Result<Order, CustomError> {
+        // the book takes any limit price, yet a limit order of a price not above zero is
+        // nothing a caller means to build
+        if let OrderType::OrderTypeLimit = self.order.r#type
+            && self.order.peg.is_none()
+            && (self.order.price.is_sign_negative() || self.order.price.is_zero())
+        {
+            return Err(CustomError::InvalidOrder {
+                order_id: self.order.id,
+                reason: format!("limit price {} must be positive", self.order.price),
+            });
+        }
+        self.order.validate()?;
+        Ok(self.order)
+    }
 }
 
 #[cfg(test)]
@@ -45,8 +292,13 @@ mod tests {
 
     use rust_decimal::Decimal;
 
-    use crate::models::models::Order;
-    use crate::models::types::{OrderStatus, OrderType, Side, TimeInForceType};
+    use crate::models::models::{Order, OrderBuilder, Peg};
+    use crate::models::types::{OrderStatus, OrderType, PegReference, Side, TimeInForceType};
+    use crate::utils::error::CustomError;
+
+    fn dec(s: &str) -> Decimal {
+        Decimal::from_str(s).unwrap()
+    }
 
     #[test]
     fn test_serialize_order() {
@@ -63,6 +315,12 @@ mod tests {
             side: Side::SideBuy,
             time_in_force: TimeInForceType::GoodTillCanceled,
             status: OrderStatus::OrderStatusNew,
+            max_slippage_bps: None,
+            priority_class: 0,
+            all_or_none: false,
+            stp_group_id: None,
+            day_order: false,
+            peg: None,
         };
 
         let s = serde_json::to_string(&order).unwrap();
@@ -76,4 +334,104 @@ mod tests {
 
         println!("{:?}", o);
     }
+
+    #[test]
+    fn test_order_builder() {
+        let order = OrderBuilder::new(1, "BTC-USD", 7)
+            .limit(Side::SideSell, dec("100"), dec("2"))
+            .time_in_force(TimeInForceType::ImmediateOrCancel)
+            .client_oid("abc")
+            .build()
+            .unwrap();
+        assert!(matches!(order.r#type, OrderType::OrderTypeLimit));
+        assert!(matches!(order.side, Side::SideSell));
+        assert!(matches!(
+            order.time_in_force,
+            TimeInForceType::ImmediateOrCancel
+        ));
+        assert_eq!(
+            (order.price, order.size, order.funds),
+            (dec("100"), dec("2"), Decimal::ZERO)
+        );
+        assert_eq!((order.user_id, order.client_oid.as_str()), (7, "abc"));
+
+        let order = OrderBuilder::new(2, "BTC-USD", 7)
+            .limit_funds(Side::SideBuy, dec("100"), dec("50"))
+            .build()
+            .unwrap();
+        assert_eq!((order.size, order.funds), (Decimal::ZERO, dec("50")));
+
+        let order = OrderBuilder::new(3, "BTC-USD", 7)
+            .market_buy_funds(dec("50"))
+            .build()
+            .unwrap();
+        assert!(matches!(order.r#type, OrderType::OrderTypeMarket));
+        assert!(matches!(order.side, Side::SideBuy));
+        assert_eq!((order.size, order.funds), (Decimal::ZERO, dec("50")));
+
+        let order = OrderBuilder::new(4, "BTC-USD", 7)
+            .market_sell_size(dec("2"))
+            .build()
+            .unwrap();
+        assert!(matches!(order.side, Side::SideSell));
+        assert_eq!((order.size, order.funds), (dec("2"), Decimal::ZERO));
+
+        let peg = Peg {
+            reference: PegReference::PegReferenceMidpoint,
+            offset: dec("-0.5"),
+        };
+        let order = OrderBuilder::new(5, "BTC-USD", 7)
+            .pegged(Side::SideBuy, peg.clone(), dec("1"))
+            .build()
+            .unwrap();
+        assert_eq!((order.price, order.size), (Decimal::ZERO, dec("1")));
+        assert_eq!(order.peg, Some(peg));
+        let json = serde_json::to_value(&order).unwrap();
+        assert_eq!(json["peg"]["reference"], "midpoint");
+        assert_eq!(json["peg"]["offset"], "-0.5");
+    }
+
+    #[test]
+    fn test_order_builder_rejects() {
+        let invalid = |r: Result<Order, CustomError>| {
+            matches!(r, Err(CustomError::InvalidOrder { order_id: 1, .. }))
+        };
+        let builder = || OrderBuilder::new(1, "BTC-USD", 7);
+
+        // no order kind given, nothing to trade
+        assert!(invalid(builder().build()));
+        assert!(invalid(
+            builder().limit(Side::SideBuy, dec("0"), dec("1")).build()
+        ));
+        assert!(invalid(
+            builder().limit(Side::SideBuy, dec("-1"), dec("1")).build()
+        ));
+        assert!(invalid(
+            builder().limit(Side::SideBuy, dec("100"), dec("0")).build()
+        ));
+        assert!(invalid(builder().market_buy_funds(dec("0")).build()));
+        assert!(invalid(builder().market_sell_size(dec("-1")).build()));
+        // all-or-none applies to limit orders only
+        assert!(invalid(
+            builder().market_sell_size(dec("1")).all_or_none().build()
+        ));
+        // a pegged order rests until cancelled, with no price of its own
+        let peg = Peg {
+            reference: PegReference::PegReferenceBestBid,
+            offset: Decimal::ZERO,
+        };
+        let pegged = || builder().pegged(Side::SideBuy, peg.clone(), dec("1"));
+        assert!(pegged().build().is_ok());
+        assert!(invalid(
+            pegged()
+                .time_in_force(TimeInForceType::ImmediateOrCancel)
+                .build()
+        ));
+        assert!(invalid(pegged().all_or_none().build()));
+        assert!(invalid(
+            builder()
+                .pegged(Side::SideBuy, peg.clone(), dec("0"))
+                .build()
+        ));
+    }
 }
@@ -1,6 +1,6 @@
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub enum OrderType {
     OrderTypeLimit,
     OrderTypeMarket,
@@ -29,7 +29,7 @@ where
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub enum Side {
     SideBuy,
     SideSell,
@@ -67,7 +67,30 @@ where
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+pub fn serialize_opt_side<S>(side: &Option<Side>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    match side {
+        Some(side) => serialize_side(side, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+pub fn deserialize_opt_side<'de, D>(deserializer: D) -> Result<Option<Side>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let string: Option<&str> = Deserialize::deserialize(deserializer)?;
+    match string {
+        None => Ok(None),
+        Some("buy") => Ok(Some(Side::SideBuy)),
+        Some("sell") => Ok(Some(Side::SideSell)),
+        _ => Err(serde::de::Error::custom("invalid side string")),
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub enum TimeInForceType {
     GoodTillCanceled,
     ImmediateOrCancel,
@@ -155,6 +178,13 @@ where
 pub enum DoneReason {
     DoneReasonFilled,
     DoneReasonCancelled,
+    DoneReasonStp,
+    // cancelled by risk control, e.g. a breached limit of the account
+    DoneReasonRisk,
+    // cancelled since the order has expired
+    DoneReasonExpired,
+    // cancelled by an operator of the venue
+    DoneReasonAdmin,
 }
 
 pub fn serialize_done_reason<S>(done_reason: &DoneReason, serializer: S) -> Result<S::Ok, S::Error>
@@ -164,6 +194,10 @@ where
     let string = match done_reason {
         DoneReason::DoneReasonFilled => "filled",
         DoneReason::DoneReasonCancelled => "cancelled",
+        DoneReason::DoneReasonStp => "stp",
+        DoneReason::DoneReasonRisk => "risk",
+        DoneReason::DoneReasonExpired => "expired",
+        DoneReason::DoneReasonAdmin => "admin",
     };
     serializer.serialize_str(string)
 }
@@ -176,10 +210,50 @@ where
     match string {
         "filled" => Ok(DoneReason::DoneReasonFilled),
         "cancelled" => Ok(DoneReason::DoneReasonCancelled),
+        "stp" => Ok(DoneReason::DoneReasonStp),
+        "risk" => Ok(DoneReason::DoneReasonRisk),
+        "expired" => Ok(DoneReason::DoneReasonExpired),
+        "admin" => Ok(DoneReason::DoneReasonAdmin),
         _ => Err(serde::de::Error::custom("invalid done_reason string")),
     }
 }
 
+// the price a pegged order follows
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum PegReference {
+    PegReferenceBestBid,
+    PegReferenceBestAsk,
+    PegReferenceMidpoint,
+}
+
+pub fn serialize_peg_reference<S>(
+    reference: &PegReference,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    let string = match reference {
+        PegReference::PegReferenceBestBid => "best_bid",
+        PegReference::PegReferenceBestAsk => "best_ask",
+        PegReference::PegReferenceMidpoint => "midpoint",
+    };
+    serializer.serialize_str(string)
+}
+
+pub fn deserialize_peg_reference<'de, D>(deserializer: D) -> Result<PegReference, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let string: &str = Deserialize::deserialize(deserializer)?;
+    match string {
+        "best_bid" => Ok(PegReference::PegReferenceBestBid),
+        "best_ask" => Ok(PegReference::PegReferenceBestAsk),
+        "midpoint" => Ok(PegReference::PegReferenceMidpoint),
+        _ => Err(serde::de::Error::custom("invalid peg_reference string")),
+    }
+}
+
 pub const ORDER_TYPE_LIMIT: OrderType = OrderType::OrderTypeLimit;
 pub const ORDER_TYPE_MARKET: OrderType = OrderType::OrderTypeMarket;
 
@@ -200,3 +274,11 @@ pub const ORDER_STATUS_FILLED: OrderStatus = OrderStatus::OrderStatusFilled;
 
 pub const DONE_REASON_FILLED: DoneReason = DoneReason::DoneReasonFilled;
 pub const DONE_REASON_CANCELLED: DoneReason = DoneReason::DoneReasonCancelled;
+pub const DONE_REASON_STP: DoneReason = DoneReason::DoneReasonStp;
+pub const DONE_REASON_RISK: DoneReason = DoneReason::DoneReasonRisk;
+pub const DONE_REASON_EXPIRED: DoneReason = DoneReason::DoneReasonExpired;
+pub const DONE_REASON_ADMIN: DoneReason = DoneReason::DoneReasonAdmin;
+
+pub const PEG_REFERENCE_BEST_BID: PegReference = PegReference::PegReferenceBestBid;
+pub const PEG_REFERENCE_BEST_ASK: PegReference = PegReference::PegReferenceBestAsk;
+pub const PEG_REFERENCE_MIDPOINT: PegReference = PegReference::PegReferenceMidpoint;
@@ -1,23 +1,180 @@
 use std::error::Error;
 use std::fmt;
 
-#[derive(Debug)]
-pub struct CustomError(pub String);
+use rust_decimal::Decimal;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CustomError {
+    // the order id has been seen by the window already
+    OrderIdDuplicate(u64),
+    // the order id is too old for the window to tell
+    OrderIdExpired {
+        order_id: u64,
+        min: u64,
+        max: u64,
+    },
+    // the order is not on the book
+    NotFound(u64),
+    // the order rests on the other side of the book than the given one
+    WrongSide(u64),
+    // the order on the book belongs to another user than the one acting on it
+    NotOwner {
+        order_id: u64,
+        user_id: u64,
+    },
+    // an order of the quote would not fill or rest as submitted, so the quote is not applied
+    QuoteRejected(u64),
+    // the order on the book has less size than to be decremented
+    InsufficientSize {
+        order_id: u64,
+        size: Decimal,
+        decrement: Decimal,
+    },
+    // a market order was about to rest on the book
+    MarketOrderResting(u64),
+    NegativeSize {
+        order_id: u64,
+        size: Decimal,
+    },
+    // the combination of the order fields is not acceptable
+    InvalidOrder {
+        order_id: u64,
+        reason: String,
+    },
+    ProductExists(String),
+    UnknownProduct(String),
+    // the snapshot filed under the product is of another product
+    SnapshotMismatch {
+        product_id: String,
+        snapshot_product_id: String,
+    },
+    // matching of the book is paused
+    Halted,
+    // checked arithmetic of matching the taker with the maker failed, e.g. a maker of zero
+    // price, which tells bad data on the book
+    Arithmetic {
+        taker_id: u64,
+        maker_id: u64,
+        op: &'static str,
+    },
+    // the log can't be encoded, e.g. a string too long for its length prefix
+    Encode(String),
+    // the bytes are not a valid encoded log
+    Decode(String),
+    // the log sequence of the product skips or repeats a log
+    SequenceGap {
+        product_id: String,
+        expected: u64,
+        actual: u64,
+    },
+    Other(String),
+}
 
 impl CustomError {
     pub fn new(e: &dyn Error) -> Self {
-        CustomError(format!("{}", e).to_string())
+        CustomError::Other(format!("{}", e).to_string())
     }
 
     pub fn from_string(s: String) -> Self {
-        CustomError(s)
+        CustomError::Other(s)
     }
 }
 
 impl fmt::Display for CustomError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Custom Error: {}", self.0)
+        let msg = match self {
+            CustomError::OrderIdDuplicate(order_id) => format!("existed val {}", order_id),
+            CustomError::OrderIdExpired { order_id, min, max } => {
+                format!("expired val {}, current Window [{}-{}]", order_id, min, max)
+            }
+            CustomError::NotFound(order_id) => format!("order {} not found on book", order_id),
+            CustomError::WrongSide(order_id) => {
+                format!("order {} rests on the other side", order_id)
+            }
+            CustomError::NotOwner { order_id, user_id } => {
+                format!("order {} is not of user {}", order_id, user_id)
+            }
+            CustomError::QuoteRejected(order_id) => {
+                format!(
+                    "order {} of the quote is not filled or rested as submitted",
+                    order_id
+                )
+            }
+            CustomError::InsufficientSize {
+                order_id,
+                size,
+                decrement,
+            } => format!("order {} size {} less than {}", order_id, size, decrement),
+            CustomError::MarketOrderResting(order_id) => {
+                format!("market order {} can't rest on book", order_id)
+            }
+            CustomError::NegativeSize { order_id, size } => {
+                format!("order {} decrement size {} is negative", order_id, size)
+            }
+            CustomError::InvalidOrder { order_id, reason } => {
+                format!("order {} {}", order_id, reason)
+            }
+            CustomError::ProductExists(product_id) => {
+                format!("product {} already exists", product_id)
+            }
+            CustomError::UnknownProduct(product_id) => format!("unknown product {}", product_id),
+            CustomError::SnapshotMismatch {
+                product_id,
+                snapshot_product_id,
+            } => format!(
+                "snapshot of product {} filed under {}",
+                snapshot_product_id, product_id
+            ),
+            CustomError::Halted => "matching is halted".to_string(),
+            CustomError::Arithmetic {
+                taker_id,
+                maker_id,
+                op,
+            } => format!("{} failed, taker {} maker {}", op, taker_id, maker_id),
+            CustomError::Encode(s) => format!("encode log: {}", s),
+            CustomError::Decode(s) => format!("decode log: {}", s),
+            CustomError::SequenceGap {
+                product_id,
+                expected,
+                actual,
+            } => format!(
+                "product {} log sequence {} expected {}",
+                product_id, actual, expected
+            ),
+            CustomError::Other(s) => s.clone(),
+        };
+        write!(f, "Custom Error: {}", msg)
     }
 }
 
 impl Error for CustomError {}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use rust_decimal::Decimal;
+
+    use crate::utils::error::CustomError;
+
+    #[test]
+    fn test_display() {
+        assert_eq!(
+            CustomError::OrderIdDuplicate(3).to_string(),
+            "Custom Error: existed val 3"
+        );
+        assert_eq!(
+            CustomError::InsufficientSize {
+                order_id: 1,
+                size: Decimal::from_str("0.5").unwrap(),
+                decrement: Decimal::from_str("1").unwrap(),
+            }
+            .to_string(),
+            "Custom Error: order 1 size 0.5 less than 1"
+        );
+        assert_eq!(
+            CustomError::from_string("boom".to_string()),
+            CustomError::Other("boom".to_string())
+        );
+    }
+}
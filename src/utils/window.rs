@@ -3,6 +3,17 @@ use serde::{Deserialize, Serialize};
 use crate::utils::bitmap::Bitmap;
 use crate::utils::error::CustomError;
 
+// what a put changed in the window, enough for undo to take it back
+pub struct WindowPut {
+    val: u64,
+    min: u64,
+    max: u64,
+    // the seen vals which slid out of the window, or the whole bitmap when the window
+    // jumped by its cap or more
+    slid_out: Vec<u64>,
+    bit_map: Option<Bitmap>,
+}
+
 #[derive(Default, Debug, Serialize, Deserialize, Clone)]
 pub struct Window {
     pub min: u64,
@@ -23,30 +34,249 @@ impl Window {
 
     pub fn put(&mut self, val: u64) -> Result<(), CustomError> {
         return if val <= self.min {
-            Err(CustomError::from_string(
-                format!(
-                    "expired val {}, current Window [{}-{}]",
-                    val, self.min, self.max
-                )
-                .to_string(),
-            ))
+            Err(CustomError::OrderIdExpired {
+                order_id: val,
+                min: self.min,
+                max: self.max,
+            })
         } else if val > self.max {
             let delta = val - self.max;
+            // the slots of the vals sliding out are reused by the new vals
+            if delta >= self.cap {
+                self.bit_map = Bitmap::new(self.cap);
+            } else {
+                for v in (self.min + 1)..=(self.min + delta) {
+                    self.bit_map.set(v % self.cap, false);
+                }
+            }
             self.min += delta;
             self.max += delta;
             self.bit_map.set(val % self.cap, true);
             Ok(())
         } else if self.bit_map.get(val % self.cap) {
-            Err(CustomError::from_string(
-                format!("existed val {}", val).to_string(),
-            ))
+            Err(CustomError::OrderIdDuplicate(val))
         } else {
             self.bit_map.set(val % self.cap, true);
             Ok(())
         };
     }
 
+    // put which can be taken back by undo, only the vals sliding out are kept, so that
+    // the cost is the distance the window moves rather than its size
+    pub fn put_undoable(&mut self, val: u64) -> Result<WindowPut, CustomError> {
+        let (min, max) = (self.min, self.max);
+        let mut slid_out = Vec::new();
+        let mut bit_map = None;
+        if val > self.max {
+            let delta = val - self.max;
+            if delta >= self.cap {
+                bit_map = Some(self.bit_map.clone());
+            } else {
+                for v in (self.min + 1)..=(self.min + delta) {
+                    if self.bit_map.get(v % self.cap) {
+                        slid_out.push(v);
+                    }
+                }
+            }
+        }
+        self.put(val)?;
+        Ok(WindowPut {
+            val,
+            min,
+            max,
+            slid_out,
+            bit_map,
+        })
+    }
+
+    // take back the latest put, nothing else may have changed the window since
+    pub fn undo(&mut self, put: WindowPut) {
+        self.min = put.min;
+        self.max = put.max;
+        match put.bit_map {
+            Some(bit_map) => self.bit_map = bit_map,
+            None => {
+                // the slot of the val is the slot of the last val sliding out, so it's
+                // cleared before the slid out vals are put back
+                self.bit_map.set(put.val % self.cap, false);
+                for v in put.slid_out {
+                    self.bit_map.set(v % self.cap, true);
+                }
+            }
+        }
+    }
+
+    // vals below the window are expired, they are regarded as seen
     pub fn contains(&self, val: u64) -> bool {
-        self.bit_map.get(val)
+        if val <= self.min {
+            return true;
+        }
+        if val > self.max {
+            return false;
+        }
+        self.bit_map.get(val % self.cap)
+    }
+
+    // forget the vals in the window for which keep returns false, so they can be put again
+    pub fn retain<F: Fn(u64) -> bool>(&mut self, keep: F) {
+        for val in (self.min + 1)..=self.max {
+            if self.bit_map.get(val % self.cap) && !keep(val) {
+                self.bit_map.set(val % self.cap, false);
+            }
+        }
+    }
+
+    // reinitialize the window as [base-(base+cap)] with every slot cleared
+    pub fn reset(&mut self, base: u64) {
+        self.min = base;
+        self.max = base + self.cap;
+        self.bit_map = Bitmap::new(self.cap);
+    }
+
+    // growing the window keeps min and extends max, so expired vals are never readmitted,
+    // shrinking keeps max and drops the oldest vals which fall out of the new range
+    pub fn set_cap(&mut self, cap: u64) {
+        if cap == 0 {
+            panic!("wrong parameter: cap");
+        }
+
+        let (min, max) = if cap >= self.cap {
+            (self.min, self.min + cap)
+        } else {
+            (self.max - cap, self.max)
+        };
+
+        let mut bit_map = Bitmap::new(cap);
+        for val in (u64::max(min, self.min) + 1)..=u64::min(max, self.max) {
+            if self.bit_map.get(val % self.cap) {
+                bit_map.set(val % cap, true);
+            }
+        }
+
+        self.min = min;
+        self.max = max;
+        self.cap = cap;
+        self.bit_map = bit_map;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::utils::window::Window;
+
+    #[test]
+    fn test_contains() {
+        let mut window = Window::new(0, 10);
+        window.put(3).unwrap();
+        window.put(10).unwrap();
+        assert!(window.contains(3));
+        assert!(window.contains(10));
+        assert!(!window.contains(4));
+        assert!(!window.contains(13));
+
+        window.put(15).unwrap();
+        assert!(window.contains(2));
+        assert!(window.contains(15));
+        assert!(!window.contains(25));
+    }
+
+    #[test]
+    fn test_slide_window() {
+        let mut window = Window::new(0, 10);
+        window.put(3).unwrap();
+        window.put(7).unwrap();
+
+        // 3 slides out and its slot is free for 13, while 7 is still in the window
+        window.put(15).unwrap();
+        window.put(13).unwrap();
+        assert!(window.put(7).is_err());
+        assert!(window.put(3).is_err());
+
+        window.put(40).unwrap();
+        window.put(37).unwrap();
+        assert!(!window.contains(35));
+    }
+
+    #[test]
+    fn test_retain() {
+        let mut window = Window::new(0, 10);
+        for val in [2u64, 5, 8].iter() {
+            window.put(*val).unwrap();
+        }
+        window.retain(|val| val != 5);
+        assert!(window.contains(2));
+        assert!(!window.contains(5));
+        assert!(window.contains(8));
+        window.put(5).unwrap();
+    }
+
+    #[test]
+    fn test_reset_window() {
+        let mut window = Window::new(0, 10);
+        window.put(3).unwrap();
+        window.put(25).unwrap();
+
+        window.reset(100);
+        assert_eq!((window.min, window.max, window.cap), (100, 110, 10));
+        assert!(window.put(100).is_err());
+        window.put(103).unwrap();
+        window.put(105).unwrap();
+        assert!(window.put(105).is_err());
+    }
+
+    #[test]
+    fn test_shrink_window_cap() {
+        let mut window = Window::new(0, 10);
+        for val in [2u64, 5, 9, 10].iter() {
+            window.put(*val).unwrap();
+        }
+
+        window.set_cap(4);
+        assert_eq!((window.min, window.max, window.cap), (6, 10, 4));
+        // dropped out of range
+        assert!(window.put(2).is_err());
+        assert!(window.put(5).is_err());
+        // still in range
+        assert!(window.put(9).is_err());
+        assert!(window.put(10).is_err());
+        window.put(7).unwrap();
+        window.put(8).unwrap();
+    }
+
+    #[test]
+    fn test_grow_window_cap() {
+        let mut window = Window::new(0, 4);
+        for val in [1u64, 3, 4].iter() {
+            window.put(*val).unwrap();
+        }
+
+        window.set_cap(10);
+        assert_eq!((window.min, window.max, window.cap), (0, 10, 10));
+        assert!(window.put(1).is_err());
+        assert!(window.put(3).is_err());
+        assert!(window.put(4).is_err());
+        window.put(2).unwrap();
+        window.put(8).unwrap();
+    }
+
+    #[test]
+    fn test_undo_put() {
+        let mut window = Window::new(0, 10);
+        window.put(1).unwrap();
+        window.put(5).unwrap();
+        let origin = window.clone();
+        let same = |a: &Window, b: &Window| {
+            a.min == b.min && a.max == b.max && (1..=40).all(|v| a.contains(v) == b.contains(v))
+        };
+
+        // inside the window, sliding by less than the cap and jumping past it
+        for val in [7, 11, 13, 30].iter() {
+            let put = window.put_undoable(*val).unwrap();
+            assert!(window.contains(*val));
+            window.undo(put);
+            assert!(same(&window, &origin));
+        }
+        assert!(window.put_undoable(5).is_err());
+        assert!(same(&window, &origin));
     }
 }